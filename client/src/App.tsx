@@ -33,6 +33,7 @@ function App() {
   const [statusText, setStatusText] = useState('Disconnected');
 
   const wsRef = useRef<WebSocket | null>(null);
+  const tokenRef = useRef<string | null>(null);
   const messagesEndRef = useRef<HTMLDivElement | null>(null);
 
   // Auto-scroll to bottom
@@ -69,7 +70,9 @@ function App() {
   const fetchPreviousMessages = async (limit: number = 100) => {
     try {
       addSystemMessage('Loading previous messages...');
-      const response = await fetch(`http://localhost:8000/messages?limit=${limit}`);
+      const response = await fetch(`http://localhost:8000/messages?limit=${limit}`, {
+        headers: { Authorization: `Bearer ${tokenRef.current}` }
+      });
       const data = await response.json();
 
       if (data.status === 'success' && data.messages) {
@@ -92,12 +95,44 @@ function App() {
     }
   };
 
-  const connect = () => {
+  const login = async (): Promise<string | null> => {
+    try {
+      const response = await fetch('http://localhost:8000/login', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ email, password, device_name: 'Desktop client' })
+      });
+      const data = await response.json();
+
+      if (data.status === 'success' && data.token) {
+        return data.token;
+      }
+      if (data.two_factor_required) {
+        addSystemMessage('Error: two-factor authentication is not supported by this client yet');
+      } else {
+        addSystemMessage('Error: ' + (data.message || 'Login failed'));
+      }
+    } catch (error) {
+      console.error('Error logging in:', error);
+      addSystemMessage('Error connecting to server');
+    }
+    return null;
+  };
+
+  const connect = async () => {
     if (!email || !password) {
       alert('Please enter your email and password');
       return;
     }
 
+    updateStatus('Logging in...', 'connecting');
+    const token = await login();
+    if (!token) {
+      updateStatus('Disconnected', 'disconnected');
+      return;
+    }
+    tokenRef.current = token;
+
     updateStatus('Connecting...', 'connecting');
     const ws = new WebSocket('ws://localhost:8000/ws');
     wsRef.current = ws;
@@ -108,9 +143,8 @@ function App() {
 
       // send authentication message first
       const authMsg = {
-        type: 'auth',
-        email: email,
-        password: password
+        type: 'auth_token',
+        token: token
       };
       ws.send(JSON.stringify(authMsg));
     };
@@ -161,6 +195,7 @@ function App() {
       wsRef.current.close();
       wsRef.current = null;
     }
+    tokenRef.current = null;
   };
 
   const sendMessage = () => {
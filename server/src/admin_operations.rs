@@ -0,0 +1,136 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::roles::{Authorized, Role, permission};
+use crate::room_operations::RoleRequest;
+use crate::user_operations::ApiResponse;
+
+/// Gives the admin role to the accounts listed in the configuration, so a
+/// fresh deployment can be administered without touching the database
+pub async fn promote_configured_admins(pool: &Pool<Postgres>, emails: &[String]) {
+    if emails.is_empty() {
+        return;
+    }
+
+    let result = sqlx::query("UPDATE users SET role = $1 WHERE email = ANY($2)")
+        .bind(Role::Admin.as_str())
+        .bind(emails)
+        .execute(pool)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("Database error: {:?}", e);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsersResponse {
+    pub status: String,
+    pub users: Vec<UserInfo>,
+}
+
+pub async fn list_users(
+    State(state): State<Arc<AppState>>,
+    _admin: Authorized<permission::ManageRoles>,
+) -> Json<UsersResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, String, String, String)>(
+        "SELECT id, name, email, role, created_at::text FROM users ORDER BY id",
+    )
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(UsersResponse {
+            status: "success".to_string(),
+            users: rows
+                .into_iter()
+                .map(|(id, username, email, role, created_at)| UserInfo {
+                    id,
+                    username,
+                    email,
+                    role,
+                    created_at,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(UsersResponse {
+                status: "error".to_string(),
+                users: vec![],
+            })
+        }
+    }
+}
+
+/// Changes the global role of a user
+pub async fn set_user_role(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageRoles>,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<RoleRequest>,
+) -> Json<ApiResponse> {
+    let Some(role) = Role::parse(&payload.role) else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Role must be one of admin, moderator or member".to_string(),
+        });
+    };
+
+    if user_id == admin.auth.user_id {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You can't change your own role".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query("UPDATE users SET role = $1 WHERE id = $2")
+        .bind(role.as_str())
+        .bind(user_id)
+        .execute(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No user found with this id".to_string(),
+        }),
+        Ok(_) => {
+            record_event(
+                &state.pool,
+                AuditEvent::RoleChanged,
+                Some(admin.auth.user_id),
+                Some(user_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "role": role.as_str() }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: format!("Role changed to {}", role.as_str()),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to change role: {}", e),
+            })
+        }
+    }
+}
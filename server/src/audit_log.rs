@@ -0,0 +1,221 @@
+use axum::extract::{Json, Query, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::roles::{Authorized, permission};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+/// Security and moderation events written to `audit_log`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    Login,
+    LoginFailed,
+    PasswordChanged,
+    PasswordReset,
+    AccountDeleted,
+    UserBanned,
+    UserMuted,
+    UserKicked,
+    ModerationLifted,
+    MessageDeleted,
+    RoleChanged,
+    RoomRoleChanged,
+    ReportResolved,
+    WorkspaceMemberRemoved,
+    WorkspaceRoleChanged,
+    WebhookCreated,
+    WebhookDeleted,
+    BotCreated,
+    BotDeleted,
+    BotTokenRegenerated,
+    IncomingWebhookCreated,
+    IncomingWebhookDeleted,
+    CommandRegistered,
+    CommandRemoved,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::PasswordChanged => "password_changed",
+            AuditEvent::PasswordReset => "password_reset",
+            AuditEvent::AccountDeleted => "account_deleted",
+            AuditEvent::UserBanned => "user_banned",
+            AuditEvent::UserMuted => "user_muted",
+            AuditEvent::UserKicked => "user_kicked",
+            AuditEvent::ModerationLifted => "moderation_lifted",
+            AuditEvent::MessageDeleted => "message_deleted",
+            AuditEvent::RoleChanged => "role_changed",
+            AuditEvent::RoomRoleChanged => "room_role_changed",
+            AuditEvent::ReportResolved => "report_resolved",
+            AuditEvent::WorkspaceMemberRemoved => "workspace_member_removed",
+            AuditEvent::WorkspaceRoleChanged => "workspace_role_changed",
+            AuditEvent::WebhookCreated => "webhook_created",
+            AuditEvent::WebhookDeleted => "webhook_deleted",
+            AuditEvent::BotCreated => "bot_created",
+            AuditEvent::BotDeleted => "bot_deleted",
+            AuditEvent::BotTokenRegenerated => "bot_token_regenerated",
+            AuditEvent::IncomingWebhookCreated => "incoming_webhook_created",
+            AuditEvent::IncomingWebhookDeleted => "incoming_webhook_deleted",
+            AuditEvent::CommandRegistered => "command_registered",
+            AuditEvent::CommandRemoved => "command_removed",
+        }
+    }
+}
+
+/// Appends an event to the audit log. Failures are only logged so that an
+/// audit problem never breaks the action being audited.
+pub async fn record_event(
+    pool: &Pool<Postgres>,
+    event: AuditEvent,
+    actor_id: Option<i32>,
+    target_user_id: Option<i32>,
+    ip_address: Option<&str>,
+    details: serde_json::Value,
+) {
+    let result = sqlx::query(
+        "INSERT INTO audit_log (event, actor_id, target_user_id, ip_address, details)
+         VALUES ($1, $2, $3, $4, $5::JSONB)",
+    )
+    .bind(event.as_str())
+    .bind(actor_id)
+    .bind(target_user_id)
+    .bind(ip_address)
+    .bind(details.to_string())
+    .execute(pool)
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to write audit log: {:?}", e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    pub event: Option<String>,
+    pub actor_id: Option<i32>,
+    pub target_user_id: Option<i32>,
+    /// Only entries at or after this time, e.g. `2025-10-08 12:00:00`
+    pub since: Option<String>,
+    /// Only entries before this time
+    pub until: Option<String>,
+    /// Only entries older than this id, for fetching the next page
+    pub before_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub event: String,
+    pub actor_id: Option<i32>,
+    pub target_user_id: Option<i32>,
+    pub ip_address: Option<String>,
+    pub details: serde_json::Value,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditLogResponse {
+    pub status: String,
+    pub message: Option<String>,
+    pub entries: Vec<AuditLogEntry>,
+    /// Pass as `before_id` to get the next page; `None` on the last page
+    pub next_before_id: Option<i64>,
+}
+
+/// Lists audit log entries, newest first
+pub async fn get_audit_log(
+    State(state): State<Arc<AppState>>,
+    _admin: Authorized<permission::ViewAuditLog>,
+    Query(params): Query<AuditLogQuery>,
+) -> Json<AuditLogResponse> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    // Fetch one extra row to know whether there is another page
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            i64,
+            String,
+            Option<i32>,
+            Option<i32>,
+            Option<String>,
+            String,
+            String,
+        ),
+    >(
+        "SELECT id, event, actor_id, target_user_id, ip_address, details::text, created_at::text
+         FROM audit_log
+         WHERE ($1::TEXT IS NULL OR event = $1)
+           AND ($2::INT IS NULL OR actor_id = $2)
+           AND ($3::INT IS NULL OR target_user_id = $3)
+           AND ($4::TIMESTAMP IS NULL OR created_at >= $4::TIMESTAMP)
+           AND ($5::TIMESTAMP IS NULL OR created_at < $5::TIMESTAMP)
+           AND ($6::BIGINT IS NULL OR id < $6)
+         ORDER BY id DESC
+         LIMIT $7",
+    )
+    .bind(params.event)
+    .bind(params.actor_id)
+    .bind(params.target_user_id)
+    .bind(params.since)
+    .bind(params.until)
+    .bind(params.before_id)
+    .bind(limit + 1)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(mut rows) => {
+            let has_more = rows.len() as i64 > limit;
+            rows.truncate(limit as usize);
+            let next_before_id = if has_more {
+                rows.last().map(|row| row.0)
+            } else {
+                None
+            };
+
+            Json(AuditLogResponse {
+                status: "success".to_string(),
+                message: None,
+                entries: rows
+                    .into_iter()
+                    .map(
+                        |(id, event, actor_id, target_user_id, ip_address, details, created_at)| {
+                            AuditLogEntry {
+                                id,
+                                event,
+                                actor_id,
+                                target_user_id,
+                                ip_address,
+                                details: serde_json::from_str(&details)
+                                    .unwrap_or(serde_json::Value::Null),
+                                created_at,
+                            }
+                        },
+                    )
+                    .collect(),
+                next_before_id,
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(AuditLogResponse {
+                status: "error".to_string(),
+                message: Some(format!("Failed to read audit log: {}", e)),
+                entries: vec![],
+                next_before_id: None,
+            })
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use axum::extract::{Json, Path, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::connections::ConnectionControl;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+
+/// Ids of the users `user_id` has blocked
+pub async fn blocked_user_ids(pool: &Pool<Postgres>, user_id: i32) -> Vec<i32> {
+    let query_result =
+        sqlx::query_as::<_, (i32,)>("SELECT blocked_id FROM user_blocks WHERE blocker_id = $1")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await;
+
+    match query_result {
+        Ok(rows) => rows.into_iter().map(|(id,)| id).collect(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// Whether `blocker_id` has blocked `user_id`; anything addressed to a single
+/// user must check this first
+pub async fn is_blocked(pool: &Pool<Postgres>, blocker_id: i32, user_id: i32) -> bool {
+    let query_result = sqlx::query_as::<_, (bool,)>(
+        "SELECT EXISTS(SELECT 1 FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2)",
+    )
+    .bind(blocker_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await;
+
+    match query_result {
+        Ok((blocked,)) => blocked,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockedUserInfo {
+    pub user_id: i32,
+    pub username: String,
+    pub blocked_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlocksResponse {
+    pub status: String,
+    pub blocks: Vec<BlockedUserInfo>,
+}
+
+/// Lists the users the caller has blocked
+pub async fn list_blocks(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<BlocksResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT u.id, u.name, b.created_at::text
+         FROM user_blocks b
+         JOIN users u ON u.id = b.blocked_id
+         WHERE b.blocker_id = $1
+         ORDER BY b.created_at DESC",
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(BlocksResponse {
+            status: "success".to_string(),
+            blocks: rows
+                .into_iter()
+                .map(|(user_id, username, blocked_at)| BlockedUserInfo {
+                    user_id,
+                    username,
+                    blocked_at,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(BlocksResponse {
+                status: "error".to_string(),
+                blocks: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockRequest {
+    pub user_id: i32,
+}
+
+/// Blocks a user: their messages are hidden from the caller from now on,
+/// in the history as well as on every open connection
+pub async fn block_user(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<BlockRequest>,
+) -> Json<ApiResponse> {
+    if payload.user_id == auth.user_id {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You can't block yourself".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query(
+        "INSERT INTO user_blocks (blocker_id, blocked_id)
+         SELECT $1, id FROM users WHERE id = $2
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(auth.user_id)
+    .bind(payload.user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => {
+            let message = if is_blocked(&state.pool, auth.user_id, payload.user_id).await {
+                "You have already blocked this user"
+            } else {
+                "No user found with this id"
+            };
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: message.to_string(),
+            })
+        }
+        Ok(_) => {
+            state
+                .connections
+                .send_to_user(auth.user_id, ConnectionControl::Block(payload.user_id));
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "User blocked".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to block user: {}", e),
+            })
+        }
+    }
+}
+
+/// Unblocks a user
+pub async fn unblock_user(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(user_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let query_result =
+        sqlx::query("DELETE FROM user_blocks WHERE blocker_id = $1 AND blocked_id = $2")
+            .bind(auth.user_id)
+            .bind(user_id)
+            .execute(&state.pool)
+            .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You haven't blocked this user".to_string(),
+        }),
+        Ok(_) => {
+            state
+                .connections
+                .send_to_user(auth.user_id, ConnectionControl::Unblock(user_id));
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "User unblocked".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to unblock user: {}", e),
+            })
+        }
+    }
+}
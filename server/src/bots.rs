@@ -0,0 +1,372 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::roles::{Authorized, permission};
+use crate::session_operations::{create_session, generate_token, hash_token, revoke_user_sessions};
+use crate::user_operations::ApiResponse;
+use crate::workspaces::{WorkspaceRole, add_workspace_member};
+
+/// API tokens start with this, which tells them apart from access tokens
+pub const API_TOKEN_PREFIX: &str = "bot_";
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_BOTS_PER_WORKSPACE: i64 = 50;
+/// Stored as the password hash of bots, which no password hashes to
+const NO_PASSWORD: &str = "!";
+/// Name of the session behind an API token, as shown by `/sessions`
+const API_TOKEN_DEVICE_NAME: &str = "API token";
+
+/// Finds the session and user behind a bot's API token. The session is
+/// checked like any other by `authenticate_token`.
+pub async fn api_token_session(pool: &Pool<Postgres>, token: &str) -> Option<(Uuid, i32)> {
+    let result = sqlx::query_as::<_, (Uuid, i32)>(
+        "SELECT s.id, s.user_id FROM api_tokens t
+         JOIN sessions s ON s.id = t.session_id
+         WHERE t.token_hash = $1",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(row) => row,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Opens a session for a bot in its workspace and returns a new API token
+/// for it. The token is only stored hashed.
+async fn issue_api_token(
+    connection: &mut PgConnection,
+    bot_id: i32,
+    workspace_id: i32,
+    ip_address: &str,
+) -> Result<String, sqlx::Error> {
+    let session_id = create_session(
+        &mut *connection,
+        bot_id,
+        workspace_id,
+        API_TOKEN_DEVICE_NAME,
+        ip_address,
+        None,
+    )
+    .await?;
+    let token = format!("{}{}", API_TOKEN_PREFIX, generate_token());
+    sqlx::query("INSERT INTO api_tokens (session_id, token_hash) VALUES ($1, $2)")
+        .bind(session_id)
+        .bind(hash_token(&token))
+        .execute(connection)
+        .await?;
+    Ok(token)
+}
+
+/// Whether a bot belongs to the workspace
+pub async fn bot_in_workspace(pool: &Pool<Postgres>, bot_id: i32, workspace_id: i32) -> bool {
+    let result = sqlx::query("SELECT 1 FROM bots WHERE user_id = $1 AND workspace_id = $2")
+        .bind(bot_id)
+        .bind(workspace_id)
+        .fetch_optional(pool)
+        .await;
+
+    match result {
+        Ok(row) => row.is_some(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bot {
+    pub id: i32,
+    pub name: String,
+    pub created_by: Option<i32>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotsResponse {
+    pub status: String,
+    pub bots: Vec<Bot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotResponse {
+    pub status: String,
+    pub message: String,
+    pub bot: Option<Bot>,
+    /// Only returned when the bot is created or its token regenerated
+    pub token: Option<String>,
+}
+
+impl BotResponse {
+    fn error(message: String) -> Json<BotResponse> {
+        Json(BotResponse {
+            status: "error".to_string(),
+            message,
+            bot: None,
+            token: None,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBotRequest {
+    pub name: String,
+}
+
+/// Lists the bots of the caller's workspace
+pub async fn list_bots(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageBots>,
+) -> Json<BotsResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, Option<i32>, String)>(
+        "SELECT u.id, u.name, b.created_by, b.created_at::text
+         FROM bots b
+         JOIN users u ON u.id = b.user_id
+         WHERE b.workspace_id = $1
+         ORDER BY u.id",
+    )
+    .bind(admin.auth.workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(BotsResponse {
+            status: "success".to_string(),
+            bots: rows
+                .into_iter()
+                .map(|(id, name, created_by, created_at)| Bot {
+                    id,
+                    name,
+                    created_by,
+                    created_at,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(BotsResponse {
+                status: "error".to_string(),
+                bots: vec![],
+            })
+        }
+    }
+}
+
+/// Creates a bot account in the caller's workspace. Bots can't log in with a
+/// password; they authenticate with the API token returned here, which is
+/// not shown again.
+pub async fn create_bot(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageBots>,
+    Json(payload): Json<CreateBotRequest>,
+) -> Json<BotResponse> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return BotResponse::error(format!(
+            "Name must be between 1 and {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    let workspace_id = admin.auth.workspace_id;
+    let ip_address = addr.ip().to_string();
+
+    let created: Result<_, sqlx::Error> = async {
+        let mut transaction = state.pool.begin().await?;
+        // Bots get an address nobody can receive mail at, so password resets can't reach them
+        let inserted = sqlx::query_as::<_, (i32, String)>(
+            "INSERT INTO users (name, email, password_hash, email_verified, is_bot)
+             SELECT $1, $2, $3, TRUE, TRUE
+             WHERE (SELECT COUNT(*) FROM bots WHERE workspace_id = $4) < $5
+             RETURNING id, created_at::text",
+        )
+        .bind(name)
+        .bind(format!("bot-{}@bots.invalid", Uuid::new_v4()))
+        .bind(NO_PASSWORD)
+        .bind(workspace_id)
+        .bind(MAX_BOTS_PER_WORKSPACE)
+        .fetch_optional(&mut transaction)
+        .await?;
+        let Some((bot_id, created_at)) = inserted else {
+            return Ok(None);
+        };
+
+        sqlx::query("INSERT INTO bots (user_id, workspace_id, created_by) VALUES ($1, $2, $3)")
+            .bind(bot_id)
+            .bind(workspace_id)
+            .bind(admin.auth.user_id)
+            .execute(&mut transaction)
+            .await?;
+        add_workspace_member(
+            &mut transaction,
+            workspace_id,
+            bot_id,
+            WorkspaceRole::Member,
+        )
+        .await?;
+        let token = issue_api_token(&mut transaction, bot_id, workspace_id, &ip_address).await?;
+        transaction.commit().await?;
+        Ok(Some((bot_id, created_at, token)))
+    }
+    .await;
+
+    match created {
+        Ok(Some((bot_id, created_at, token))) => {
+            record_event(
+                &state.pool,
+                AuditEvent::BotCreated,
+                Some(admin.auth.user_id),
+                Some(bot_id),
+                Some(&ip_address),
+                serde_json::json!({ "workspace_id": workspace_id, "name": name }),
+            )
+            .await;
+            Json(BotResponse {
+                status: "success".to_string(),
+                message: "Bot created".to_string(),
+                bot: Some(Bot {
+                    id: bot_id,
+                    name: name.to_string(),
+                    created_by: Some(admin.auth.user_id),
+                    created_at,
+                }),
+                token: Some(token),
+            })
+        }
+        Ok(None) => BotResponse::error(format!(
+            "A workspace can't have more than {} bots",
+            MAX_BOTS_PER_WORKSPACE
+        )),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            BotResponse::error(format!("Failed to create bot: {}", e))
+        }
+    }
+}
+
+/// Replaces a bot's API token: its current token stops working and its
+/// connections are closed
+pub async fn regenerate_bot_token(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageBots>,
+    Path(bot_id): Path<i32>,
+) -> Json<BotResponse> {
+    let workspace_id = admin.auth.workspace_id;
+    if !bot_in_workspace(&state.pool, bot_id, workspace_id).await {
+        return BotResponse::error("No bot found with this id".to_string());
+    }
+    let ip_address = addr.ip().to_string();
+
+    let regenerated: Result<_, sqlx::Error> = async {
+        revoke_user_sessions(&state, bot_id, None).await?;
+        let mut connection = state.pool.acquire().await?;
+        issue_api_token(&mut connection, bot_id, workspace_id, &ip_address).await
+    }
+    .await;
+
+    match regenerated {
+        Ok(token) => {
+            record_event(
+                &state.pool,
+                AuditEvent::BotTokenRegenerated,
+                Some(admin.auth.user_id),
+                Some(bot_id),
+                Some(&ip_address),
+                serde_json::json!({ "workspace_id": workspace_id }),
+            )
+            .await;
+            Json(BotResponse {
+                status: "success".to_string(),
+                message: "Token regenerated".to_string(),
+                bot: None,
+                token: Some(token),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            BotResponse::error(format!("Failed to regenerate token: {}", e))
+        }
+    }
+}
+
+/// Deletes a bot of the caller's workspace: its token stops working, its
+/// incoming webhooks are removed and it leaves the workspace and its rooms.
+/// Its messages are kept.
+pub async fn delete_bot(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageBots>,
+    Path(bot_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let workspace_id = admin.auth.workspace_id;
+
+    let deleted: Result<_, sqlx::Error> = async {
+        let mut transaction = state.pool.begin().await?;
+        let deleted = sqlx::query("DELETE FROM bots WHERE user_id = $1 AND workspace_id = $2")
+            .bind(bot_id)
+            .bind(workspace_id)
+            .execute(&mut transaction)
+            .await?;
+        if deleted.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "DELETE FROM room_members rm USING rooms r
+             WHERE r.id = rm.room_id AND r.workspace_id = $1 AND rm.user_id = $2",
+        )
+        .bind(workspace_id)
+        .bind(bot_id)
+        .execute(&mut transaction)
+        .await?;
+        sqlx::query("DELETE FROM workspace_members WHERE workspace_id = $1 AND user_id = $2")
+            .bind(workspace_id)
+            .bind(bot_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        revoke_user_sessions(&state, bot_id, None).await?;
+        Ok(true)
+    }
+    .await;
+
+    match deleted {
+        Ok(true) => {
+            record_event(
+                &state.pool,
+                AuditEvent::BotDeleted,
+                Some(admin.auth.user_id),
+                Some(bot_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "workspace_id": workspace_id }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Bot deleted".to_string(),
+            })
+        }
+        Ok(false) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No bot found with this id".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to delete bot: {}", e),
+            })
+        }
+    }
+}
@@ -0,0 +1,898 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::bots::bot_in_workspace;
+use crate::connections::{ConnectionControl, Notice, NoticeKind};
+use crate::content_filter::filter_message;
+use crate::http_client::{build_http_client, check_url};
+use crate::message_operations::post_message;
+use crate::moderation::{ModerationAction, ModerationRequest, active_sanction, mute};
+use crate::roles::{Permission, RoomRole, has_room_permission, room_role};
+use crate::room_operations::{add_room_member, room_in_workspace};
+use crate::scheduler::remind_later;
+use crate::session_operations::{AuthSession, load_session};
+use crate::user_operations::ApiResponse;
+use crate::webhooks::{generate_secret, sign};
+
+const MAX_NAME_LENGTH: usize = 32;
+const MAX_DESCRIPTION_LENGTH: usize = 200;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_COMMANDS_PER_WORKSPACE: i64 = 100;
+const MAX_TOPIC_LENGTH: usize = 250;
+/// Bytes of a bot's answer that are read; the rest is ignored
+const MAX_REPLY_BYTES: usize = 16 * 1024;
+const USER_AGENT: &str = "ChatPlatform-Commands/1.0";
+
+/// What a chat message sent over the WebSocket turned out to be
+#[derive(Debug, PartialEq)]
+pub enum ChatInput<'a> {
+    /// Text to post as a message
+    Text(&'a str),
+    /// A slash command, with its lowercased name and the rest of the line
+    Command { name: String, args: &'a str },
+}
+
+/// Tells slash commands apart from messages. A command is a `/` followed by
+/// a name made of letters, digits, `_` and `-`, then a space or the end of
+/// the message, so paths such as `/usr/bin` stay messages. Messages starting
+/// with `//` are posted with the first `/` removed.
+pub fn parse_chat_input(content: &str) -> ChatInput<'_> {
+    if content.starts_with("//") {
+        return ChatInput::Text(&content[1..]);
+    }
+    let Some(rest) = content.strip_prefix('/') else {
+        return ChatInput::Text(content);
+    };
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let name = &rest[..end];
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return ChatInput::Text(content);
+    }
+    ChatInput::Command {
+        name: name.to_ascii_lowercase(),
+        args: rest[end..].trim(),
+    }
+}
+
+/// Commands the server handles itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuiltinCommand {
+    Help,
+    Me,
+    Topic,
+    Mute,
+    Invite,
+    Remind,
+}
+
+impl BuiltinCommand {
+    const ALL: [BuiltinCommand; 6] = [
+        BuiltinCommand::Help,
+        BuiltinCommand::Me,
+        BuiltinCommand::Topic,
+        BuiltinCommand::Mute,
+        BuiltinCommand::Invite,
+        BuiltinCommand::Remind,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            BuiltinCommand::Help => "help",
+            BuiltinCommand::Me => "me",
+            BuiltinCommand::Topic => "topic",
+            BuiltinCommand::Mute => "mute",
+            BuiltinCommand::Invite => "invite",
+            BuiltinCommand::Remind => "remind",
+        }
+    }
+
+    fn usage(&self) -> &'static str {
+        match self {
+            BuiltinCommand::Help => "/help",
+            BuiltinCommand::Me => "/me <action>",
+            BuiltinCommand::Topic => "/topic [text]",
+            BuiltinCommand::Mute => "/mute @name [duration] <reason>",
+            BuiltinCommand::Invite => "/invite @name",
+            BuiltinCommand::Remind => "/remind <duration> <note>",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            BuiltinCommand::Help => "List the commands of this workspace",
+            BuiltinCommand::Me => "Post an action, e.g. /me waves",
+            BuiltinCommand::Topic => "Show the room's topic, or set it",
+            BuiltinCommand::Mute => "Mute a member, e.g. /mute @sam 30m spam",
+            BuiltinCommand::Invite => "Add a member to this room",
+            BuiltinCommand::Remind => "Remind yourself of something, e.g. /remind 1h30m call Sam",
+        }
+    }
+
+    fn parse(name: &str) -> Option<BuiltinCommand> {
+        BuiltinCommand::ALL
+            .into_iter()
+            .find(|command| command.name() == name)
+    }
+}
+
+/// Where a command was run and by whom
+#[derive(Clone)]
+pub struct CommandContext {
+    pub state: Arc<AppState>,
+    pub session_id: Uuid,
+    pub user_id: i32,
+    pub workspace_id: i32,
+    pub room_id: i32,
+    /// Connection the command came from, which gets its output
+    pub connection_id: u64,
+    pub ip_address: String,
+}
+
+impl CommandContext {
+    /// Shows a command's output to the connection that ran it only
+    fn reply(&self, command: &str, text: String) {
+        self.state.connections.send_to_connection(
+            self.connection_id,
+            ConnectionControl::Notice(
+                Notice::new(NoticeKind::Command, text)
+                    .in_room(self.room_id)
+                    .with_detail("command", command),
+            ),
+        );
+    }
+
+    fn fail(&self, reason: String) {
+        self.state
+            .connections
+            .send_to_connection(self.connection_id, ConnectionControl::Error(reason));
+    }
+}
+
+/// Runs a slash command typed in a room. Built-in commands run here; other
+/// names are looked up among the commands bots registered in the workspace
+/// and forwarded to them. Output and errors only go to the connection the
+/// command came from, unless the command posts a message.
+pub async fn run_command(context: &CommandContext, name: &str, args: &str) {
+    let pool = &context.state.pool;
+    // Roles may have changed since the connection was opened
+    let Some(auth) = load_session(pool, context.session_id, context.user_id).await else {
+        context.fail("Your session has expired".to_string());
+        return;
+    };
+    if !room_in_workspace(pool, context.room_id, context.workspace_id).await
+        || room_role(pool, context.room_id, context.user_id)
+            .await
+            .is_none()
+    {
+        context.fail("You are not a member of this room".to_string());
+        return;
+    }
+
+    let result = match BuiltinCommand::parse(name) {
+        Some(BuiltinCommand::Help) => help(context).await,
+        Some(BuiltinCommand::Me) => me(context, &auth, args).await,
+        Some(BuiltinCommand::Topic) => topic(context, &auth, args).await,
+        Some(BuiltinCommand::Mute) => mute_member(context, &auth, args).await,
+        Some(BuiltinCommand::Invite) => invite(context, &auth, args).await,
+        Some(BuiltinCommand::Remind) => remind(context, &auth, args).await,
+        None => forward_to_bot(context, &auth, name, args).await,
+    };
+    match result {
+        Ok(Some(text)) => context.reply(name, text),
+        Ok(None) => {}
+        Err(reason) => context.fail(reason),
+    }
+}
+
+/// Parses durations such as `30s`, `10m`, `2h`, `1d` or `1h30m` into seconds
+fn parse_duration(text: &str) -> Option<i64> {
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        let value: i64 = number.parse().ok()?;
+        seconds = seconds.checked_add(value.checked_mul(unit)?)?;
+        number.clear();
+    }
+    if !number.is_empty() || seconds == 0 {
+        return None;
+    }
+    Some(seconds)
+}
+
+/// Splits the first word off a command's arguments
+fn split_word(args: &str) -> (&str, &str) {
+    match args.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (args, ""),
+    }
+}
+
+/// Finds a member of the workspace by name, ignoring case and a leading `@`
+async fn find_member(context: &CommandContext, name: &str) -> Result<(i32, String), String> {
+    let name = name.trim_start_matches('@');
+    if name.is_empty() {
+        return Err("Give the name of a member, e.g. @sam".to_string());
+    }
+    let result = sqlx::query_as::<_, (i32, String)>(
+        "SELECT u.id, u.name FROM workspace_members wm
+         JOIN users u ON u.id = wm.user_id
+         WHERE wm.workspace_id = $1 AND LOWER(u.name) = LOWER($2)
+         ORDER BY u.id
+         LIMIT 1",
+    )
+    .bind(context.workspace_id)
+    .bind(name)
+    .fetch_optional(&context.state.pool)
+    .await;
+
+    match result {
+        Ok(Some(member)) => Ok(member),
+        Ok(None) => Err(format!("No member named {} in this workspace", name)),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err("Failed to look up member".to_string())
+        }
+    }
+}
+
+/// Escapes the characters Markdown would otherwise interpret
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+async fn help(context: &CommandContext) -> Result<Option<String>, String> {
+    let mut lines: Vec<String> = BuiltinCommand::ALL
+        .iter()
+        .map(|command| format!("{}: {}", command.usage(), command.description()))
+        .collect();
+    for command in bot_commands(&context.state, context.workspace_id).await? {
+        lines.push(format!("/{}: {}", command.name, command.description));
+    }
+    Ok(Some(lines.join("\n")))
+}
+
+async fn me(
+    context: &CommandContext,
+    auth: &AuthSession,
+    args: &str,
+) -> Result<Option<String>, String> {
+    if args.is_empty() {
+        return Err(format!("Usage: {}", BuiltinCommand::Me.usage()));
+    }
+    let content = format!("*{} {}*", escape_markdown(&auth.username), args);
+    post_message(
+        &context.state,
+        context.workspace_id,
+        context.user_id,
+        context.room_id,
+        &content,
+        None,
+    )
+    .await?;
+    Ok(None)
+}
+
+async fn topic(
+    context: &CommandContext,
+    auth: &AuthSession,
+    args: &str,
+) -> Result<Option<String>, String> {
+    let pool = &context.state.pool;
+    if args.is_empty() {
+        let result =
+            sqlx::query_as::<_, (Option<String>,)>("SELECT topic FROM rooms WHERE id = $1")
+                .bind(context.room_id)
+                .fetch_one(pool)
+                .await;
+        return match result {
+            Ok((Some(topic),)) => Ok(Some(format!("Topic: {}", topic))),
+            Ok((None,)) => Ok(Some("This room has no topic".to_string())),
+            Err(e) => {
+                eprintln!("Database error: {:?}", e);
+                Err("Failed to look up the topic".to_string())
+            }
+        };
+    }
+
+    if !has_room_permission(pool, auth, context.room_id, Permission::ManageRooms).await {
+        return Err("You don't have permission to set the topic of this room".to_string());
+    }
+    let topic = filter_message(&context.state, args)
+        .map_err(|reason| format!("Topic rejected: {}", reason))?;
+    if topic.chars().count() > MAX_TOPIC_LENGTH {
+        return Err(format!(
+            "The topic can be at most {} characters",
+            MAX_TOPIC_LENGTH
+        ));
+    }
+    if let Err(e) = sqlx::query("UPDATE rooms SET topic = $1 WHERE id = $2")
+        .bind(&topic)
+        .bind(context.room_id)
+        .execute(pool)
+        .await
+    {
+        eprintln!("Database error: {:?}", e);
+        return Err("Failed to set the topic".to_string());
+    }
+
+    // The topic is set even if the room can't be told, e.g. in slow mode
+    let announcement = format!(
+        "*{} set the topic:* {}",
+        escape_markdown(&auth.username),
+        topic
+    );
+    match post_message(
+        &context.state,
+        context.workspace_id,
+        context.user_id,
+        context.room_id,
+        &announcement,
+        None,
+    )
+    .await
+    {
+        Ok(_) => Ok(None),
+        Err(_) => Ok(Some(format!("Topic set: {}", topic))),
+    }
+}
+
+async fn mute_member(
+    context: &CommandContext,
+    auth: &AuthSession,
+    args: &str,
+) -> Result<Option<String>, String> {
+    if !auth.has(Permission::ModerateUsers) {
+        return Err("You don't have permission to mute members".to_string());
+    }
+    let (name, rest) = split_word(args);
+    if name.is_empty() {
+        return Err(format!("Usage: {}", BuiltinCommand::Mute.usage()));
+    }
+    let (user_id, username) = find_member(context, name).await?;
+    // The duration is optional, so a first word that isn't one starts the reason
+    let (duration, reason) = match split_word(rest) {
+        (word, reason) if parse_duration(word).is_some() => (parse_duration(word), reason),
+        _ => (None, rest),
+    };
+    let duration_minutes = match duration {
+        Some(seconds) => Some(
+            i32::try_from((seconds + 59) / 60)
+                .map_err(|_| "That duration is too long".to_string())?,
+        ),
+        None => None,
+    };
+
+    let request = ModerationRequest {
+        user_id,
+        reason: reason.to_string(),
+        duration_minutes,
+        room_id: None,
+    };
+    mute(&context.state, auth, &context.ip_address, &request).await?;
+    Ok(Some(match duration_minutes {
+        Some(minutes) => format!("Muted {} for {} minutes", username, minutes),
+        None => format!("Muted {}", username),
+    }))
+}
+
+async fn invite(
+    context: &CommandContext,
+    auth: &AuthSession,
+    args: &str,
+) -> Result<Option<String>, String> {
+    let pool = &context.state.pool;
+    let (name, _) = split_word(args);
+    if name.is_empty() {
+        return Err(format!("Usage: {}", BuiltinCommand::Invite.usage()));
+    }
+    if !has_room_permission(pool, auth, context.room_id, Permission::ManageRooms).await {
+        return Err("You don't have permission to invite members to this room".to_string());
+    }
+    let (user_id, username) = find_member(context, name).await?;
+    if room_role(pool, context.room_id, user_id).await.is_some() {
+        return Err(format!("{} is already in this room", username));
+    }
+    if active_sanction(
+        pool,
+        user_id,
+        context.workspace_id,
+        ModerationAction::Kick,
+        Some(context.room_id),
+    )
+    .await
+    .is_some()
+    {
+        return Err(format!("{} has been kicked from this room", username));
+    }
+
+    if let Err(e) = add_room_member(pool, context.room_id, user_id, RoomRole::Member).await {
+        eprintln!("Database error: {:?}", e);
+        return Err(format!("Failed to invite {}", username));
+    }
+    let connections = &context.state.connections;
+    connections.send_to_member(
+        context.workspace_id,
+        user_id,
+        ConnectionControl::JoinRoom(context.room_id),
+    );
+    connections.send_to_member(
+        context.workspace_id,
+        user_id,
+        ConnectionControl::Notice(
+            Notice::new(
+                NoticeKind::AddedToRoom,
+                format!("{} added you to this room", auth.username),
+            )
+            .in_room(context.room_id),
+        ),
+    );
+    Ok(Some(format!("Added {} to this room", username)))
+}
+
+async fn remind(
+    context: &CommandContext,
+    auth: &AuthSession,
+    args: &str,
+) -> Result<Option<String>, String> {
+    let (duration, note) = split_word(args);
+    let Some(seconds) = parse_duration(duration) else {
+        return Err(format!("Usage: {}", BuiltinCommand::Remind.usage()));
+    };
+    remind_later(&context.state, auth, note, seconds).await?;
+    Ok(Some(format!("I'll remind you in {}", duration)))
+}
+
+/// A command registered by a bot
+#[derive(Debug, Serialize)]
+pub struct BotCommand {
+    pub name: String,
+    pub description: String,
+    pub bot_id: i32,
+    pub created_at: String,
+}
+
+async fn bot_commands(state: &AppState, workspace_id: i32) -> Result<Vec<BotCommand>, String> {
+    let query_result = sqlx::query_as::<_, (String, String, i32, String)>(
+        "SELECT name, description, bot_id, created_at::text FROM bot_commands
+         WHERE workspace_id = $1
+         ORDER BY name",
+    )
+    .bind(workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Ok(rows
+            .into_iter()
+            .map(|(name, description, bot_id, created_at)| BotCommand {
+                name,
+                description,
+                bot_id,
+                created_at,
+            })
+            .collect()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err("Failed to look up commands".to_string())
+        }
+    }
+}
+
+fn http_client(state: &AppState) -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    let config = &state.config.webhooks;
+    // Redirects aren't followed: the command's address is the one registered
+    CLIENT.get_or_init(|| {
+        build_http_client(
+            USER_AGENT,
+            Duration::from_secs(config.timeout_seconds),
+            0,
+            config.allow_private_addresses,
+        )
+    })
+}
+
+/// What a bot answers a command with. Replies are ephemeral unless the bot
+/// asks for them to be posted.
+#[derive(Debug, Default, Deserialize)]
+struct BotReply {
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    visibility: Option<String>,
+}
+
+/// Sends a command to the bot that registered it. The bot is called in the
+/// background so a slow bot doesn't hold up the connection; its reply is
+/// shown to the user or, if it asks for it, posted in the room as the bot.
+async fn forward_to_bot(
+    context: &CommandContext,
+    auth: &AuthSession,
+    name: &str,
+    args: &str,
+) -> Result<Option<String>, String> {
+    let query_result = sqlx::query_as::<_, (i32, String, String)>(
+        "SELECT bot_id, url, secret FROM bot_commands WHERE workspace_id = $1 AND name = $2",
+    )
+    .bind(context.workspace_id)
+    .bind(name)
+    .fetch_optional(&context.state.pool)
+    .await;
+    let (bot_id, url, secret) = match query_result {
+        Ok(Some(command)) => command,
+        Ok(None) => return Err(format!("Unknown command /{}; try /help", name)),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Err("Failed to look up the command".to_string());
+        }
+    };
+
+    let payload = serde_json::json!({
+        "command": name,
+        "text": args,
+        "user_id": auth.user_id,
+        "username": auth.username,
+        "room_id": context.room_id,
+        "workspace_id": context.workspace_id,
+    })
+    .to_string();
+    let context = context.clone();
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let reply = match call_bot(&context.state, &url, &secret, &payload).await {
+            Ok(reply) => reply,
+            Err(error) => {
+                context.fail(format!("The /{} command failed: {}", name, error));
+                return;
+            }
+        };
+        if reply.text.trim().is_empty() {
+            return;
+        }
+        if reply.visibility.as_deref() != Some("public") {
+            context.reply(&name, reply.text);
+            return;
+        }
+
+        // Joining would give the bot's token the room's history
+        if room_role(&context.state.pool, context.room_id, bot_id)
+            .await
+            .is_none()
+        {
+            context.fail(format!(
+                "The /{} command's bot is not a member of this room, invite it to post its reply",
+                name
+            ));
+            return;
+        }
+        if let Err(reason) = post_message(
+            &context.state,
+            context.workspace_id,
+            bot_id,
+            context.room_id,
+            &reply.text,
+            None,
+        )
+        .await
+        {
+            context.fail(format!(
+                "The /{} command's reply was rejected: {}",
+                name, reason
+            ));
+        }
+    });
+    Ok(None)
+}
+
+/// Posts a command to a bot, signed like webhook deliveries, and reads its reply
+async fn call_bot(
+    state: &AppState,
+    url: &str,
+    secret: &str,
+    payload: &str,
+) -> Result<BotReply, String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+    check_url(&url, state.config.webhooks.allow_private_addresses)?;
+    let mut response = http_client(state)
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Event", "command")
+        .header(
+            "X-Webhook-Signature",
+            format!("sha256={}", sign(secret, payload)),
+        )
+        .body(payload.to_string())
+        .send()
+        .await
+        .map_err(|_| "The bot could not be reached".to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "The bot answered with status {}",
+            response.status()
+        ));
+    }
+
+    let mut body = Vec::new();
+    while body.len() < MAX_REPLY_BYTES {
+        match response.chunk().await.map_err(|e| e.to_string())? {
+            Some(chunk) => body.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(BotReply::default());
+    }
+    if body.len() > MAX_REPLY_BYTES {
+        return Err("The bot's reply is too long".to_string());
+    }
+    serde_json::from_slice(&body).map_err(|_| "The bot's reply is not valid JSON".to_string())
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub usage: String,
+    pub description: String,
+    /// Bot the command is forwarded to; `None` for built-in commands
+    pub bot_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandsResponse {
+    pub status: String,
+    pub commands: Vec<CommandInfo>,
+}
+
+/// Lists the built-in commands and those registered by the workspace's bots
+pub async fn list_commands(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<CommandsResponse> {
+    let mut commands: Vec<CommandInfo> = BuiltinCommand::ALL
+        .iter()
+        .map(|command| CommandInfo {
+            name: command.name().to_string(),
+            usage: command.usage().to_string(),
+            description: command.description().to_string(),
+            bot_id: None,
+        })
+        .collect();
+
+    match bot_commands(&state, auth.workspace_id).await {
+        Ok(registered) => {
+            commands.extend(registered.into_iter().map(|command| CommandInfo {
+                usage: format!("/{}", command.name),
+                name: command.name,
+                description: command.description,
+                bot_id: Some(command.bot_id),
+            }));
+            Json(CommandsResponse {
+                status: "success".to_string(),
+                commands,
+            })
+        }
+        Err(_) => Json(CommandsResponse {
+            status: "error".to_string(),
+            commands: vec![],
+        }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterCommandRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Address the command is posted to when someone runs it
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisterCommandResponse {
+    pub status: String,
+    pub message: String,
+    pub command: Option<BotCommand>,
+    /// Key of the signature of the requests sent to the bot; only returned
+    /// when the command is registered
+    pub secret: Option<String>,
+}
+
+/// Registers a slash command for the calling bot in its workspace. The
+/// answer is the only time the secret its requests are signed with is shown.
+pub async fn register_command(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Json(payload): Json<RegisterCommandRequest>,
+) -> Json<RegisterCommandResponse> {
+    let error = |message: String| {
+        Json(RegisterCommandResponse {
+            status: "error".to_string(),
+            message,
+            command: None,
+            secret: None,
+        })
+    };
+    let pool = &state.pool;
+    let workspace_id = auth.workspace_id;
+    if !bot_in_workspace(pool, auth.user_id, workspace_id).await {
+        return error("Only bots can register commands".to_string());
+    }
+
+    let name = payload
+        .name
+        .trim()
+        .trim_start_matches('/')
+        .to_ascii_lowercase();
+    let valid_name = match parse_chat_input(&format!("/{}", name)) {
+        ChatInput::Command { name: parsed, args } => parsed == name && args.is_empty(),
+        ChatInput::Text(_) => false,
+    };
+    if !valid_name || name.len() > MAX_NAME_LENGTH {
+        return error(format!(
+            "The name must be 1 to {} letters, digits, '_' or '-'",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if BuiltinCommand::parse(&name).is_some() {
+        return error(format!("/{} is a built-in command", name));
+    }
+    let description = payload.description.trim();
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return error(format!(
+            "The description can be at most {} characters",
+            MAX_DESCRIPTION_LENGTH
+        ));
+    }
+    let url = payload.url.trim();
+    let checked = Url::parse(url)
+        .map_err(|e| format!("Invalid URL: {}", e))
+        .and_then(|url| check_url(&url, state.config.webhooks.allow_private_addresses));
+    if let Err(message) = checked {
+        return error(message);
+    }
+    if url.len() > MAX_URL_LENGTH {
+        return error(format!(
+            "The URL can be at most {} characters",
+            MAX_URL_LENGTH
+        ));
+    }
+
+    let secret = generate_secret();
+    // The limit is checked in the same statement so concurrent requests can't exceed it
+    let query_result = sqlx::query_as::<_, (String,)>(
+        "INSERT INTO bot_commands (workspace_id, bot_id, name, description, url, secret)
+         SELECT $1, $2, $3, $4, $5, $6
+         WHERE (SELECT COUNT(*) FROM bot_commands WHERE workspace_id = $1) < $7
+         ON CONFLICT (workspace_id, name) DO NOTHING
+         RETURNING created_at::text",
+    )
+    .bind(workspace_id)
+    .bind(auth.user_id)
+    .bind(&name)
+    .bind(description)
+    .bind(url)
+    .bind(&secret)
+    .bind(MAX_COMMANDS_PER_WORKSPACE)
+    .fetch_optional(pool)
+    .await;
+
+    match query_result {
+        Ok(Some((created_at,))) => {
+            record_event(
+                pool,
+                AuditEvent::CommandRegistered,
+                Some(auth.user_id),
+                None,
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": workspace_id,
+                    "command": name,
+                    "url": url,
+                }),
+            )
+            .await;
+            Json(RegisterCommandResponse {
+                status: "success".to_string(),
+                message: "Command registered".to_string(),
+                command: Some(BotCommand {
+                    name,
+                    description: description.to_string(),
+                    bot_id: auth.user_id,
+                    created_at,
+                }),
+                secret: Some(secret),
+            })
+        }
+        Ok(None) => error(format!(
+            "/{} is already registered, or the workspace has {} commands",
+            name, MAX_COMMANDS_PER_WORKSPACE
+        )),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            error(format!("Failed to register command: {}", e))
+        }
+    }
+}
+
+/// Removes a command of the caller's workspace. Bots can remove their own
+/// commands, and members who manage bots any of them.
+pub async fn delete_command(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Path(name): Path<String>,
+) -> Json<ApiResponse> {
+    let name = name.to_ascii_lowercase();
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "DELETE FROM bot_commands
+         WHERE workspace_id = $1 AND name = $2 AND (bot_id = $3 OR $4)
+         RETURNING bot_id",
+    )
+    .bind(auth.workspace_id)
+    .bind(&name)
+    .bind(auth.user_id)
+    .bind(auth.has(Permission::ManageBots))
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(Some((bot_id,))) => {
+            record_event(
+                &state.pool,
+                AuditEvent::CommandRemoved,
+                Some(auth.user_id),
+                Some(bot_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "workspace_id": auth.workspace_id, "command": name }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Command removed".to_string(),
+            })
+        }
+        Ok(None) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No command you can remove has this name".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to remove command: {}", e),
+            })
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Server settings read from `config.toml` (or the file named by `CONFIG_PATH`).
+/// Every field has a default, so the file and any of its keys may be omitted.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Base URL used when building links sent to users, e.g. in emails
+    pub public_url: String,
+    /// Reject password logins until the user has verified their email address
+    pub require_email_verification: bool,
+    /// Whether anyone can create an account or only people with an invite code
+    pub registration: RegistrationMode,
+    /// Emails of the accounts that are given the admin role on startup
+    pub admins: Vec<String>,
+    pub mail: MailConfig,
+    /// Filters every chat message goes through; reloadable at runtime
+    pub content_filter: ContentFilterConfig,
+    pub link_previews: LinkPreviewConfig,
+    pub webhooks: WebhookConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    Open,
+    InviteOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Deliver through an SMTP relay
+    Smtp,
+    /// Write emails to a file or stdout instead of sending them
+    Log,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// File the log transport appends to; stdout when unset
+    pub log_path: Option<String>,
+}
+
+/// What to do with a message that contains a banned word or matches a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Replace the offending text
+    Mask,
+    /// Refuse the whole message
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ContentFilterConfig {
+    /// Longest message accepted, in characters
+    pub max_length: usize,
+    /// Remove control characters other than newlines and tabs
+    pub strip_control_characters: bool,
+    /// Words matched case-insensitively as whole words
+    pub banned_words: Vec<String>,
+    pub banned_word_action: FilterAction,
+    pub rules: Vec<FilterRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterRule {
+    /// Regular expression, in the syntax of the `regex` crate
+    pub pattern: String,
+    pub action: FilterAction,
+    /// Text that replaces a match when masking
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+    /// Reason given to the sender when rejecting
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LinkPreviewConfig {
+    pub enabled: bool,
+    /// Links previewed per message; further links are ignored
+    pub max_links: usize,
+    /// Time allowed to fetch a page, redirects included
+    pub timeout_seconds: u64,
+    /// Bytes of a page that are read; the rest is ignored
+    pub max_bytes: usize,
+    /// How long fetched previews, and failures, are reused
+    pub cache_hours: i64,
+    /// Allow links to loopback and private network addresses. Only meant for
+    /// development and tests against a local server.
+    pub allow_private_addresses: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Time a webhook has to answer a delivery
+    pub timeout_seconds: u64,
+    /// Deliveries are given up on after this many failed attempts
+    pub max_attempts: i32,
+    /// Allow webhooks on loopback and private network addresses, e.g. for
+    /// tooling running next to the server
+    pub allow_private_addresses: bool,
+}
+
+fn default_replacement() -> String {
+    "***".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            public_url: "http://localhost:8000".to_string(),
+            require_email_verification: false,
+            registration: RegistrationMode::Open,
+            admins: vec![],
+            mail: MailConfig::default(),
+            content_filter: ContentFilterConfig::default(),
+            link_previews: LinkPreviewConfig::default(),
+            webhooks: WebhookConfig::default(),
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransport::Log,
+            from: "Chat Platform <noreply@localhost>".to_string(),
+            smtp_host: "localhost".to_string(),
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password: None,
+            log_path: None,
+        }
+    }
+}
+
+impl Default for ContentFilterConfig {
+    fn default() -> Self {
+        ContentFilterConfig {
+            max_length: 4000,
+            strip_control_characters: true,
+            banned_words: vec![],
+            banned_word_action: FilterAction::Mask,
+            rules: vec![],
+        }
+    }
+}
+
+impl Default for LinkPreviewConfig {
+    fn default() -> Self {
+        LinkPreviewConfig {
+            enabled: true,
+            max_links: 3,
+            timeout_seconds: 5,
+            max_bytes: 512 * 1024,
+            cache_hours: 24,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            timeout_seconds: 10,
+            max_attempts: 10,
+            allow_private_addresses: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration, falling back to defaults when the file does not exist
+    pub fn load() -> Config {
+        Config::read().expect("Failed to parse configuration file.")
+    }
+
+    /// Reads the configuration again, e.g. to reload it while running. Unlike
+    /// `load`, an invalid file is reported instead of panicking.
+    pub fn read() -> Result<Config, String> {
+        let path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                println!("Loading configuration from {}", path);
+                toml::from_str(&contents).map_err(|e| format!("Invalid configuration file: {}", e))
+            }
+            Err(_) => {
+                println!("No configuration file at {}, using defaults", path);
+                Ok(Config::default())
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::websocket_handler::ChatMessage;
+
+/// Instructions sent from the rest of the server to a single live WebSocket connection
+#[derive(Debug, Clone)]
+pub enum ConnectionControl {
+    /// Close the connection, telling the client why
+    Close(String),
+    /// Start delivering events of a room the user just joined
+    JoinRoom(i32),
+    /// Stop delivering events of a room
+    LeaveRoom(i32),
+    /// Send an error frame to the client without closing the connection
+    Error(String),
+    /// Stop delivering messages written by a user the connection's user blocked
+    Block(i32),
+    /// Deliver messages of a user again after they were unblocked
+    Unblock(i32),
+    /// Tell the user they were mentioned in a message, whichever room it is in
+    Mention(ChatMessage),
+    /// Deliver one of the user's reminders, with the message it is about if any
+    Reminder {
+        reminder_id: i32,
+        note: Option<String>,
+        message: Option<ChatMessage>,
+    },
+    /// Show a notice from the server to this user only
+    Notice(Notice),
+}
+
+/// What a notice is about, sent as its `kind` so clients can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeKind {
+    /// Output of a slash command the user ran
+    Command,
+    /// The user was muted in the workspace
+    Muted,
+    /// The user was kicked from a room
+    Kicked,
+    /// A mute or kick of the user was lifted early
+    SanctionLifted,
+    /// Someone else added the user to a room
+    AddedToRoom,
+    /// One of the user's scheduled messages could not be posted
+    ScheduledMessageFailed,
+}
+
+impl NoticeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoticeKind::Command => "command",
+            NoticeKind::Muted => "muted",
+            NoticeKind::Kicked => "kicked",
+            NoticeKind::SanctionLifted => "sanction_lifted",
+            NoticeKind::AddedToRoom => "added_to_room",
+            NoticeKind::ScheduledMessageFailed => "scheduled_message_failed",
+        }
+    }
+}
+
+/// A message from the server for one user. Notices are neither stored nor
+/// broadcast: only the connections they are sent to while open see them.
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub kind: NoticeKind,
+    pub text: String,
+    /// Room the notice is about, if any
+    pub room_id: Option<i32>,
+    /// Details that depend on the kind, e.g. the name of a command
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Notice {
+    pub fn new(kind: NoticeKind, text: String) -> Notice {
+        Notice {
+            kind,
+            text,
+            room_id: None,
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn in_room(mut self, room_id: i32) -> Notice {
+        self.room_id = Some(room_id);
+        self
+    }
+
+    pub fn with_detail(mut self, key: &str, value: impl Into<serde_json::Value>) -> Notice {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// The `data` of the notice's WebSocket frame
+    pub fn data(&self) -> serde_json::Value {
+        let mut data = self.details.clone();
+        data.insert("kind".to_string(), self.kind.as_str().into());
+        data.insert("room_id".to_string(), self.room_id.into());
+        serde_json::Value::Object(data)
+    }
+}
+
+struct ConnectionHandle {
+    user_id: i32,
+    session_id: Uuid,
+    workspace_id: i32,
+    control: mpsc::UnboundedSender<ConnectionControl>,
+}
+
+/// Keeps track of every authenticated WebSocket connection so the rest of the
+/// server can reach them, e.g. to close them when their session is revoked
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, ConnectionHandle>>,
+}
+
+impl ConnectionRegistry {
+    /// Registers a connection and returns its id and the receiving end of its control channel
+    pub fn register(
+        &self,
+        user_id: i32,
+        session_id: Uuid,
+        workspace_id: i32,
+    ) -> (u64, mpsc::UnboundedReceiver<ConnectionControl>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (control, control_rx) = mpsc::unbounded_channel();
+        self.connections.lock().unwrap().insert(
+            id,
+            ConnectionHandle {
+                user_id,
+                session_id,
+                workspace_id,
+                control,
+            },
+        );
+        (id, control_rx)
+    }
+
+    pub fn unregister(&self, connection_id: u64) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+
+    /// Closes every connection opened with the given session
+    pub fn close_session(&self, session_id: Uuid, reason: &str) {
+        self.close_where(reason, |handle| handle.session_id == session_id);
+    }
+
+    /// Closes every connection of a user, optionally sparing one session
+    pub fn close_user(&self, user_id: i32, except_session: Option<Uuid>, reason: &str) {
+        self.close_where(reason, |handle| {
+            handle.user_id == user_id && Some(handle.session_id) != except_session
+        });
+    }
+
+    /// Closes every connection a user opened in one workspace
+    pub fn close_member(&self, workspace_id: i32, user_id: i32, reason: &str) {
+        self.close_where(reason, |handle| {
+            handle.workspace_id == workspace_id && handle.user_id == user_id
+        });
+    }
+
+    /// Ids of the users with at least one connection open in a workspace
+    pub fn connected_user_ids(&self, workspace_id: i32) -> Vec<i32> {
+        let mut user_ids: Vec<i32> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.workspace_id == workspace_id)
+            .map(|handle| handle.user_id)
+            .collect();
+        user_ids.sort_unstable();
+        user_ids.dedup();
+        user_ids
+    }
+
+    /// Every workspace and user pair with at least one open connection
+    pub fn connected_members(&self) -> Vec<(i32, i32)> {
+        let mut members: Vec<(i32, i32)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| (handle.workspace_id, handle.user_id))
+            .collect();
+        members.sort_unstable();
+        members.dedup();
+        members
+    }
+
+    /// Sends an instruction to a single connection
+    pub fn send_to_connection(&self, connection_id: u64, control: ConnectionControl) {
+        if let Some(handle) = self.connections.lock().unwrap().get(&connection_id) {
+            let _ = handle.control.send(control);
+        }
+    }
+
+    /// Sends an instruction to every connection of a user, in any workspace
+    pub fn send_to_user(&self, user_id: i32, control: ConnectionControl) {
+        let connections = self.connections.lock().unwrap();
+        for handle in connections
+            .values()
+            .filter(|handle| handle.user_id == user_id)
+        {
+            let _ = handle.control.send(control.clone());
+        }
+    }
+
+    /// Sends an instruction to every connection a user opened in one workspace
+    pub fn send_to_member(&self, workspace_id: i32, user_id: i32, control: ConnectionControl) {
+        let connections = self.connections.lock().unwrap();
+        for handle in connections
+            .values()
+            .filter(|handle| handle.workspace_id == workspace_id && handle.user_id == user_id)
+        {
+            let _ = handle.control.send(control.clone());
+        }
+    }
+
+    fn close_where(&self, reason: &str, predicate: impl Fn(&ConnectionHandle) -> bool) {
+        let connections = self.connections.lock().unwrap();
+        for handle in connections.values().filter(|handle| predicate(handle)) {
+            let _ = handle
+                .control
+                .send(ConnectionControl::Close(reason.to_string()));
+        }
+    }
+}
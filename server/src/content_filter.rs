@@ -0,0 +1,200 @@
+use axum::extract::{Json, State};
+use regex::Regex;
+use std::sync::{Arc, RwLock};
+
+use crate::AppState;
+use crate::config::{Config, ContentFilterConfig, FilterAction};
+use crate::roles::{Authorized, permission};
+use crate::user_operations::ApiResponse;
+
+/// One step of the content filter. Returns the possibly rewritten message,
+/// or the reason it was rejected.
+trait MessageFilter: Send + Sync {
+    fn apply(&self, content: String) -> Result<String, String>;
+}
+
+/// Removes control characters, which can break clients or hide text
+struct ControlCharacterFilter;
+
+impl MessageFilter for ControlCharacterFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        Ok(content
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+            .collect())
+    }
+}
+
+struct MaxLengthFilter {
+    max_length: usize,
+}
+
+impl MessageFilter for MaxLengthFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        if content.chars().count() > self.max_length {
+            return Err(format!(
+                "Message is longer than {} characters",
+                self.max_length
+            ));
+        }
+        Ok(content)
+    }
+}
+
+struct BannedWordFilter {
+    words: Regex,
+    action: FilterAction,
+}
+
+impl MessageFilter for BannedWordFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        match self.action {
+            FilterAction::Reject if self.words.is_match(&content) => {
+                Err("Message contains a banned word".to_string())
+            }
+            FilterAction::Reject => Ok(content),
+            FilterAction::Mask => Ok(self
+                .words
+                .replace_all(&content, |captures: &regex::Captures| {
+                    "*".repeat(captures[0].chars().count())
+                })
+                .into_owned()),
+        }
+    }
+}
+
+struct RegexFilter {
+    pattern: Regex,
+    action: FilterAction,
+    replacement: String,
+    message: Option<String>,
+}
+
+impl MessageFilter for RegexFilter {
+    fn apply(&self, content: String) -> Result<String, String> {
+        match self.action {
+            FilterAction::Reject if self.pattern.is_match(&content) => Err(self
+                .message
+                .clone()
+                .unwrap_or_else(|| "Message is not allowed".to_string())),
+            FilterAction::Reject => Ok(content),
+            FilterAction::Mask => Ok(self
+                .pattern
+                .replace_all(&content, regex::NoExpand(&self.replacement))
+                .into_owned()),
+        }
+    }
+}
+
+/// The chain of filters every chat message goes through before it is stored
+pub struct ContentFilter {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl ContentFilter {
+    /// Builds the chain from the configuration, failing on invalid patterns
+    pub fn from_config(config: &ContentFilterConfig) -> Result<ContentFilter, String> {
+        let mut filters: Vec<Box<dyn MessageFilter>> = vec![];
+
+        if config.strip_control_characters {
+            filters.push(Box::new(ControlCharacterFilter));
+        }
+
+        filters.push(Box::new(MaxLengthFilter {
+            max_length: config.max_length,
+        }));
+
+        let words: Vec<String> = config
+            .banned_words
+            .iter()
+            .map(|word| word.trim())
+            .filter(|word| !word.is_empty())
+            .map(regex::escape)
+            .collect();
+        if !words.is_empty() {
+            let pattern = format!(r"(?i)\b(?:{})\b", words.join("|"));
+            filters.push(Box::new(BannedWordFilter {
+                words: Regex::new(&pattern)
+                    .map_err(|e| format!("Invalid banned word list: {}", e))?,
+                action: config.banned_word_action,
+            }));
+        }
+
+        for rule in &config.rules {
+            filters.push(Box::new(RegexFilter {
+                pattern: Regex::new(&rule.pattern)
+                    .map_err(|e| format!("Invalid filter rule {:?}: {}", rule.pattern, e))?,
+                action: rule.action,
+                replacement: rule.replacement.clone(),
+                message: rule.message.clone(),
+            }));
+        }
+
+        Ok(ContentFilter { filters })
+    }
+
+    /// Runs a message through every filter in order
+    pub fn apply(&self, content: &str) -> Result<String, String> {
+        self.filters
+            .iter()
+            .try_fold(content.to_string(), |content, filter| filter.apply(content))
+    }
+}
+
+/// The filter currently in use, swapped out when the configuration is reloaded
+pub type SharedContentFilter = RwLock<Arc<ContentFilter>>;
+
+/// Filters a chat message with the current configuration
+pub fn filter_message(state: &AppState, content: &str) -> Result<String, String> {
+    let filter = state.content_filter.read().unwrap().clone();
+    filter.apply(content)
+}
+
+/// Reads the configuration file again and replaces the content filter. The
+/// old filter stays in place if the new configuration is invalid.
+pub fn reload_content_filter(state: &AppState) -> Result<(), String> {
+    let config = Config::read()?;
+    let filter = ContentFilter::from_config(&config.content_filter)?;
+    *state.content_filter.write().unwrap() = Arc::new(filter);
+    Ok(())
+}
+
+/// Reloads the content filter every time the process receives SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup(state: Arc<AppState>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match reload_content_filter(&state) {
+            Ok(()) => println!("Content filter reloaded"),
+            Err(e) => eprintln!("Failed to reload content filter: {}", e),
+        }
+    }
+}
+
+pub async fn reload_config(
+    State(state): State<Arc<AppState>>,
+    _admin: Authorized<permission::ManageRoles>,
+) -> Json<ApiResponse> {
+    match reload_content_filter(&state) {
+        Ok(()) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Content filter reloaded".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Failed to reload content filter: {}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: e,
+            })
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use axum::{
+    extract::{ConnectInfo, Json, Query, State},
+    http::header,
+    response::{Html, IntoResponse},
+};
+use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::mailer::Email;
+use crate::session_operations::{generate_token, hash_token, revoke_user_sessions};
+use crate::user_operations::{ApiResponse, hash_password};
+
+const PURPOSE_VERIFY_EMAIL: &str = "verify_email";
+const PURPOSE_PASSWORD_RESET: &str = "password_reset";
+
+const VERIFY_EMAIL_TTL_HOURS: i32 = 24;
+const PASSWORD_RESET_TTL_HOURS: i32 = 1;
+
+/// Emails of one kind that can be requested for an address per window
+const MAX_REQUESTS_PER_EMAIL: i64 = 3;
+/// Emails of any kind that can be requested from one IP address per window
+const MAX_REQUESTS_PER_IP: i64 = 10;
+const REQUEST_WINDOW_MINUTES: i32 = 60;
+
+/// Basic shape check for an email address: `local@domain.tld` without whitespace
+pub fn is_valid_email(email: &str) -> bool {
+    if email.len() > 100 || email.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return false;
+    }
+
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        }
+        None => false,
+    }
+}
+
+/// Stores a new single-use token for `user_id` and returns it in plain text
+async fn create_email_token(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+    purpose: &str,
+    ttl_hours: i32,
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+    sqlx::query(
+        "INSERT INTO email_tokens (user_id, purpose, token_hash, expires_at)
+         VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(hours => $4))",
+    )
+    .bind(user_id)
+    .bind(purpose)
+    .bind(hash_token(&token))
+    .bind(ttl_hours)
+    .execute(pool)
+    .await?;
+    Ok(token)
+}
+
+/// Marks a token as used and returns its user, if it is valid, unused and not expired
+async fn consume_email_token(
+    pool: &Pool<Postgres>,
+    token: &str,
+    purpose: &str,
+) -> Result<Option<i32>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (i32,)>(
+        "UPDATE email_tokens SET used_at = CURRENT_TIMESTAMP
+         WHERE token_hash = $1 AND purpose = $2
+           AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+         RETURNING user_id",
+    )
+    .bind(hash_token(token))
+    .bind(purpose)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(user_id,)| user_id))
+}
+
+/// Emails a verification link to a freshly registered user
+pub async fn send_verification_email(state: &AppState, user_id: i32, email: &str) {
+    let token = match create_email_token(
+        &state.pool,
+        user_id,
+        PURPOSE_VERIFY_EMAIL,
+        VERIFY_EMAIL_TTL_HOURS,
+    )
+    .await
+    {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return;
+        }
+    };
+
+    let link = format!("{}/verify_email?token={}", state.config.public_url, token);
+    let result = state
+        .mailer
+        .send(Email {
+            to: email.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Welcome! Confirm your email address by opening this link:\n\n{}\n\nThe link expires in {} hours.",
+                link, VERIFY_EMAIL_TTL_HOURS
+            ),
+        })
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to send verification email to {}: {}", email, e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+pub async fn verify_email(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<VerifyEmailQuery>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+    let user_id = match consume_email_token(pool, &params.token, PURPOSE_VERIFY_EMAIL).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "Invalid or expired verification link".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to verify email: {}", e),
+            });
+        }
+    };
+
+    let query_result = sqlx::query("UPDATE users SET email_verified = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await;
+
+    match query_result {
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Email verified successfully".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to verify email: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailRequest {
+    pub email: String,
+}
+
+/// Looks up a user by email, returning their id and verification state
+async fn find_user_by_email(pool: &Pool<Postgres>, email: &str) -> Option<(i32, bool)> {
+    let result =
+        sqlx::query_as::<_, (i32, bool)>("SELECT id, email_verified FROM users WHERE email = $1")
+            .bind(email)
+            .fetch_optional(pool)
+            .await;
+
+    match result {
+        Ok(row) => row,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Records a request for an email unless the address or the IP address has
+/// asked for too many lately. Requests are counted whether or not the
+/// account exists, so the limit doesn't tell which addresses are registered.
+async fn allow_email_request(
+    pool: &Pool<Postgres>,
+    purpose: &str,
+    email: &str,
+    ip_address: &str,
+) -> bool {
+    // Counted in the same statement so concurrent requests can't exceed the limits
+    let result = sqlx::query_as::<_, (i64,)>(
+        "INSERT INTO email_requests (purpose, email, ip_address)
+         SELECT $1, $2, $3
+         WHERE (
+             SELECT COUNT(*) FROM email_requests
+             WHERE purpose = $1 AND LOWER(email) = LOWER($2)
+               AND requested_at > CURRENT_TIMESTAMP - make_interval(mins => $4)
+         ) < $5
+         AND (
+             SELECT COUNT(*) FROM email_requests
+             WHERE ip_address = $3
+               AND requested_at > CURRENT_TIMESTAMP - make_interval(mins => $4)
+         ) < $6
+         RETURNING id",
+    )
+    .bind(purpose)
+    .bind(email)
+    .bind(ip_address)
+    .bind(REQUEST_WINDOW_MINUTES)
+    .bind(MAX_REQUESTS_PER_EMAIL)
+    .bind(MAX_REQUESTS_PER_IP)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(row) => row.is_some(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+fn too_many_requests() -> Json<ApiResponse> {
+    Json(ApiResponse {
+        status: "error".to_string(),
+        message: "Too many emails requested, try again later".to_string(),
+    })
+}
+
+pub async fn resend_verification_email(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<EmailRequest>,
+) -> Json<ApiResponse> {
+    let ip_address = addr.ip().to_string();
+    if !allow_email_request(
+        &state.pool,
+        PURPOSE_VERIFY_EMAIL,
+        &payload.email,
+        &ip_address,
+    )
+    .await
+    {
+        return too_many_requests();
+    }
+    if let Some((user_id, false)) = find_user_by_email(&state.pool, &payload.email).await {
+        send_verification_email(&state, user_id, &payload.email).await;
+    }
+
+    // Same answer whether or not the account exists, so emails can't be enumerated
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "If the account exists and is unverified, a verification email has been sent"
+            .to_string(),
+    })
+}
+
+pub async fn request_password_reset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<EmailRequest>,
+) -> Json<ApiResponse> {
+    let ip_address = addr.ip().to_string();
+    if !allow_email_request(
+        &state.pool,
+        PURPOSE_PASSWORD_RESET,
+        &payload.email,
+        &ip_address,
+    )
+    .await
+    {
+        return too_many_requests();
+    }
+    if let Some((user_id, _)) = find_user_by_email(&state.pool, &payload.email).await {
+        match create_email_token(
+            &state.pool,
+            user_id,
+            PURPOSE_PASSWORD_RESET,
+            PASSWORD_RESET_TTL_HOURS,
+        )
+        .await
+        {
+            Ok(token) => {
+                let link = format!("{}/password_reset?token={}", state.config.public_url, token);
+                let result = state
+                    .mailer
+                    .send(Email {
+                        to: payload.email.clone(),
+                        subject: "Reset your password".to_string(),
+                        body: format!(
+                            "Someone asked to reset the password of your account. Choose a new password by opening this link:\n\n{}\n\nIt expires in {} hour. If this wasn't you, ignore this email.",
+                            link, PASSWORD_RESET_TTL_HOURS
+                        ),
+                    })
+                    .await;
+                if let Err(e) = result {
+                    eprintln!(
+                        "Failed to send password reset email to {}: {}",
+                        payload.email, e
+                    );
+                }
+            }
+            Err(e) => eprintln!("Database error: {:?}", e),
+        }
+    }
+
+    // Same answer whether or not the account exists, so emails can't be enumerated
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "If the account exists, a password reset email has been sent".to_string(),
+    })
+}
+
+/// Page the reset link opens: it asks for the new password and sends it to
+/// `/password_reset/confirm` with the token from the link
+pub async fn password_reset_page() -> impl IntoResponse {
+    (
+        // Keeps the token out of the Referer header of anything the page loads
+        [(header::REFERRER_POLICY, "no-referrer")],
+        Html(include_str!("password_reset.html")),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmPasswordResetRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+pub async fn confirm_password_reset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ConfirmPasswordResetRequest>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+    let user_id = match consume_email_token(pool, &payload.token, PURPOSE_PASSWORD_RESET).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "Invalid or expired reset token".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to reset password: {}", e),
+            });
+        }
+    };
+
+    // The reset link reached the user's inbox, which also proves the address
+    let query_result =
+        sqlx::query("UPDATE users SET password_hash = $1, email_verified = TRUE WHERE id = $2")
+            .bind(hash_password(&payload.new_password))
+            .bind(user_id)
+            .execute(pool)
+            .await;
+
+    if let Err(e) = query_result {
+        eprintln!("Database error: {:?}", e);
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: format!("Failed to reset password: {}", e),
+        });
+    }
+
+    // Invalidate any other outstanding reset tokens and sign out everywhere
+    let _ = sqlx::query(
+        "UPDATE email_tokens SET used_at = CURRENT_TIMESTAMP
+         WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(PURPOSE_PASSWORD_RESET)
+    .execute(pool)
+    .await;
+
+    if let Err(e) = revoke_user_sessions(&state, user_id, None).await {
+        eprintln!("Database error: {:?}", e);
+    }
+
+    record_event(
+        pool,
+        AuditEvent::PasswordReset,
+        Some(user_id),
+        Some(user_id),
+        Some(&addr.ip().to_string()),
+        serde_json::json!({}),
+    )
+    .await;
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "Password reset successfully".to_string(),
+    })
+}
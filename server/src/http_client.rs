@@ -0,0 +1,222 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{Url, redirect};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Builds a client for requests to addresses given by users, such as links in
+/// messages and webhooks. Unless `allow_private_addresses` is set, it refuses
+/// to connect to loopback, private network, link-local and other non-public
+/// addresses, including through redirects and host names that resolve to them.
+pub fn build_http_client(
+    user_agent: &str,
+    timeout: Duration,
+    max_redirects: usize,
+    allow_private_addresses: bool,
+) -> reqwest::Client {
+    let redirects = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects {
+            attempt.error("Too many redirects")
+        } else if let Err(e) = check_url(attempt.url(), allow_private_addresses) {
+            attempt.error(e)
+        } else {
+            attempt.follow()
+        }
+    });
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .timeout(timeout)
+        .redirect(redirects)
+        // A proxy would resolve host names itself, without our checks
+        .no_proxy()
+        .dns_resolver(Arc::new(PublicResolver {
+            allow_private_addresses,
+        }))
+        .build()
+        .expect("Failed to build HTTP client.")
+}
+
+/// Resolves host names to their public addresses only, so that a name can't
+/// be used to reach the server's own network. The connection is made to the
+/// addresses checked here, which also rules out DNS rebinding.
+struct PublicResolver {
+    allow_private_addresses: bool,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_private_addresses = self.allow_private_addresses;
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|address| allow_private_addresses || is_public(address.ip()))
+                .collect();
+            if addresses.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Checks the parts of a URL that are known before connecting: only http and
+/// https are fetched, and hosts given as IP addresses must be public. Host
+/// names are checked by the resolver.
+pub fn check_url(url: &Url, allow_private_addresses: bool) -> Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Unsupported scheme: {}", url.scheme()));
+    }
+    let host = url.host_str().ok_or("Missing host")?;
+    let ip = host.trim_start_matches('[').trim_end_matches(']');
+    match ip.parse::<IpAddr>() {
+        Ok(ip) if !allow_private_addresses && !is_public(ip) => {
+            Err(format!("{} is not a public address", ip))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether an address is reachable on the public internet, as opposed to
+/// loopback, private, shared, link-local, documentation, multicast and
+/// reserved ranges
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                // Shared address space (100.64.0.0/10)
+                || (a == 100 && (b & 0xc0) == 64)
+                // IETF protocol assignments (192.0.0.0/24)
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking (198.18.0.0/15)
+                || (a == 198 && (b & 0xfe) == 18))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let segments = ip.segments();
+            // NAT64 (64:ff9b::/96) embeds an IPv4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public(IpAddr::from([a, b, c, d]));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local (fc00::/7)
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local (fe80::/10)
+                || (segments[0] & 0xffc0) == 0xfe80
+                // Documentation (2001:db8::/32)
+                || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn public_ipv4_addresses_are_allowed() {
+        for address in [
+            "8.8.8.8",
+            "1.1.1.1",
+            "93.184.216.34",
+            "100.128.0.1",
+            "172.32.0.1",
+        ] {
+            assert!(is_public(ip(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn non_public_ipv4_addresses_are_refused() {
+        for address in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "100.127.255.255",
+            "192.0.0.8",
+            "192.0.2.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_public(ip(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn public_ipv6_addresses_are_allowed() {
+        for address in [
+            "2606:4700:4700::1111",
+            "2a00:1450:4001::200e",
+            "64:ff9b::808:808",
+        ] {
+            assert!(is_public(ip(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn non_public_ipv6_addresses_are_refused() {
+        for address in [
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "2001:db8::1",
+            // IPv4 addresses in disguise
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public(ip(address)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn check_url_refuses_other_schemes_and_private_hosts() {
+        let check = |url: &str, allow_private_addresses| {
+            check_url(&Url::parse(url).unwrap(), allow_private_addresses)
+        };
+
+        assert!(check("https://example.com/page", false).is_ok());
+        assert!(check("http://93.184.216.34/", false).is_ok());
+        assert!(check("ftp://example.com/file", false).is_err());
+        assert!(check("file:///etc/passwd", false).is_err());
+        assert!(check("http://127.0.0.1:8000/", false).is_err());
+        assert!(check("http://[::1]/", false).is_err());
+        assert!(check("http://[::ffff:192.168.0.1]/", false).is_err());
+        // Host names are left to the resolver
+        assert!(check("http://localhost/", false).is_ok());
+
+        assert!(check("http://127.0.0.1:8000/", true).is_ok());
+        assert!(check("ftp://127.0.0.1/", true).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::bots::bot_in_workspace;
+use crate::message_operations::post_message;
+use crate::roles::{Authorized, RoomRole, permission};
+use crate::room_operations::{add_room_member, check_can_join};
+use crate::session_operations::{generate_token, hash_token};
+use crate::user_operations::ApiResponse;
+
+/// Path incoming webhook URLs start with, followed by their token
+const HOOK_PATH: &str = "/hooks/";
+
+/// An incoming webhook: a secret URL that posts into a room as a bot
+#[derive(Debug, Serialize)]
+pub struct IncomingWebhook {
+    pub id: i32,
+    pub bot_id: i32,
+    pub room_id: i32,
+    pub created_by: Option<i32>,
+    pub created_at: String,
+    /// Only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IncomingWebhooksResponse {
+    pub status: String,
+    pub webhooks: Vec<IncomingWebhook>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IncomingWebhookResponse {
+    pub status: String,
+    pub message: String,
+    pub webhook: Option<IncomingWebhook>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateIncomingWebhookRequest {
+    /// Bot of the workspace the messages are posted as
+    pub bot_id: i32,
+    pub room_id: i32,
+}
+
+/// Lists the incoming webhooks of the caller's workspace, without their URLs
+pub async fn list_incoming_webhooks(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageBots>,
+) -> Json<IncomingWebhooksResponse> {
+    let query_result = sqlx::query_as::<_, (i32, i32, i32, Option<i32>, String)>(
+        "SELECT w.id, w.bot_id, w.room_id, w.created_by, w.created_at::text
+         FROM incoming_webhooks w
+         JOIN bots b ON b.user_id = w.bot_id
+         WHERE b.workspace_id = $1
+         ORDER BY w.id",
+    )
+    .bind(admin.auth.workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(IncomingWebhooksResponse {
+            status: "success".to_string(),
+            webhooks: rows
+                .into_iter()
+                .map(
+                    |(id, bot_id, room_id, created_by, created_at)| IncomingWebhook {
+                        id,
+                        bot_id,
+                        room_id,
+                        created_by,
+                        created_at,
+                        url: None,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(IncomingWebhooksResponse {
+                status: "error".to_string(),
+                webhooks: vec![],
+            })
+        }
+    }
+}
+
+/// Creates an incoming webhook that posts into a room of the caller's
+/// workspace as one of its bots, adding the bot to the room. The answer is
+/// the only time the webhook's URL is shown.
+pub async fn create_incoming_webhook(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageBots>,
+    Json(payload): Json<CreateIncomingWebhookRequest>,
+) -> Json<IncomingWebhookResponse> {
+    let error = |message: String| {
+        Json(IncomingWebhookResponse {
+            status: "error".to_string(),
+            message,
+            webhook: None,
+        })
+    };
+    let pool = &state.pool;
+    let workspace_id = admin.auth.workspace_id;
+
+    if !bot_in_workspace(pool, payload.bot_id, workspace_id).await {
+        return error("No bot found with this id".to_string());
+    }
+    // The bot can read the room's history, so private rooms need the caller to be in them
+    if let Err(reason) =
+        check_can_join(pool, workspace_id, payload.room_id, admin.auth.user_id).await
+    {
+        return error(reason);
+    }
+    if let Err(e) = add_room_member(pool, payload.room_id, payload.bot_id, RoomRole::Member).await {
+        eprintln!("Database error: {:?}", e);
+        return error(format!("Failed to add the bot to the room: {}", e));
+    }
+
+    let token = generate_token();
+    let query_result = sqlx::query_as::<_, (i32, String)>(
+        "INSERT INTO incoming_webhooks (bot_id, room_id, token_hash, created_by)
+         VALUES ($1, $2, $3, $4)
+         RETURNING id, created_at::text",
+    )
+    .bind(payload.bot_id)
+    .bind(payload.room_id)
+    .bind(hash_token(&token))
+    .bind(admin.auth.user_id)
+    .fetch_one(pool)
+    .await;
+
+    match query_result {
+        Ok((id, created_at)) => {
+            record_event(
+                pool,
+                AuditEvent::IncomingWebhookCreated,
+                Some(admin.auth.user_id),
+                Some(payload.bot_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": workspace_id,
+                    "webhook_id": id,
+                    "room_id": payload.room_id,
+                }),
+            )
+            .await;
+            Json(IncomingWebhookResponse {
+                status: "success".to_string(),
+                message: "Incoming webhook created".to_string(),
+                webhook: Some(IncomingWebhook {
+                    id,
+                    bot_id: payload.bot_id,
+                    room_id: payload.room_id,
+                    created_by: Some(admin.auth.user_id),
+                    created_at,
+                    url: Some(format!(
+                        "{}{}{}",
+                        state.config.public_url.trim_end_matches('/'),
+                        HOOK_PATH,
+                        token
+                    )),
+                }),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            error(format!("Failed to create incoming webhook: {}", e))
+        }
+    }
+}
+
+/// Removes an incoming webhook of the caller's workspace; its URL stops working
+pub async fn delete_incoming_webhook(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageBots>,
+    Path(webhook_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query_as::<_, (i32, i32)>(
+        "DELETE FROM incoming_webhooks w USING bots b
+         WHERE w.id = $1 AND b.user_id = w.bot_id AND b.workspace_id = $2
+         RETURNING w.bot_id, w.room_id",
+    )
+    .bind(webhook_id)
+    .bind(admin.auth.workspace_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(Some((bot_id, room_id))) => {
+            record_event(
+                &state.pool,
+                AuditEvent::IncomingWebhookDeleted,
+                Some(admin.auth.user_id),
+                Some(bot_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": admin.auth.workspace_id,
+                    "webhook_id": webhook_id,
+                    "room_id": room_id,
+                }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Incoming webhook deleted".to_string(),
+            })
+        }
+        Ok(None) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No incoming webhook found with this id".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to delete incoming webhook: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IncomingMessage {
+    /// `text` is accepted too, which many alerting tools send
+    #[serde(alias = "text")]
+    pub content: String,
+}
+
+/// Posts a message into the room of the incoming webhook whose token is in
+/// the path, as its bot. The message goes through the same checks and
+/// content filter as any other. Unlike other routes, failures are reported
+/// with an HTTP error status, so callers such as CI scripts notice them.
+pub async fn post_incoming_webhook(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+    Json(payload): Json<IncomingMessage>,
+) -> (StatusCode, Json<ApiResponse>) {
+    let response = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(ApiResponse {
+                status: if status.is_success() {
+                    "success"
+                } else {
+                    "error"
+                }
+                .to_string(),
+                message,
+            }),
+        )
+    };
+
+    if payload.content.trim().is_empty() {
+        return response(
+            StatusCode::BAD_REQUEST,
+            "The message can't be empty".to_string(),
+        );
+    }
+
+    let query_result = sqlx::query_as::<_, (i32, i32, i32)>(
+        "SELECT w.bot_id, w.room_id, b.workspace_id
+         FROM incoming_webhooks w
+         JOIN bots b ON b.user_id = w.bot_id
+         WHERE w.token_hash = $1",
+    )
+    .bind(hash_token(&token))
+    .fetch_optional(&state.pool)
+    .await;
+
+    let (bot_id, room_id, workspace_id) = match query_result {
+        Ok(Some(hook)) => hook,
+        Ok(None) => {
+            return response(
+                StatusCode::NOT_FOUND,
+                "No incoming webhook found".to_string(),
+            );
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to post message".to_string(),
+            );
+        }
+    };
+
+    match post_message(
+        &state,
+        workspace_id,
+        bot_id,
+        room_id,
+        &payload.content,
+        None,
+    )
+    .await
+    {
+        Ok(message) => response(StatusCode::OK, format!("Message {} posted", message.id)),
+        Err(message) => response(StatusCode::BAD_REQUEST, message),
+    }
+}
@@ -0,0 +1,403 @@
+use axum::extract::{Json, Path, State};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::connections::ConnectionControl;
+use crate::roles::{Permission, RoomRole, has_room_permission};
+use crate::room_operations::add_room_member;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::workspaces::{WorkspaceRole, add_workspace_member};
+
+const INVITE_CODE_LENGTH: usize = 12;
+
+/// Condition shared by every query that looks for usable invites
+const USABLE_INVITE: &str = "revoked_at IS NULL
+    AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+    AND (max_uses IS NULL OR uses < max_uses)";
+
+fn generate_invite_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(INVITE_CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// An invite that was just redeemed
+#[derive(Debug, Clone)]
+pub struct RedeemedInvite {
+    /// Workspace the invite grants membership of
+    pub workspace_id: i32,
+    /// Room the invite grants membership of, if any
+    pub room_id: Option<i32>,
+}
+
+/// Uses up one redemption of an invite, or returns `None` if the code is
+/// unknown, revoked, expired or used up
+pub async fn consume_invite<'e, E>(
+    executor: E,
+    code: &str,
+) -> Result<Option<RedeemedInvite>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let row = sqlx::query_as::<_, (i32, Option<i32>)>(&format!(
+        "UPDATE invites SET uses = uses + 1
+         WHERE code = $1 AND {}
+         RETURNING workspace_id, room_id",
+        USABLE_INVITE
+    ))
+    .bind(code.trim())
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row.map(|(workspace_id, room_id)| RedeemedInvite {
+        workspace_id,
+        room_id,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteRequest {
+    /// Room the invite grants membership of; an invite to the workspace alone when omitted
+    pub room_id: Option<i32>,
+    /// Number of times the invite can be used; unlimited when omitted
+    pub max_uses: Option<i32>,
+    /// Hours until the invite expires; never when omitted
+    pub expires_in_hours: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateInviteResponse {
+    pub status: String,
+    pub message: String,
+    pub code: Option<String>,
+    /// Link that shows what the invite is for
+    pub link: Option<String>,
+}
+
+impl CreateInviteResponse {
+    fn error(message: String) -> Json<CreateInviteResponse> {
+        Json(CreateInviteResponse {
+            status: "error".to_string(),
+            message,
+            code: None,
+            link: None,
+        })
+    }
+}
+
+/// Creates an invite to the caller's workspace. Admins can create any invite;
+/// room owners can create invites to their rooms.
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Json<CreateInviteResponse> {
+    let allowed = match payload.room_id {
+        Some(room_id) => {
+            has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await
+        }
+        None => auth.has(Permission::ManageInvites),
+    };
+    if !allowed {
+        return CreateInviteResponse::error(
+            "You don't have permission to create this invite".to_string(),
+        );
+    }
+
+    if payload.max_uses.is_some_and(|uses| uses <= 0)
+        || payload.expires_in_hours.is_some_and(|hours| hours <= 0)
+    {
+        return CreateInviteResponse::error(
+            "max_uses and expires_in_hours must be positive".to_string(),
+        );
+    }
+
+    let code = generate_invite_code();
+    let query_result = sqlx::query(
+        "INSERT INTO invites (code, created_by, workspace_id, room_id, max_uses, expires_at)
+         VALUES ($1, $2, $3, $4, $5, CURRENT_TIMESTAMP + make_interval(hours => $6))",
+    )
+    .bind(&code)
+    .bind(auth.user_id)
+    .bind(auth.workspace_id)
+    .bind(payload.room_id)
+    .bind(payload.max_uses)
+    .bind(payload.expires_in_hours)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(_) => Json(CreateInviteResponse {
+            status: "success".to_string(),
+            message: "Invite created".to_string(),
+            link: Some(format!("{}/invites/{}", state.config.public_url, code)),
+            code: Some(code),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            CreateInviteResponse::error(format!("Failed to create invite: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteInfo {
+    pub code: String,
+    pub created_by: Option<i32>,
+    pub room_id: Option<i32>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<String>,
+    pub revoked: bool,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvitesResponse {
+    pub status: String,
+    pub invites: Vec<InviteInfo>,
+}
+
+/// Lists the caller's invites, or every invite of the workspace for admins
+pub async fn list_invites(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<InvitesResponse> {
+    let created_by = (!auth.has(Permission::ManageInvites)).then_some(auth.user_id);
+
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            String,
+            Option<i32>,
+            Option<i32>,
+            Option<i32>,
+            i32,
+            Option<String>,
+            bool,
+            String,
+        ),
+    >(
+        "SELECT code, created_by, room_id, max_uses, uses, expires_at::text,
+                revoked_at IS NOT NULL, created_at::text
+         FROM invites
+         WHERE workspace_id = $1 AND ($2::INT IS NULL OR created_by = $2)
+         ORDER BY created_at DESC",
+    )
+    .bind(auth.workspace_id)
+    .bind(created_by)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(InvitesResponse {
+            status: "success".to_string(),
+            invites: rows
+                .into_iter()
+                .map(
+                    |(
+                        code,
+                        created_by,
+                        room_id,
+                        max_uses,
+                        uses,
+                        expires_at,
+                        revoked,
+                        created_at,
+                    )| {
+                        InviteInfo {
+                            code,
+                            created_by,
+                            room_id,
+                            max_uses,
+                            uses,
+                            expires_at,
+                            revoked,
+                            created_at,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(InvitesResponse {
+                status: "error".to_string(),
+                invites: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvitePreviewResponse {
+    pub status: String,
+    pub message: String,
+    pub workspace_name: Option<String>,
+    pub room_id: Option<i32>,
+    pub room_name: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+/// Shows what an invite is for, without using it up. This is where invite links point.
+pub async fn preview_invite(
+    State(state): State<Arc<AppState>>,
+    Path(code): Path<String>,
+) -> Json<InvitePreviewResponse> {
+    let query_result =
+        sqlx::query_as::<_, (String, Option<i32>, Option<String>, Option<String>)>(&format!(
+            "SELECT w.name, i.room_id, r.name, i.expires_at::text
+             FROM (SELECT * FROM invites WHERE code = $1 AND {}) i
+             JOIN workspaces w ON w.id = i.workspace_id
+             LEFT JOIN rooms r ON r.id = i.room_id",
+            USABLE_INVITE
+        ))
+        .bind(&code)
+        .fetch_optional(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(Some((workspace_name, room_id, room_name, expires_at))) => Json(InvitePreviewResponse {
+            status: "success".to_string(),
+            message: match &room_name {
+                Some(name) => format!("You are invited to join #{} in {}", name, workspace_name),
+                None => format!("You are invited to join {}", workspace_name),
+            },
+            workspace_name: Some(workspace_name),
+            room_id,
+            room_name,
+            expires_at,
+        }),
+        Ok(None) => Json(InvitePreviewResponse {
+            status: "error".to_string(),
+            message: "This invite is invalid or has expired".to_string(),
+            workspace_name: None,
+            room_id: None,
+            room_name: None,
+            expires_at: None,
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(InvitePreviewResponse {
+                status: "error".to_string(),
+                message: format!("Failed to look up invite: {}", e),
+                workspace_name: None,
+                room_id: None,
+                room_name: None,
+                expires_at: None,
+            })
+        }
+    }
+}
+
+/// Redeems an invite for an existing account, making the caller a member of
+/// its workspace and room. The caller logs in to the workspace to use it.
+pub async fn redeem_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(code): Path<String>,
+) -> Json<ApiResponse> {
+    let invite = match consume_invite(&state.pool, &code).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "This invite is invalid or has expired".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to redeem invite: {}", e),
+            });
+        }
+    };
+
+    if let Err(e) = add_workspace_member(
+        &state.pool,
+        invite.workspace_id,
+        auth.user_id,
+        WorkspaceRole::Member,
+    )
+    .await
+    {
+        eprintln!("Database error: {:?}", e);
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: format!("Failed to join workspace: {}", e),
+        });
+    }
+
+    let Some(room_id) = invite.room_id else {
+        return Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("Joined workspace {}", invite.workspace_id),
+        });
+    };
+
+    match add_room_member(&state.pool, room_id, auth.user_id, RoomRole::Member).await {
+        Ok(()) => {
+            state.connections.send_to_member(
+                invite.workspace_id,
+                auth.user_id,
+                ConnectionControl::JoinRoom(room_id),
+            );
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: format!("Joined room {}", room_id),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to join room: {}", e),
+            })
+        }
+    }
+}
+
+/// Revokes an invite so it can't be used any more
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(code): Path<String>,
+) -> Json<ApiResponse> {
+    let created_by = (!auth.has(Permission::ManageInvites)).then_some(auth.user_id);
+
+    let query_result = sqlx::query(
+        "UPDATE invites SET revoked_at = CURRENT_TIMESTAMP
+         WHERE code = $1 AND workspace_id = $2 AND revoked_at IS NULL
+           AND ($3::INT IS NULL OR created_by = $3)",
+    )
+    .bind(&code)
+    .bind(auth.workspace_id)
+    .bind(created_by)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No active invite of yours found with this code".to_string(),
+        }),
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Invite revoked".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to revoke invite: {}", e),
+            })
+        }
+    }
+}
@@ -0,0 +1,708 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use regex::{Captures, Regex};
+use reqwest::Url;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::{Semaphore, mpsc};
+
+use crate::AppState;
+use crate::config::LinkPreviewConfig;
+use crate::http_client::{build_http_client, check_url};
+use crate::websocket_handler::{ChatMessage, ServerEvent};
+
+const MAX_REDIRECTS: usize = 3;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_TITLE_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+/// Messages waiting for their previews; messages posted while it is full get none
+const QUEUE_SIZE: usize = 256;
+/// Messages whose links are fetched at the same time
+const CONCURRENT_JOBS: usize = 4;
+const USER_AGENT: &str = "ChatPlatformBot/1.0 (link previews)";
+
+/// What a link points to, from the page's OpenGraph tags or, failing that,
+/// its title and description. The texts are plain text and must be escaped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkPreview {
+    /// The link as it appears in the message
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub site_name: Option<String>,
+}
+
+/// An HTML page, read up to the configured size
+pub struct FetchedPage {
+    /// Address of the page after redirects
+    pub url: String,
+    pub html: String,
+}
+
+/// Fetches the pages that links in messages point to
+#[async_trait]
+pub trait LinkFetcher: Send + Sync {
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, String>;
+}
+
+/// Builds the fetcher used for link previews
+pub fn build_link_fetcher(config: &LinkPreviewConfig) -> Arc<dyn LinkFetcher> {
+    Arc::new(HttpFetcher::new(config))
+}
+
+/// Fetches pages over HTTP and HTTPS, from public addresses only unless
+/// private addresses are allowed
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    max_bytes: usize,
+    allow_private_addresses: bool,
+}
+
+impl HttpFetcher {
+    pub fn new(config: &LinkPreviewConfig) -> HttpFetcher {
+        HttpFetcher {
+            client: build_http_client(
+                USER_AGENT,
+                Duration::from_secs(config.timeout_seconds),
+                MAX_REDIRECTS,
+                config.allow_private_addresses,
+            ),
+            max_bytes: config.max_bytes,
+            allow_private_addresses: config.allow_private_addresses,
+        }
+    }
+}
+
+#[async_trait]
+impl LinkFetcher for HttpFetcher {
+    async fn fetch(&self, url: &str) -> Result<FetchedPage, String> {
+        let url = Url::parse(url).map_err(|e| e.to_string())?;
+        check_url(&url, self.allow_private_addresses)?;
+
+        let mut response = self
+            .client
+            .get(url)
+            .header(ACCEPT, "text/html,application/xhtml+xml")
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Status {}", response.status()));
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if !content_type.starts_with("text/html")
+            && !content_type.starts_with("application/xhtml+xml")
+        {
+            return Err(format!("Not an HTML page: {}", content_type));
+        }
+
+        // The tags previews are made of are at the top of the page
+        let url = response.url().to_string();
+        let mut body = Vec::new();
+        while body.len() < self.max_bytes {
+            match response.chunk().await.map_err(|e| e.to_string())? {
+                Some(chunk) => body.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        body.truncate(self.max_bytes);
+
+        Ok(FetchedPage {
+            url,
+            html: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
+
+fn url_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r#"(?i)\bhttps?://[^\s<>"'`]+"#).unwrap())
+}
+
+fn meta_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?is)<meta\s[^>]*>").unwrap())
+}
+
+fn attribute_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r#"\s([a-zA-Z:_-]+)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap())
+}
+
+fn title_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap())
+}
+
+fn entity_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"&(#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[a-zA-Z]+);").unwrap())
+}
+
+/// The distinct http(s) links of a message, in order, leaving out punctuation
+/// that ends the sentence around them
+fn extract_urls(content: &str, max_links: usize) -> Vec<String> {
+    let mut urls: Vec<String> = Vec::new();
+    for found in url_pattern().find_iter(content) {
+        if urls.len() >= max_links {
+            break;
+        }
+        let url = found
+            .as_str()
+            .trim_end_matches(['.', ',', ';', ':', '!', '?', '*', '_', '~']);
+        // A closing parenthesis without an opening one belongs to the text,
+        // e.g. a markdown link
+        let url = match url.strip_suffix(')') {
+            Some(stripped) if url.matches('(').count() < url.matches(')').count() => stripped,
+            _ => url,
+        };
+        if url.len() <= MAX_URL_LENGTH && !urls.iter().any(|known| known == url) {
+            urls.push(url.to_string());
+        }
+    }
+    urls
+}
+
+/// Builds the preview of `url` from the page it led to. Pages with neither a
+/// title nor a description get no preview.
+fn parse_preview(url: &str, page: &FetchedPage) -> Option<LinkPreview> {
+    let mut properties: HashMap<String, &str> = HashMap::new();
+    for tag in meta_pattern().find_iter(&page.html) {
+        let mut key = None;
+        let mut value = None;
+        for attribute in attribute_pattern().captures_iter(tag.as_str()) {
+            let text = attribute
+                .get(2)
+                .or_else(|| attribute.get(3))
+                .map_or("", |text| text.as_str());
+            match attribute[1].to_ascii_lowercase().as_str() {
+                "property" | "name" => key = Some(text.to_ascii_lowercase()),
+                "content" => value = Some(text),
+                _ => {}
+            }
+        }
+        if let (Some(key), Some(value)) = (key, value) {
+            properties.entry(key).or_insert(value);
+        }
+    }
+    let property = |keys: &[&str], max_length: usize| {
+        keys.iter()
+            .filter_map(|key| properties.get(*key))
+            .find_map(|value| clean_text(value, max_length))
+    };
+
+    let title = property(&["og:title", "twitter:title"], MAX_TITLE_LENGTH).or_else(|| {
+        title_pattern()
+            .captures(&page.html)
+            .and_then(|title| clean_text(&title[1], MAX_TITLE_LENGTH))
+    });
+    let description = property(
+        &["og:description", "twitter:description", "description"],
+        MAX_DESCRIPTION_LENGTH,
+    );
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+    // Relative image addresses are relative to the page, after redirects
+    let image_url = property(&["og:image", "twitter:image"], MAX_URL_LENGTH)
+        .and_then(|image| Url::parse(&page.url).ok()?.join(&image).ok())
+        .filter(|image| matches!(image.scheme(), "http" | "https"))
+        .map(String::from)
+        .filter(|image| image.len() <= MAX_URL_LENGTH);
+
+    Some(LinkPreview {
+        url: url.to_string(),
+        title,
+        description,
+        image_url,
+        site_name: property(&["og:site_name"], MAX_TITLE_LENGTH),
+    })
+}
+
+/// Decodes HTML entities, collapses whitespace and drops control characters.
+/// Returns `None` for empty texts.
+fn clean_text(text: &str, max_length: usize) -> Option<String> {
+    let decoded = entity_pattern().replace_all(text, |entity: &Captures| {
+        let name = &entity[1];
+        let decoded = match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => name
+                    .strip_prefix('#')
+                    .and_then(|decimal| decimal.parse().ok()),
+            }
+            .and_then(char::from_u32),
+        };
+        decoded.map_or_else(|| entity[0].to_string(), String::from)
+    });
+    let text = decoded
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_length)
+        .collect::<String>();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Looks a link up in the cache. `Some(None)` means it was fetched recently
+/// without giving a preview.
+async fn cached_preview(
+    pool: &Pool<Postgres>,
+    url: &str,
+    cache_hours: i64,
+) -> Result<Option<Option<LinkPreview>>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Option<String>,)>(
+        "SELECT preview::text FROM link_previews
+         WHERE url = $1 AND fetched_at > CURRENT_TIMESTAMP - make_interval(hours => $2::INT)",
+    )
+    .bind(url)
+    .bind(cache_hours)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|(preview,)| preview.and_then(|preview| serde_json::from_str(&preview).ok())))
+}
+
+/// The preview of a link, from the cache or fetched and then cached. Failures
+/// are cached too, so a broken link isn't fetched for every message.
+async fn preview_link(state: &AppState, url: &str) -> Option<LinkPreview> {
+    let config = &state.config.link_previews;
+    match cached_preview(&state.pool, url, config.cache_hours).await {
+        Ok(Some(preview)) => return preview,
+        Ok(None) => {}
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+
+    let preview = match state.link_fetcher.fetch(url).await {
+        Ok(page) => parse_preview(url, &page),
+        Err(e) => {
+            eprintln!("Failed to fetch link preview of {}: {}", url, e);
+            None
+        }
+    };
+    let stored = sqlx::query(
+        "INSERT INTO link_previews (url, preview) VALUES ($1, $2::JSONB)
+         ON CONFLICT (url) DO UPDATE SET preview = EXCLUDED.preview, fetched_at = CURRENT_TIMESTAMP",
+    )
+    .bind(url)
+    .bind(
+        preview
+            .as_ref()
+            .and_then(|preview| serde_json::to_string(preview).ok()),
+    )
+    .execute(&state.pool)
+    .await;
+    if let Err(e) = stored {
+        eprintln!("Database error: {:?}", e);
+    }
+    preview
+}
+
+/// A message whose links are waiting to be previewed
+pub struct PreviewJob {
+    message_id: i32,
+    room_id: i32,
+    user_id: i32,
+    urls: Vec<String>,
+}
+
+/// Hands newly posted messages to the background task that previews their links
+#[derive(Clone)]
+pub struct LinkPreviewQueue {
+    jobs: mpsc::Sender<PreviewJob>,
+    enabled: bool,
+    max_links: usize,
+}
+
+impl LinkPreviewQueue {
+    pub fn new(config: &LinkPreviewConfig) -> (LinkPreviewQueue, mpsc::Receiver<PreviewJob>) {
+        let (jobs, receiver) = mpsc::channel(QUEUE_SIZE);
+        let queue = LinkPreviewQueue {
+            jobs,
+            enabled: config.enabled,
+            max_links: config.max_links,
+        };
+        (queue, receiver)
+    }
+
+    /// Queues the links of a message, if it has any. Never waits: when the
+    /// queue is full the message goes without previews.
+    pub fn push(&self, message: &ChatMessage) {
+        if !self.enabled {
+            return;
+        }
+        let urls = extract_urls(&message.content, self.max_links);
+        if urls.is_empty() {
+            return;
+        }
+        let job = PreviewJob {
+            message_id: message.id,
+            room_id: message.room_id,
+            user_id: message.user_id,
+            urls,
+        };
+        if self.jobs.try_send(job).is_err() {
+            eprintln!(
+                "Link preview queue is full, skipping message {}",
+                message.id
+            );
+        }
+    }
+}
+
+/// Previews the links of queued messages, a few messages at a time. Runs for
+/// as long as the server does.
+pub async fn run_link_previews(state: Arc<AppState>, mut jobs: mpsc::Receiver<PreviewJob>) {
+    let slots = Arc::new(Semaphore::new(CONCURRENT_JOBS));
+    while let Some(job) = jobs.recv().await {
+        let Ok(slot) = slots.clone().acquire_owned().await else {
+            break;
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            attach_previews(&state, job).await;
+            drop(slot);
+        });
+    }
+}
+
+/// Stores the previews of a message's links with the message and sends them
+/// to its room
+async fn attach_previews(state: &AppState, job: PreviewJob) {
+    let previews: Vec<LinkPreview> = join_all(job.urls.iter().map(|url| preview_link(state, url)))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    if previews.is_empty() {
+        return;
+    }
+
+    let query_result = sqlx::query("UPDATE messages SET link_previews = $2::JSONB WHERE id = $1")
+        .bind(job.message_id)
+        .bind(serde_json::to_string(&previews).unwrap_or_else(|_| "[]".to_string()))
+        .execute(&state.pool)
+        .await;
+    match query_result {
+        // The message was deleted in the meantime
+        Ok(result) if result.rows_affected() == 0 => {}
+        Ok(_) => {
+            let _ = state.tx.send(ServerEvent::LinkPreviews {
+                room_id: job.room_id,
+                message_id: job.message_id,
+                user_id: job.user_id,
+                previews,
+            });
+        }
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+}
+
+/// Reads the previews stored with a message
+pub fn parse_stored_previews(previews: Option<&str>) -> Vec<LinkPreview> {
+    previews
+        .and_then(|previews| serde_json::from_str(previews).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::connections::ConnectionRegistry;
+    use crate::content_filter::ContentFilter;
+    use crate::mailer::build_mailer;
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::RwLock;
+    use tokio::sync::broadcast;
+
+    fn page(url: &str, html: &str) -> FetchedPage {
+        FetchedPage {
+            url: url.to_string(),
+            html: html.to_string(),
+        }
+    }
+
+    #[test]
+    fn extract_urls_finds_distinct_http_links_in_order() {
+        let content = "See https://example.com/a, then HTTP://example.org/b! \
+                       Again: https://example.com/a. Not these: ftp://example.com/c javascript:alert(1)";
+        assert_eq!(
+            extract_urls(content, 5),
+            ["https://example.com/a", "HTTP://example.org/b"]
+        );
+    }
+
+    #[test]
+    fn extract_urls_leaves_out_surrounding_markup() {
+        assert_eq!(
+            extract_urls(
+                "[docs](https://example.com/docs) and <https://example.com/x>",
+                5
+            ),
+            ["https://example.com/docs", "https://example.com/x"]
+        );
+        assert_eq!(
+            extract_urls(
+                "**https://example.com/bold** (https://example.com/aside)",
+                5
+            ),
+            ["https://example.com/bold", "https://example.com/aside"]
+        );
+        // Balanced parentheses are part of the link
+        assert_eq!(
+            extract_urls(
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)",
+                5
+            ),
+            ["https://en.wikipedia.org/wiki/Rust_(programming_language)"]
+        );
+        assert_eq!(
+            extract_urls("\"https://example.com/q?a=1&b=2\"", 5),
+            ["https://example.com/q?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn extract_urls_respects_the_limits() {
+        let content = "https://a.example https://b.example https://c.example";
+        assert_eq!(
+            extract_urls(content, 2),
+            ["https://a.example", "https://b.example"]
+        );
+        assert!(extract_urls(content, 0).is_empty());
+
+        let long = format!("https://example.com/{}", "a".repeat(MAX_URL_LENGTH));
+        assert_eq!(
+            extract_urls(&format!("{} https://example.com/short", long), 5),
+            ["https://example.com/short"]
+        );
+        assert!(extract_urls("no links here, just http:// and https:", 5).is_empty());
+    }
+
+    #[test]
+    fn parse_preview_prefers_opengraph_tags() {
+        let html = r#"<html><head>
+            <title>Page title</title>
+            <meta name="description" content="Plain description">
+            <meta property="og:title" content="Open &amp; shut">
+            <META content='Graph description' PROPERTY='og:description'>
+            <meta property="og:image" content="/images/cover.png">
+            <meta property="og:site_name" content="Example">
+            <meta property="og:title" content="Second title is ignored">
+        </head></html>"#;
+        let preview = parse_preview(
+            "https://example.com/short",
+            &page("https://example.com/articles/1", html),
+        )
+        .unwrap();
+
+        assert_eq!(preview.url, "https://example.com/short");
+        assert_eq!(preview.title.as_deref(), Some("Open & shut"));
+        assert_eq!(preview.description.as_deref(), Some("Graph description"));
+        // Resolved against the page after redirects
+        assert_eq!(
+            preview.image_url.as_deref(),
+            Some("https://example.com/images/cover.png")
+        );
+        assert_eq!(preview.site_name.as_deref(), Some("Example"));
+    }
+
+    #[test]
+    fn parse_preview_falls_back_to_title_and_description() {
+        let html = "<title>\n  Fallback   title </title>\
+                    <meta name=\"twitter:title\" content=\"  \">\
+                    <meta name=\"Description\" content=\"Described\">";
+        let preview =
+            parse_preview("https://example.com", &page("https://example.com", html)).unwrap();
+
+        assert_eq!(preview.title.as_deref(), Some("Fallback title"));
+        assert_eq!(preview.description.as_deref(), Some("Described"));
+        assert_eq!(preview.image_url, None);
+        assert_eq!(preview.site_name, None);
+    }
+
+    #[test]
+    fn parse_preview_drops_unsafe_images_and_empty_pages() {
+        let html = r#"<title>T</title><meta property="og:image" content="javascript:alert(1)">"#;
+        let preview =
+            parse_preview("https://example.com", &page("https://example.com", html)).unwrap();
+        assert_eq!(preview.image_url, None);
+
+        let html =
+            r#"<title>T</title><meta property="og:image" content="data:image/png;base64,AAAA">"#;
+        let preview =
+            parse_preview("https://example.com", &page("https://example.com", html)).unwrap();
+        assert_eq!(preview.image_url, None);
+
+        let html =
+            r#"<html><body><meta property="og:image" content="/a.png"><p>Hi</p></body></html>"#;
+        assert!(parse_preview("https://example.com", &page("https://example.com", html)).is_none());
+        assert!(parse_preview("https://example.com", &page("https://example.com", "")).is_none());
+    }
+
+    #[test]
+    fn clean_text_decodes_entities() {
+        assert_eq!(
+            clean_text(
+                "Tom &amp; Jerry &lt;3 &quot;x&quot; &apos;y&#39; &#x2014;&#X41;",
+                100
+            )
+            .as_deref(),
+            Some("Tom & Jerry <3 \"x\" 'y' \u{2014}A")
+        );
+        // Unknown entities and invalid code points are kept as written
+        assert_eq!(
+            clean_text("&copy; &#xD800; &#99999999;", 100).as_deref(),
+            Some("&copy; &#xD800; &#99999999;")
+        );
+        // Decoding happens once
+        assert_eq!(clean_text("&amp;lt;", 100).as_deref(), Some("&lt;"));
+    }
+
+    #[test]
+    fn clean_text_collapses_whitespace_and_drops_control_characters() {
+        assert_eq!(
+            clean_text("  a\n\tb&nbsp;&nbsp;c \u{7}d\u{200b}", 100).as_deref(),
+            Some("a b c d\u{200b}")
+        );
+        assert_eq!(clean_text(" \n\t ", 100), None);
+        assert_eq!(clean_text("&#1;", 100), None);
+        assert_eq!(clean_text("", 100), None);
+    }
+
+    #[test]
+    fn clean_text_truncates_by_characters() {
+        assert_eq!(clean_text("héllo wörld", 5).as_deref(), Some("héllo"));
+        assert_eq!(clean_text("日本語のテキスト", 3).as_deref(), Some("日本語"));
+    }
+
+    /// Serves fixed pages instead of fetching them
+    struct StubFetcher {
+        pages: HashMap<String, String>,
+    }
+
+    #[async_trait]
+    impl LinkFetcher for StubFetcher {
+        async fn fetch(&self, url: &str) -> Result<FetchedPage, String> {
+            match self.pages.get(url) {
+                Some(html) => Ok(page(url, html)),
+                None => Err("Status 404 Not Found".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn attach_previews_stores_and_broadcasts_the_previews() {
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .connect(crate::DATABASE_URL)
+            .await
+            .expect("attach_previews needs the development database");
+        // Unique links so earlier runs' cache entries don't count
+        let run = uuid::Uuid::new_v4();
+        let found = format!("https://example.com/{}/found", run);
+        let missing = format!("https://example.com/{}/missing", run);
+        let untitled = format!("https://example.com/{}/untitled", run);
+
+        let config = Config::default();
+        let (tx, mut events) = broadcast::channel(4);
+        let state = AppState {
+            pool: pool.clone(),
+            tx,
+            connections: ConnectionRegistry::default(),
+            mailer: build_mailer(&config.mail),
+            content_filter: RwLock::new(Arc::new(
+                ContentFilter::from_config(&config.content_filter).unwrap(),
+            )),
+            link_fetcher: Arc::new(StubFetcher {
+                pages: HashMap::from([
+                    (
+                        found.clone(),
+                        r#"<meta property="og:title" content="Found it">"#.to_string(),
+                    ),
+                    (untitled.clone(), "<p>No title</p>".to_string()),
+                ]),
+            }),
+            link_previews: LinkPreviewQueue::new(&config.link_previews).0,
+            config,
+            default_workspace_id: 0,
+        };
+
+        let content = format!("{} {} {}", missing, found, untitled);
+        let (message_id,) =
+            sqlx::query_as::<_, (i32,)>("INSERT INTO messages (content) VALUES ($1) RETURNING id")
+                .bind(&content)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        attach_previews(
+            &state,
+            PreviewJob {
+                message_id,
+                room_id: 7,
+                user_id: 3,
+                urls: extract_urls(&content, 3),
+            },
+        )
+        .await;
+
+        let event = events.try_recv().expect("the previews are broadcast");
+        let ServerEvent::LinkPreviews {
+            room_id,
+            message_id: event_message_id,
+            user_id,
+            previews,
+        } = event
+        else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert_eq!((room_id, event_message_id, user_id), (7, message_id, 3));
+        assert_eq!(previews.len(), 1);
+        assert_eq!(previews[0].url, found);
+        assert_eq!(previews[0].title.as_deref(), Some("Found it"));
+
+        let (stored,) = sqlx::query_as::<_, (Option<String>,)>(
+            "SELECT link_previews::text FROM messages WHERE id = $1",
+        )
+        .bind(message_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let stored = parse_stored_previews(stored.as_deref());
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].url, found);
+
+        // Failed fetches are cached too
+        let cached = cached_preview(&pool, &missing, 24).await.unwrap();
+        assert!(matches!(cached, Some(None)));
+
+        let _ = sqlx::query("DELETE FROM messages WHERE id = $1")
+            .bind(message_id)
+            .execute(&pool)
+            .await;
+        let _ = sqlx::query("DELETE FROM link_previews WHERE url = ANY($1)")
+            .bind(vec![found, missing, untitled])
+            .execute(&pool)
+            .await;
+    }
+}
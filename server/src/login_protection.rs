@@ -0,0 +1,215 @@
+use sqlx::{Pool, Postgres};
+
+/// Failed attempts allowed against one account before it is locked
+const MAX_ACCOUNT_FAILURES: i64 = 5;
+/// Failed attempts allowed from one IP address (across all accounts) before it is locked
+const MAX_IP_FAILURES: i64 = 20;
+/// Only failures newer than this count towards a lockout
+const FAILURE_WINDOW_MINUTES: i64 = 15;
+/// Length of the first lockout, doubled for every further lockout within a day
+const BASE_LOCKOUT_SECONDS: i64 = 60;
+const MAX_LOCKOUT_SECONDS: i64 = 24 * 60 * 60;
+
+const SCOPE_ACCOUNT: &str = "account";
+const SCOPE_IP: &str = "ip";
+
+/// Why a credential check was rejected
+#[derive(Debug, Clone)]
+pub enum AuthFailure {
+    InvalidCredentials,
+    Locked {
+        retry_after_seconds: i64,
+    },
+    EmailNotVerified,
+    TwoFactorRequired,
+    InvalidTwoFactorCode,
+    Banned {
+        reason: String,
+        expires_at: Option<String>,
+    },
+    NotWorkspaceMember,
+}
+
+impl AuthFailure {
+    pub fn message(&self) -> String {
+        match self {
+            AuthFailure::InvalidCredentials => "Invalid email or password".to_string(),
+            AuthFailure::Locked {
+                retry_after_seconds,
+            } => format!(
+                "Too many failed login attempts. Try again in {} seconds",
+                retry_after_seconds
+            ),
+            AuthFailure::EmailNotVerified => {
+                "Please verify your email address before logging in".to_string()
+            }
+            AuthFailure::TwoFactorRequired => "Two-factor authentication code required".to_string(),
+            AuthFailure::InvalidTwoFactorCode => "Invalid two-factor code".to_string(),
+            AuthFailure::Banned { reason, expires_at } => match expires_at {
+                Some(expires_at) => {
+                    format!(
+                        "You are banned from this workspace until {}: {}",
+                        expires_at, reason
+                    )
+                }
+                None => format!("You are banned from this workspace: {}", reason),
+            },
+            AuthFailure::NotWorkspaceMember => "You are not a member of this workspace".to_string(),
+        }
+    }
+}
+
+/// Rejects the attempt if either the account or the client IP is currently locked
+pub async fn ensure_not_locked(
+    pool: &Pool<Postgres>,
+    email: &str,
+    ip_address: &str,
+) -> Result<(), AuthFailure> {
+    let account_wait = remaining_lockout(pool, SCOPE_ACCOUNT, email).await;
+    let ip_wait = remaining_lockout(pool, SCOPE_IP, ip_address).await;
+
+    match account_wait.max(ip_wait) {
+        Some(retry_after_seconds) => Err(AuthFailure::Locked {
+            retry_after_seconds,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Records the outcome of a credential check and locks the account or IP once
+/// the failure threshold is crossed
+pub async fn record_login_attempt(
+    pool: &Pool<Postgres>,
+    email: &str,
+    ip_address: &str,
+    success: bool,
+) {
+    let result =
+        sqlx::query("INSERT INTO login_attempts (email, ip_address, success) VALUES ($1, $2, $3)")
+            .bind(email)
+            .bind(ip_address)
+            .bind(success)
+            .execute(pool)
+            .await;
+
+    if let Err(e) = result {
+        eprintln!("Database error: {:?}", e);
+        return;
+    }
+
+    if success {
+        return;
+    }
+
+    let account_failures = recent_failures(pool, SCOPE_ACCOUNT, email).await;
+    if account_failures >= MAX_ACCOUNT_FAILURES {
+        lock(pool, SCOPE_ACCOUNT, email, account_failures).await;
+    }
+
+    let ip_failures = recent_failures(pool, SCOPE_IP, ip_address).await;
+    if ip_failures >= MAX_IP_FAILURES {
+        lock(pool, SCOPE_IP, ip_address, ip_failures).await;
+    }
+}
+
+/// Seconds left on the newest active lockout for `subject`, if any
+async fn remaining_lockout(pool: &Pool<Postgres>, scope: &str, subject: &str) -> Option<i64> {
+    let result = sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT CEIL(EXTRACT(EPOCH FROM (MAX(locked_until) - NOW())))::BIGINT
+         FROM login_lockouts
+         WHERE scope = $1 AND subject = $2 AND locked_until > NOW()",
+    )
+    .bind(scope)
+    .bind(subject)
+    .fetch_one(pool)
+    .await;
+
+    match result {
+        Ok((seconds,)) => seconds.map(|s| s.max(1)),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Counts failures inside the window that happened after the last lockout, so
+/// every lockout starts a fresh count. For accounts, a successful login also
+/// starts a fresh count. For IP addresses it doesn't: otherwise an attacker
+/// could log into their own account now and then to keep guessing the
+/// passwords of others.
+async fn recent_failures(pool: &Pool<Postgres>, scope: &str, subject: &str) -> i64 {
+    let (column, since_success) = if scope == SCOPE_ACCOUNT {
+        (
+            "email",
+            "AND attempted_at > COALESCE(
+               (SELECT MAX(attempted_at) FROM login_attempts WHERE email = $1 AND success),
+               '-infinity')",
+        )
+    } else {
+        ("ip_address", "")
+    };
+
+    let query = format!(
+        "SELECT COUNT(*) FROM login_attempts
+         WHERE {column} = $1
+           AND success = FALSE
+           AND attempted_at > NOW() - make_interval(mins => $2::INT)
+           {since_success}
+           AND attempted_at > COALESCE(
+               (SELECT MAX(created_at) FROM login_lockouts WHERE scope = $3 AND subject = $1),
+               '-infinity')"
+    );
+
+    let result = sqlx::query_as::<_, (i64,)>(&query)
+        .bind(subject)
+        .bind(FAILURE_WINDOW_MINUTES as i32)
+        .bind(scope)
+        .fetch_one(pool)
+        .await;
+
+    match result {
+        Ok((count,)) => count,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            0
+        }
+    }
+}
+
+/// Inserts a lockout whose length doubles with every lockout in the past day
+async fn lock(pool: &Pool<Postgres>, scope: &str, subject: &str, failure_count: i64) {
+    let previous = sqlx::query_as::<_, (i64,)>(
+        "SELECT COUNT(*) FROM login_lockouts
+         WHERE scope = $1 AND subject = $2 AND created_at > NOW() - INTERVAL '1 day'",
+    )
+    .bind(scope)
+    .bind(subject)
+    .fetch_one(pool)
+    .await
+    .map(|(count,)| count)
+    .unwrap_or(0);
+
+    let duration = BASE_LOCKOUT_SECONDS
+        .saturating_mul(1_i64 << previous.min(20))
+        .min(MAX_LOCKOUT_SECONDS);
+
+    let result = sqlx::query(
+        "INSERT INTO login_lockouts (scope, subject, failure_count, locked_until)
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4::DOUBLE PRECISION))",
+    )
+    .bind(scope)
+    .bind(subject)
+    .bind(failure_count as i32)
+    .bind(duration as f64)
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(_) => println!(
+            "Locked {} {} for {} seconds after {} failed logins",
+            scope, subject, duration, failure_count
+        ),
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+}
@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    transport::smtp::authentication::Credentials,
+};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::{MailConfig, MailTransport};
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers transactional emails such as verification and password reset links
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), String>;
+}
+
+/// Builds the mailer selected in the configuration
+pub fn build_mailer(config: &MailConfig) -> Arc<dyn Mailer> {
+    match config.transport {
+        MailTransport::Smtp => Arc::new(SmtpMailer::new(config)),
+        MailTransport::Log => Arc::new(LogMailer {
+            path: config.log_path.clone(),
+        }),
+    }
+}
+
+/// Sends emails through an SMTP relay using STARTTLS
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &MailConfig) -> SmtpMailer {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)
+            .expect("Failed to configure SMTP transport.")
+            .port(config.smtp_port);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        SmtpMailer {
+            transport: builder.build(),
+            from: config.from.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let message = Message::builder()
+            .from(
+                self.from
+                    .parse()
+                    .map_err(|e| format!("Invalid sender: {}", e))?,
+            )
+            .to(email
+                .to
+                .parse()
+                .map_err(|e| format!("Invalid recipient: {}", e))?)
+            .subject(email.subject)
+            .body(email.body)
+            .map_err(|e| e.to_string())?;
+
+        self.transport
+            .send(message)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Writes emails to a file (or stdout) instead of sending them, for development and tests
+pub struct LogMailer {
+    pub path: Option<String>,
+}
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> Result<(), String> {
+        let entry = format!(
+            "To: {}\nSubject: {}\n\n{}\n---\n",
+            email.to, email.subject, email.body
+        );
+
+        match &self.path {
+            Some(path) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                file.write_all(entry.as_bytes())
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            None => {
+                println!("{}", entry);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser, Tag, TagEnd};
+
+/// Only links to these schemes are rendered as links, anything else is shown
+/// as the link's text
+const LINK_SCHEMES: [&str; 3] = ["http://", "https://", "mailto:"];
+const MAX_LANGUAGE_LENGTH: usize = 32;
+
+/// Renders the markdown subset supported in messages to HTML that is safe to
+/// insert into a page: paragraphs, line breaks, bold, italic, inline code,
+/// code blocks with an optional language, quotes and http(s) or mailto links.
+/// Everything else, including raw HTML, headings, lists and images, is shown
+/// as the text the user typed. All text is escaped and the only attributes
+/// are the escaped `href` of links and the `language-*` class of code blocks.
+pub fn render_markdown(content: &str) -> String {
+    let mut html = String::with_capacity(content.len() + content.len() / 2);
+    let mut events = Parser::new_ext(content, Options::empty()).into_offset_iter();
+    // Whether each open link was rendered as an `<a>` element
+    let mut links = Vec::new();
+
+    while let Some((event, range)) = events.next() {
+        match event {
+            Event::Start(Tag::Paragraph) => html.push_str("<p>"),
+            Event::Start(Tag::BlockQuote(_)) => html.push_str("<blockquote>"),
+            Event::Start(Tag::CodeBlock(kind)) => match kind {
+                CodeBlockKind::Fenced(info) if let Some(language) = code_language(&info) => {
+                    html.push_str("<pre><code class=\"language-");
+                    html.push_str(&language);
+                    html.push_str("\">");
+                }
+                _ => html.push_str("<pre><code>"),
+            },
+            Event::Start(Tag::Emphasis) => html.push_str("<em>"),
+            Event::Start(Tag::Strong) => html.push_str("<strong>"),
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                ..
+            }) => {
+                let href = match link_type {
+                    LinkType::Email => format!("mailto:{}", dest_url),
+                    _ => dest_url.to_string(),
+                };
+                let allowed = LINK_SCHEMES.iter().any(|scheme| {
+                    href.get(..scheme.len())
+                        .is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+                });
+                if allowed {
+                    html.push_str("<a href=\"");
+                    escape_html(&mut html, &href);
+                    html.push_str("\" rel=\"noopener noreferrer nofollow\">");
+                }
+                links.push(allowed);
+            }
+            Event::Start(tag) => {
+                // Unsupported elements are skipped along with their content
+                // and replaced by their source
+                let block = !matches!(
+                    tag,
+                    Tag::Image { .. } | Tag::Strikethrough | Tag::Superscript | Tag::Subscript
+                );
+                let mut depth = 1;
+                while depth > 0 {
+                    match events.next() {
+                        Some((Event::Start(_), _)) => depth += 1,
+                        Some((Event::End(_), _)) => depth -= 1,
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                push_source(&mut html, content[range].trim_end(), block);
+            }
+            Event::End(TagEnd::Paragraph) => html.push_str("</p>"),
+            Event::End(TagEnd::BlockQuote(_)) => html.push_str("</blockquote>"),
+            Event::End(TagEnd::CodeBlock) => html.push_str("</code></pre>"),
+            Event::End(TagEnd::Emphasis) => html.push_str("</em>"),
+            Event::End(TagEnd::Strong) => html.push_str("</strong>"),
+            Event::End(TagEnd::Link) => {
+                if links.pop() == Some(true) {
+                    html.push_str("</a>");
+                }
+            }
+            Event::End(_) => {}
+            Event::Text(text) | Event::InlineHtml(text) => escape_html(&mut html, &text),
+            Event::Code(code) => {
+                html.push_str("<code>");
+                escape_html(&mut html, &code);
+                html.push_str("</code>");
+            }
+            Event::SoftBreak | Event::HardBreak => html.push_str("<br>"),
+            Event::Rule | Event::Html(_) | Event::DisplayMath(_) => {
+                push_source(&mut html, content[range].trim_end(), true)
+            }
+            Event::InlineMath(_) | Event::FootnoteReference(_) | Event::TaskListMarker(_) => {
+                push_source(&mut html, &content[range], false)
+            }
+        }
+    }
+    html
+}
+
+/// The language of a fenced code block: the first word of its info string,
+/// when it looks like a language name
+fn code_language(info: &str) -> Option<String> {
+    let language = info.split_whitespace().next()?;
+    let valid = language.len() <= MAX_LANGUAGE_LENGTH
+        && language
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.' | '#'));
+    valid.then(|| language.to_ascii_lowercase())
+}
+
+/// Shows markdown source as text, keeping its line breaks
+fn push_source(html: &mut String, source: &str, block: bool) {
+    if block {
+        html.push_str("<p>");
+    }
+    for (i, line) in source.lines().enumerate() {
+        if i > 0 {
+            html.push_str("<br>");
+        }
+        escape_html(html, line);
+    }
+    if block {
+        html.push_str("</p>");
+    }
+}
+
+fn escape_html(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+}
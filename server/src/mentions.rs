@@ -0,0 +1,228 @@
+use axum::extract::{Json, State};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+
+use crate::AppState;
+use crate::connections::ConnectionControl;
+use crate::markdown::render_markdown;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::websocket_handler::ChatMessage;
+
+/// At most this many distinct `@name` mentions of a message are looked up
+const MAX_MENTIONED_NAMES: usize = 20;
+
+/// The mentions found in a message
+#[derive(Debug, Default, PartialEq)]
+struct ParsedMentions {
+    /// Lowercased names mentioned with `@name`
+    names: Vec<String>,
+    /// `@here`: members of the room who are online
+    here: bool,
+    /// `@room`: every member of the room
+    room: bool,
+}
+
+fn mention_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    // The @ must start a word, so email addresses aren't mentions
+    PATTERN.get_or_init(|| Regex::new(r"(?:^|[^\w@])@([\w][\w.\-]*)").unwrap())
+}
+
+fn parse_mentions(content: &str) -> ParsedMentions {
+    let mut mentions = ParsedMentions::default();
+    for captures in mention_pattern().captures_iter(content) {
+        // A sentence may end right after a mention
+        let name = captures[1].trim_end_matches(['.', '-']).to_lowercase();
+        match name.as_str() {
+            "here" => mentions.here = true,
+            "room" => mentions.room = true,
+            _ if !mentions.names.contains(&name) && mentions.names.len() < MAX_MENTIONED_NAMES => {
+                mentions.names.push(name)
+            }
+            _ => {}
+        }
+    }
+    mentions
+}
+
+/// Finds the mentions in a message that was just posted, stores one mention
+/// per mentioned user and sends each of them a mention event. Only members of
+/// the message's room can be mentioned; names are matched against their names,
+/// ignoring case. Users who blocked the author are not notified.
+pub async fn record_mentions(state: &AppState, workspace_id: i32, message: &ChatMessage) {
+    let mentions = parse_mentions(&message.content);
+    if mentions == ParsedMentions::default() {
+        return;
+    }
+    let online_user_ids = if mentions.here && !mentions.room {
+        state.connections.connected_user_ids(workspace_id)
+    } else {
+        vec![]
+    };
+
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO mentions (message_id, user_id)
+         SELECT $1, wm.user_id
+         FROM workspace_members wm
+         JOIN users u ON u.id = wm.user_id
+         WHERE wm.workspace_id = $2
+           AND wm.user_id <> $3
+           AND (LOWER(u.name) = ANY($4) OR $5 OR wm.user_id = ANY($6))
+           AND EXISTS (
+               SELECT 1 FROM room_members rm
+               WHERE rm.room_id = $7 AND rm.user_id = wm.user_id
+           )
+           AND NOT EXISTS (
+               SELECT 1 FROM user_blocks b WHERE b.blocker_id = wm.user_id AND b.blocked_id = $3
+           )
+         ON CONFLICT DO NOTHING
+         RETURNING user_id",
+    )
+    .bind(message.id)
+    .bind(workspace_id)
+    .bind(message.user_id)
+    .bind(&mentions.names)
+    .bind(mentions.room)
+    .bind(&online_user_ids)
+    .bind(message.room_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => {
+            for (user_id,) in rows {
+                state.connections.send_to_member(
+                    workspace_id,
+                    user_id,
+                    ConnectionControl::Mention(message.clone()),
+                );
+            }
+        }
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MentionInfo {
+    pub id: i32,
+    pub message_id: i32,
+    pub room_id: i32,
+    pub room_name: String,
+    pub author_id: i32,
+    pub author_name: String,
+    pub content: String,
+    pub html: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MentionsResponse {
+    pub status: String,
+    pub mentions: Vec<MentionInfo>,
+}
+
+/// Lists the caller's unread mentions in the rooms of their workspace they
+/// are still a member of, newest first
+pub async fn list_mentions(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<MentionsResponse> {
+    let query_result = sqlx::query_as::<_, (i32, i32, i32, String, i32, String, String, String)>(
+        "SELECT mn.id, m.id, r.id, r.name, u.id, u.name, m.content, mn.created_at::text
+         FROM mentions mn
+         JOIN messages m ON m.id = mn.message_id
+         JOIN rooms r ON r.id = m.room_id
+         JOIN room_members rm ON rm.room_id = r.id AND rm.user_id = mn.user_id
+         JOIN users u ON u.id = m.user_id
+         WHERE mn.user_id = $1 AND r.workspace_id = $2 AND mn.read_at IS NULL
+           AND NOT EXISTS (
+               SELECT 1 FROM user_blocks b WHERE b.blocker_id = $1 AND b.blocked_id = m.user_id
+           )
+         ORDER BY mn.id DESC
+         LIMIT 100",
+    )
+    .bind(auth.user_id)
+    .bind(auth.workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(MentionsResponse {
+            status: "success".to_string(),
+            mentions: rows
+                .into_iter()
+                .map(
+                    |(
+                        id,
+                        message_id,
+                        room_id,
+                        room_name,
+                        author_id,
+                        author_name,
+                        content,
+                        created_at,
+                    )| MentionInfo {
+                        id,
+                        message_id,
+                        room_id,
+                        room_name,
+                        author_id,
+                        author_name,
+                        html: render_markdown(&content),
+                        content,
+                        created_at,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(MentionsResponse {
+                status: "error".to_string(),
+                mentions: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkMentionsReadRequest {
+    /// Mentions to mark as read; every unread mention in the workspace when omitted
+    pub mention_ids: Option<Vec<i32>>,
+}
+
+/// Marks some or all of the caller's mentions in their workspace as read
+pub async fn mark_mentions_read(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<MarkMentionsReadRequest>,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query(
+        "UPDATE mentions mn SET read_at = CURRENT_TIMESTAMP
+         FROM messages m, rooms r
+         WHERE m.id = mn.message_id AND r.id = m.room_id
+           AND mn.user_id = $1 AND r.workspace_id = $2 AND mn.read_at IS NULL
+           AND ($3::INT[] IS NULL OR mn.id = ANY($3))",
+    )
+    .bind(auth.user_id)
+    .bind(auth.workspace_id)
+    .bind(payload.mention_ids)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("{} mentions marked as read", result.rows_affected()),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to mark mentions as read: {}", e),
+            })
+        }
+    }
+}
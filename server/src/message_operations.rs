@@ -1,20 +1,46 @@
-use axum::extract::{Json, Query, State};
+use axum::extract::{ConnectInfo, Json, Path, Query, State};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
+use sqlx::{Executor, Pool, Postgres};
+use std::net::SocketAddr;
 use std::sync::Arc;
 
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::content_filter::filter_message;
+use crate::link_previews::{LinkPreview, parse_stored_previews};
+use crate::markdown::render_markdown;
+use crate::mentions::record_mentions;
+use crate::moderation::{ModerationAction, active_sanction};
+use crate::polls::{PollDraft, create_poll, load_polls};
+use crate::roles::{Permission, has_room_permission, room_role};
+use crate::room_operations::{check_can_post, room_in_workspace};
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::webhooks::{WebhookEvent, queue_event};
+use crate::websocket_handler::{ChatMessage, MessagePayload, ServerEvent};
+use crate::workspaces::default_room_id;
+
 #[derive(Debug, Deserialize)]
 pub struct GetMessagesQuery {
     pub limit: Option<i64>,
+    pub room_id: Option<i32>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct MessageResponse {
     pub id: i32,
+    pub room_id: i32,
+    pub user_id: i32,
     pub user_email: String,
     pub username: String,
+    pub bot: bool,
     pub content: String,
+    pub html: String,
     pub timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<MessagePayload>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub link_previews: Vec<LinkPreview>,
 }
 
 #[derive(Debug, Serialize)]
@@ -23,50 +49,349 @@ pub struct MessagesResponse {
     pub messages: Vec<MessageResponse>,
 }
 
+/// Lists the latest messages of a room of the caller's workspace that the
+/// caller is a member of, the workspace's default room when none is given.
+/// Messages of users the caller blocked are left out.
 pub async fn get_messages(
-    State(state): State<Arc<(Pool<Postgres>, tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>)>>,
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
     Query(params): Query<GetMessagesQuery>,
 ) -> Json<MessagesResponse> {
-    let pool = &state.0;
+    let pool = &state.pool;
     let limit = params.limit.unwrap_or(100).min(500); // Default 100, max 500
+    let room_id = match params.room_id {
+        Some(room_id) if room_in_workspace(pool, room_id, auth.workspace_id).await => {
+            Some(room_id)
+        }
+        Some(_) => None,
+        None => default_room_id(pool, auth.workspace_id).await,
+    };
+    let Some(room_id) = room_id else {
+        return Json(MessagesResponse {
+            status: "error".to_string(),
+            messages: vec![],
+        });
+    };
+    if room_role(pool, room_id, auth.user_id).await.is_none() {
+        return Json(MessagesResponse {
+            status: "error".to_string(),
+            messages: vec![],
+        });
+    }
 
-    let query_result = sqlx::query_as::<_, (i32, String, String, String, String)>(
-        "SELECT m.id, u.email, u.name, m.content, m.created_at::text 
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            i32,
+            i32,
+            String,
+            String,
+            bool,
+            String,
+            String,
+            Option<String>,
+        ),
+    >(
+        "SELECT m.id, u.id, u.email, u.name, u.is_bot, m.content, m.created_at::text,
+                m.link_previews::text
          FROM messages m
          JOIN users u ON m.user_id = u.id
+         WHERE m.room_id = $2
+           AND NOT EXISTS (
+               SELECT 1 FROM user_blocks b WHERE b.blocker_id = $3 AND b.blocked_id = m.user_id
+           )
          ORDER BY m.created_at DESC
          LIMIT $1"
     )
     .bind(limit)
+    .bind(room_id)
+    .bind(auth.user_id)
     .fetch_all(pool)
     .await;
 
-    match query_result {
-        Ok(rows) => {
-            let mut messages: Vec<MessageResponse> = rows
-                .into_iter()
-                .map(|(id, email, username, content, timestamp)| MessageResponse {
+    let rows = match query_result {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(MessagesResponse {
+                status: "error".to_string(),
+                messages: vec![],
+            });
+        }
+    };
+    let message_ids: Vec<i32> = rows.iter().map(|row| row.0).collect();
+    let mut polls = match load_polls(pool, &message_ids, Some(auth.user_id)).await {
+        Ok(polls) => polls,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(MessagesResponse {
+                status: "error".to_string(),
+                messages: vec![],
+            });
+        }
+    };
+
+    let mut messages: Vec<MessageResponse> = rows
+        .into_iter()
+        .map(
+            |(id, user_id, email, username, bot, content, timestamp, link_previews)| {
+                MessageResponse {
                     id,
+                    room_id,
+                    user_id,
                     user_email: email,
                     username,
+                    bot,
+                    html: render_markdown(&content),
                     content,
                     timestamp,
-                })
-                .collect();
-            
-            // Reverse to get chronological order (oldest first)
-            messages.reverse();
-
-            Json(MessagesResponse {
-                status: "success".to_string(),
-                messages,
-            })
+                    payload: polls.remove(&id).map(MessagePayload::Poll),
+                    link_previews: parse_stored_previews(link_previews.as_deref()),
+                }
+            },
+        )
+        .collect();
+
+    // Reverse to get chronological order (oldest first)
+    messages.reverse();
+
+    Json(MessagesResponse {
+        status: "success".to_string(),
+        messages,
+    })
+}
+
+/// Stores message in DB and returns its id along with the author's current
+/// email and name, which may have changed since the connection was opened,
+/// and whether they are a bot
+async fn store_message<'e, E>(
+    executor: E,
+    user_id: i32,
+    room_id: i32,
+    content: &str,
+) -> Result<(i32, String, String, bool), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_as::<_, (i32, String, String, bool)>(
+        "WITH inserted AS (
+            INSERT INTO messages (user_id, room_id, content) VALUES ($1, $2, $3) RETURNING id
+         )
+         SELECT inserted.id, users.email, users.name, users.is_bot
+         FROM inserted, users WHERE users.id = $1",
+    )
+    .bind(user_id)
+    .bind(room_id)
+    .bind(content)
+    .fetch_one(executor)
+    .await
+}
+
+/// Posts a message in a room on behalf of a user: checks that they may post
+/// there, runs the content filter, stores the message, broadcasts it to the
+/// room and notifies the users it mentions. A message asking a poll has the
+/// poll's question as its content. The error is meant to be shown to the user.
+pub async fn post_message(
+    state: &AppState,
+    workspace_id: i32,
+    user_id: i32,
+    room_id: i32,
+    content: &str,
+    poll: Option<PollDraft>,
+) -> Result<ChatMessage, String> {
+    let pool = &state.pool;
+    if let Some(ban) = active_sanction(pool, user_id, workspace_id, ModerationAction::Ban, None).await {
+        return Err(ban.describe("You are banned from this workspace"));
+    }
+    if let Some(mute) = active_sanction(pool, user_id, workspace_id, ModerationAction::Mute, None).await {
+        return Err(mute.describe("You are muted"));
+    }
+    let poll = poll.map(|poll| poll.validate(state)).transpose()?;
+    let content = filter_message(state, poll.as_ref().map_or(content, |poll| &poll.question))
+        .map_err(|reason| format!("Message rejected: {}", reason))?;
+    let mut transaction = pool.begin().await.map_err(|e| {
+        eprintln!("Database error: {:?}", e);
+        "Failed to send message".to_string()
+    })?;
+    // Last, since passing slow mode counts as posting. It is rolled back with
+    // the message if storing it fails.
+    check_can_post(&mut transaction, workspace_id, room_id, user_id).await?;
+
+    let stored: Result<_, sqlx::Error> = async {
+        let (id, user_email, username, bot) =
+            store_message(&mut transaction, user_id, room_id, &content).await?;
+        let payload = match poll {
+            Some(mut poll) => {
+                poll.question = content.clone();
+                Some(MessagePayload::Poll(create_poll(&mut transaction, id, poll).await?))
+            }
+            None => None,
+        };
+        let message = ChatMessage {
+            id,
+            room_id,
+            user_id,
+            user_email,
+            username,
+            bot,
+            html: render_markdown(&content),
+            content,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            payload,
+            link_previews: vec![],
+        };
+        queue_event(
+            &mut transaction,
+            workspace_id,
+            Some(room_id),
+            WebhookEvent::MessageCreated,
+            serde_json::to_value(&message).unwrap_or_default(),
+        )
+        .await?;
+        transaction.commit().await?;
+        Ok(message)
+    }
+    .await;
+    let message = stored.map_err(|e| {
+        eprintln!("Database error: {:?}", e);
+        "Failed to send message".to_string()
+    })?;
+
+    let _ = state.tx.send(ServerEvent::Message(message.clone()));
+    record_mentions(state, workspace_id, &message).await;
+    state.link_previews.push(&message);
+    Ok(message)
+}
+
+/// A stored message, as needed to check and carry out its deletion
+pub struct StoredMessage {
+    pub id: i32,
+    pub workspace_id: i32,
+    pub author_id: i32,
+    pub room_id: i32,
+    pub content: String,
+}
+
+/// Looks up a message posted in one of the workspace's rooms
+pub async fn find_message(
+    pool: &Pool<Postgres>,
+    workspace_id: i32,
+    message_id: i32,
+) -> Result<Option<StoredMessage>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (i32, i32, String)>(
+        "SELECT m.user_id, m.room_id, m.content
+         FROM messages m
+         JOIN rooms r ON r.id = m.room_id
+         WHERE m.id = $1 AND r.workspace_id = $2",
+    )
+    .bind(message_id)
+    .bind(workspace_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|(author_id, room_id, content)| StoredMessage {
+        id: message_id,
+        workspace_id,
+        author_id,
+        room_id,
+        content,
+    }))
+}
+
+/// Deletes a message, tells the connected members of its room and webhooks,
+/// and records the deletion in the audit log
+pub async fn remove_message(
+    state: &AppState,
+    message: &StoredMessage,
+    actor_id: i32,
+    ip_address: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = state.pool.begin().await?;
+    sqlx::query("DELETE FROM messages WHERE id = $1")
+        .bind(message.id)
+        .execute(&mut transaction)
+        .await?;
+    queue_event(
+        &mut transaction,
+        message.workspace_id,
+        Some(message.room_id),
+        WebhookEvent::MessageDeleted,
+        serde_json::json!({
+            "message_id": message.id,
+            "room_id": message.room_id,
+            "author_id": message.author_id,
+            "deleted_by": actor_id,
+        }),
+    )
+    .await?;
+    transaction.commit().await?;
+
+    let _ = state.tx.send(ServerEvent::MessageDeleted {
+        room_id: message.room_id,
+        message_id: message.id,
+    });
+    record_event(
+        &state.pool,
+        AuditEvent::MessageDeleted,
+        Some(actor_id),
+        Some(message.author_id),
+        Some(ip_address),
+        serde_json::json!({
+            "message_id": message.id,
+            "room_id": message.room_id,
+            "content": message.content,
+        }),
+    )
+    .await;
+    Ok(())
+}
+
+/// Deletes a message. Authors can delete their own messages; deleting anyone
+/// else's needs the `DeleteAnyMessage` permission globally or in the room.
+pub async fn delete_message(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Path(message_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+
+    let message = match find_message(pool, auth.workspace_id, message_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "No message found with this id".to_string(),
+            });
         }
         Err(e) => {
             eprintln!("Database error: {:?}", e);
-            Json(MessagesResponse {
+            return Json(ApiResponse {
                 status: "error".to_string(),
-                messages: vec![],
+                message: format!("Failed to delete message: {}", e),
+            });
+        }
+    };
+
+    if message.author_id != auth.user_id
+        && !has_room_permission(pool, &auth, message.room_id, Permission::DeleteAnyMessage).await
+    {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to delete this message".to_string(),
+        });
+    }
+
+    match remove_message(&state, &message, auth.user_id, &addr.ip().to_string()).await {
+        Ok(()) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Message deleted".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to delete message: {}", e),
             })
         }
     }
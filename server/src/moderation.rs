@@ -0,0 +1,578 @@
+use axum::extract::{ConnectInfo, Json, Path, Query, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::connections::{ConnectionControl, Notice, NoticeKind};
+use crate::roles::{
+    Authorized, Permission, Role, RoomRole, has_room_permission, permission, room_role,
+};
+use crate::session_operations::{AuthSession, revoke_workspace_sessions};
+use crate::user_operations::ApiResponse;
+use crate::workspaces::{WorkspaceRole, is_default_room};
+
+/// Kinds of sanction a moderator can put on a user, stored in `moderation_actions.action`.
+/// Every sanction only applies inside the workspace it was given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    /// Rejects logins and closes every connection of the user
+    Ban,
+    /// Rejects chat messages from the user
+    Mute,
+    /// Removes the user from a room and keeps them out of it
+    Kick,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::Ban => "ban",
+            ModerationAction::Mute => "mute",
+            ModerationAction::Kick => "kick",
+        }
+    }
+}
+
+/// A sanction that is currently in force
+#[derive(Debug, Clone)]
+pub struct Sanction {
+    pub reason: String,
+    /// When the sanction ends; `None` for permanent ones
+    pub expires_at: Option<String>,
+}
+
+impl Sanction {
+    /// Describes the sanction to the sanctioned user, e.g. "You are muted until ...: spam"
+    pub fn describe(&self, what: &str) -> String {
+        match &self.expires_at {
+            Some(expires_at) => format!("{} until {}: {}", what, expires_at, self.reason),
+            None => format!("{}: {}", what, self.reason),
+        }
+    }
+}
+
+/// The longest-running active sanction of the given kind in a workspace, if
+/// any. `room_id` is only used for kicks.
+pub async fn active_sanction(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+    workspace_id: i32,
+    action: ModerationAction,
+    room_id: Option<i32>,
+) -> Option<Sanction> {
+    let result = sqlx::query_as::<_, (String, Option<String>)>(
+        "SELECT reason, expires_at::text FROM moderation_actions
+         WHERE user_id = $1 AND action = $2 AND room_id IS NOT DISTINCT FROM $3
+           AND workspace_id = $4
+           AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY expires_at DESC NULLS FIRST
+         LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(action.as_str())
+    .bind(room_id)
+    .bind(workspace_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(row) => row.map(|(reason, expires_at)| Sanction { reason, expires_at }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationRequest {
+    pub user_id: i32,
+    pub reason: String,
+    /// Length of the sanction in minutes; permanent when omitted
+    pub duration_minutes: Option<i32>,
+    /// Room to kick the user from; required by kicks and rejected by bans and mutes
+    pub room_id: Option<i32>,
+}
+
+/// Stores a sanction, writes it to the audit log and returns when it expires
+async fn record_action(
+    pool: &Pool<Postgres>,
+    action: ModerationAction,
+    actor: &AuthSession,
+    ip_address: &str,
+    payload: &ModerationRequest,
+) -> Result<Option<String>, sqlx::Error> {
+    let (expires_at,) = sqlx::query_as::<_, (Option<String>,)>(
+        "INSERT INTO moderation_actions
+            (user_id, workspace_id, room_id, action, reason, actor_id, expires_at)
+         VALUES ($1, $2, $3, $4, $5, $6, CURRENT_TIMESTAMP + make_interval(mins => $7))
+         RETURNING expires_at::text",
+    )
+    .bind(payload.user_id)
+    .bind(actor.workspace_id)
+    .bind(payload.room_id)
+    .bind(action.as_str())
+    .bind(payload.reason.trim())
+    .bind(actor.user_id)
+    .bind(payload.duration_minutes)
+    .fetch_one(pool)
+    .await?;
+
+    let event = match action {
+        ModerationAction::Ban => AuditEvent::UserBanned,
+        ModerationAction::Mute => AuditEvent::UserMuted,
+        ModerationAction::Kick => AuditEvent::UserKicked,
+    };
+    record_event(
+        pool,
+        event,
+        Some(actor.user_id),
+        Some(payload.user_id),
+        Some(ip_address),
+        serde_json::json!({
+            "reason": payload.reason.trim(),
+            "workspace_id": actor.workspace_id,
+            "room_id": payload.room_id,
+            "expires_at": expires_at,
+        }),
+    )
+    .await;
+
+    Ok(expires_at)
+}
+
+/// Checks the request and that the actor may sanction its target: the target
+/// must belong to the actor's workspace, nobody can sanction themselves, and
+/// only admins can sanction admins
+async fn validate_request(
+    pool: &Pool<Postgres>,
+    actor: &AuthSession,
+    payload: &ModerationRequest,
+) -> Result<(), String> {
+    if payload.reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    if payload.duration_minutes.is_some_and(|minutes| minutes <= 0) {
+        return Err("Duration must be a positive number of minutes".to_string());
+    }
+    if payload.user_id == actor.user_id {
+        return Err("You can't moderate yourself".to_string());
+    }
+
+    let result = sqlx::query_as::<_, (String, String)>(
+        "SELECT u.role, wm.role FROM workspace_members wm
+         JOIN users u ON u.id = wm.user_id
+         WHERE wm.workspace_id = $1 AND wm.user_id = $2",
+    )
+    .bind(actor.workspace_id)
+    .bind(payload.user_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(Some((role, workspace_role))) => {
+            if Role::parse(&role) == Some(Role::Admin) && actor.role != Role::Admin {
+                return Err("Only admins can moderate admins".to_string());
+            }
+            if WorkspaceRole::parse(&workspace_role) == Some(WorkspaceRole::Admin)
+                && actor.role != Role::Admin
+                && actor.workspace_role != WorkspaceRole::Admin
+            {
+                return Err("Only admins can moderate admins".to_string());
+            }
+            Ok(())
+        }
+        Ok(None) => Err("No user found with this id in this workspace".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err(format!("Failed to look up user: {}", e))
+        }
+    }
+}
+
+/// Bans a user from the actor's workspace: their sessions in it are revoked,
+/// their connections closed and their logins rejected until the ban expires
+pub async fn ban(
+    state: &AppState,
+    actor: &AuthSession,
+    ip_address: &str,
+    payload: &ModerationRequest,
+) -> Result<(), String> {
+    // Only workspace-wide bans are enforced
+    if payload.room_id.is_some() {
+        return Err("Bans apply to the whole workspace, a room_id can't be given".to_string());
+    }
+    validate_request(&state.pool, actor, payload).await?;
+
+    let expires_at = record_action(
+        &state.pool,
+        ModerationAction::Ban,
+        actor,
+        ip_address,
+        payload,
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {:?}", e);
+        format!("Failed to ban user: {}", e)
+    })?;
+
+    let sanction = Sanction {
+        reason: payload.reason.trim().to_string(),
+        expires_at,
+    };
+    if let Err(e) = revoke_workspace_sessions(
+        state,
+        payload.user_id,
+        actor.workspace_id,
+        &sanction.describe("You have been banned"),
+    )
+    .await
+    {
+        eprintln!("Database error: {:?}", e);
+    }
+    Ok(())
+}
+
+/// Mutes a user: their chat messages are rejected until the mute expires.
+/// Their open connections are told right away.
+pub async fn mute(
+    state: &AppState,
+    actor: &AuthSession,
+    ip_address: &str,
+    payload: &ModerationRequest,
+) -> Result<(), String> {
+    // Only workspace-wide mutes are enforced
+    if payload.room_id.is_some() {
+        return Err("Mutes apply to the whole workspace, a room_id can't be given".to_string());
+    }
+    validate_request(&state.pool, actor, payload).await?;
+
+    let expires_at = record_action(
+        &state.pool,
+        ModerationAction::Mute,
+        actor,
+        ip_address,
+        payload,
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {:?}", e);
+        format!("Failed to mute user: {}", e)
+    })?;
+
+    let sanction = Sanction {
+        reason: payload.reason.trim().to_string(),
+        expires_at,
+    };
+    state.connections.send_to_member(
+        actor.workspace_id,
+        payload.user_id,
+        ConnectionControl::Notice(
+            Notice::new(NoticeKind::Muted, sanction.describe("You have been muted"))
+                .with_detail("expires_at", sanction.expires_at),
+        ),
+    );
+    Ok(())
+}
+
+pub async fn ban_user(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Json(payload): Json<ModerationRequest>,
+) -> Json<ApiResponse> {
+    match ban(&state, &moderator.auth, &addr.ip().to_string(), &payload).await {
+        Ok(()) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "User banned".to_string(),
+        }),
+        Err(message) => Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        }),
+    }
+}
+
+pub async fn mute_user(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Json(payload): Json<ModerationRequest>,
+) -> Json<ApiResponse> {
+    match mute(&state, &moderator.auth, &addr.ip().to_string(), &payload).await {
+        Ok(()) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "User muted".to_string(),
+        }),
+        Err(message) => Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        }),
+    }
+}
+
+/// Removes a user from a room; they can't join it again until the kick expires
+pub async fn kick_user(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Json(payload): Json<ModerationRequest>,
+) -> Json<ApiResponse> {
+    let Some(room_id) = payload.room_id else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "A room_id is required".to_string(),
+        });
+    };
+
+    if is_default_room(&state.pool, room_id).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Nobody can be kicked from the default room, mute or ban them instead"
+                .to_string(),
+        });
+    }
+
+    if !has_room_permission(&state.pool, &auth, room_id, Permission::ModerateUsers).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to moderate this room".to_string(),
+        });
+    }
+
+    if let Err(message) = validate_request(&state.pool, &auth, &payload).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        });
+    }
+
+    match room_role(&state.pool, room_id, payload.user_id).await {
+        None => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "This user is not a member of the room".to_string(),
+            });
+        }
+        Some(RoomRole::Owner)
+            if !has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await =>
+        {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "You can't kick the owner of this room".to_string(),
+            });
+        }
+        Some(_) => {}
+    }
+
+    let expires_at = match record_action(
+        &state.pool,
+        ModerationAction::Kick,
+        &auth,
+        &addr.ip().to_string(),
+        &payload,
+    )
+    .await
+    {
+        Ok(expires_at) => expires_at,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to kick user: {}", e),
+            });
+        }
+    };
+
+    let _ = sqlx::query("DELETE FROM room_members WHERE room_id = $1 AND user_id = $2")
+        .bind(room_id)
+        .bind(payload.user_id)
+        .execute(&state.pool)
+        .await;
+
+    let sanction = Sanction {
+        reason: payload.reason.trim().to_string(),
+        expires_at,
+    };
+    state.connections.send_to_member(
+        auth.workspace_id,
+        payload.user_id,
+        ConnectionControl::LeaveRoom(room_id),
+    );
+    state.connections.send_to_member(
+        auth.workspace_id,
+        payload.user_id,
+        ConnectionControl::Notice(
+            Notice::new(
+                NoticeKind::Kicked,
+                sanction.describe(&format!("You have been kicked from room {}", room_id)),
+            )
+            .in_room(room_id)
+            .with_detail("expires_at", sanction.expires_at),
+        ),
+    );
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "User kicked".to_string(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationActionsQuery {
+    pub user_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModerationActionInfo {
+    pub id: i32,
+    pub user_id: i32,
+    pub room_id: Option<i32>,
+    pub action: String,
+    pub reason: String,
+    pub actor_id: Option<i32>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModerationActionsResponse {
+    pub status: String,
+    pub actions: Vec<ModerationActionInfo>,
+}
+
+/// Lists the sanctions currently in force in the workspace, optionally for a single user
+pub async fn list_moderation_actions(
+    State(state): State<Arc<AppState>>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Query(params): Query<ModerationActionsQuery>,
+) -> Json<ModerationActionsResponse> {
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            i32,
+            i32,
+            Option<i32>,
+            String,
+            String,
+            Option<i32>,
+            String,
+            Option<String>,
+        ),
+    >(
+        "SELECT id, user_id, room_id, action, reason, actor_id, created_at::text, expires_at::text
+         FROM moderation_actions
+         WHERE workspace_id = $1 AND ($2::INT IS NULL OR user_id = $2)
+           AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+         ORDER BY created_at DESC",
+    )
+    .bind(moderator.auth.workspace_id)
+    .bind(params.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(ModerationActionsResponse {
+            status: "success".to_string(),
+            actions: rows
+                .into_iter()
+                .map(
+                    |(id, user_id, room_id, action, reason, actor_id, created_at, expires_at)| {
+                        ModerationActionInfo {
+                            id,
+                            user_id,
+                            room_id,
+                            action,
+                            reason,
+                            actor_id,
+                            created_at,
+                            expires_at,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ModerationActionsResponse {
+                status: "error".to_string(),
+                actions: vec![],
+            })
+        }
+    }
+}
+
+/// Lifts a sanction before it expires
+pub async fn revoke_moderation_action(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Path(action_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, Option<i32>)>(
+        "UPDATE moderation_actions SET revoked_at = CURRENT_TIMESTAMP
+         WHERE id = $1 AND workspace_id = $2 AND revoked_at IS NULL
+         RETURNING user_id, action, room_id",
+    )
+    .bind(action_id)
+    .bind(moderator.auth.workspace_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(None) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No active moderation action found with this id".to_string(),
+        }),
+        Ok(Some((user_id, action, room_id))) => {
+            record_event(
+                &state.pool,
+                AuditEvent::ModerationLifted,
+                Some(moderator.auth.user_id),
+                Some(user_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "moderation_action_id": action_id, "action": action }),
+            )
+            .await;
+            // Banned users have no connections left to tell
+            let lifted = match (action.as_str(), room_id) {
+                ("mute", _) => Some(Notice::new(
+                    NoticeKind::SanctionLifted,
+                    "Your mute has been lifted".to_string(),
+                )),
+                ("kick", Some(room_id)) => Some(
+                    Notice::new(
+                        NoticeKind::SanctionLifted,
+                        format!("Your kick from room {} has been lifted", room_id),
+                    )
+                    .in_room(room_id),
+                ),
+                _ => None,
+            };
+            if let Some(notice) = lifted {
+                state.connections.send_to_member(
+                    moderator.auth.workspace_id,
+                    user_id,
+                    ConnectionControl::Notice(notice.with_detail("action", action)),
+                );
+            }
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Moderation action lifted".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to lift moderation action: {}", e),
+            })
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use axum::extract::{Json, Path, State};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::markdown::render_markdown;
+use crate::message_operations::find_message;
+use crate::roles::{Permission, has_room_permission, room_role};
+use crate::room_operations::room_in_workspace;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::websocket_handler::ServerEvent;
+
+/// Rooms can't have more pinned messages than this
+const MAX_PINS_PER_ROOM: i64 = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PinnedMessage {
+    pub message_id: i32,
+    pub room_id: i32,
+    pub user_id: i32,
+    pub username: String,
+    pub content: String,
+    pub html: String,
+    pub timestamp: String,
+    pub pinned_by: Option<i32>,
+    pub pinned_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinsResponse {
+    pub status: String,
+    pub pins: Vec<PinnedMessage>,
+}
+
+/// Lists the pinned messages of a room of the caller's workspace that the
+/// caller is a member of, most recently pinned first
+pub async fn list_pins(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(room_id): Path<i32>,
+) -> Json<PinsResponse> {
+    if !room_in_workspace(&state.pool, room_id, auth.workspace_id).await
+        || room_role(&state.pool, room_id, auth.user_id)
+            .await
+            .is_none()
+    {
+        return Json(PinsResponse {
+            status: "error".to_string(),
+            pins: vec![],
+        });
+    }
+
+    let query_result =
+        sqlx::query_as::<_, (i32, i32, String, String, String, Option<i32>, String)>(
+            "SELECT m.id, u.id, u.name, m.content, m.created_at::text, p.pinned_by, p.pinned_at::text
+             FROM pins p
+             JOIN messages m ON m.id = p.message_id
+             JOIN users u ON u.id = m.user_id
+             WHERE m.room_id = $1
+             ORDER BY p.pinned_at DESC",
+        )
+        .bind(room_id)
+        .fetch_all(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(rows) => Json(PinsResponse {
+            status: "success".to_string(),
+            pins: rows
+                .into_iter()
+                .map(
+                    |(message_id, user_id, username, content, timestamp, pinned_by, pinned_at)| {
+                        PinnedMessage {
+                            message_id,
+                            room_id,
+                            user_id,
+                            username,
+                            html: render_markdown(&content),
+                            content,
+                            timestamp,
+                            pinned_by,
+                            pinned_at,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(PinsResponse {
+                status: "error".to_string(),
+                pins: vec![],
+            })
+        }
+    }
+}
+
+/// Pins a message to its room. Needs the `PinMessages` permission globally
+/// or in the room.
+pub async fn pin_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(message_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+    let message = match find_message(pool, auth.workspace_id, message_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "No message found with this id".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to pin message: {}", e),
+            });
+        }
+    };
+
+    if !has_room_permission(pool, &auth, message.room_id, Permission::PinMessages).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to pin messages in this room".to_string(),
+        });
+    }
+
+    // The limit is checked in the same statement so concurrent pins can't exceed it
+    let query_result = sqlx::query_as::<_, (String, i32, String, String)>(
+        "WITH pinned AS (
+            INSERT INTO pins (message_id, room_id, pinned_by)
+            SELECT $1, $2, $3
+            WHERE (SELECT COUNT(*) FROM pins WHERE room_id = $2) < $4
+            ON CONFLICT (message_id) DO NOTHING
+            RETURNING pinned_at
+         )
+         SELECT pinned.pinned_at::text, u.id, u.name, m.created_at::text
+         FROM pinned, messages m JOIN users u ON u.id = m.user_id
+         WHERE m.id = $1",
+    )
+    .bind(message.id)
+    .bind(message.room_id)
+    .bind(auth.user_id)
+    .bind(MAX_PINS_PER_ROOM)
+    .fetch_optional(pool)
+    .await;
+
+    match query_result {
+        Ok(Some((pinned_at, user_id, username, timestamp))) => {
+            let _ = state.tx.send(ServerEvent::MessagePinned(PinnedMessage {
+                message_id: message.id,
+                room_id: message.room_id,
+                user_id,
+                username,
+                html: render_markdown(&message.content),
+                content: message.content,
+                timestamp,
+                pinned_by: Some(auth.user_id),
+                pinned_at,
+            }));
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Message pinned".to_string(),
+            })
+        }
+        Ok(None) => {
+            let already_pinned = sqlx::query_as::<_, (bool,)>(
+                "SELECT EXISTS(SELECT 1 FROM pins WHERE message_id = $1)",
+            )
+            .bind(message.id)
+            .fetch_one(pool)
+            .await
+            .is_ok_and(|(pinned,)| pinned);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: if already_pinned {
+                    "This message is already pinned".to_string()
+                } else {
+                    format!("A room can't have more than {} pins", MAX_PINS_PER_ROOM)
+                },
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to pin message: {}", e),
+            })
+        }
+    }
+}
+
+/// Unpins a message, with the same permission as pinning it
+pub async fn unpin_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(message_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+    let message = match find_message(pool, auth.workspace_id, message_id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "No message found with this id".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to unpin message: {}", e),
+            });
+        }
+    };
+
+    if !has_room_permission(pool, &auth, message.room_id, Permission::PinMessages).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to pin messages in this room".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query("DELETE FROM pins WHERE message_id = $1")
+        .bind(message.id)
+        .execute(pool)
+        .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "This message is not pinned".to_string(),
+        }),
+        Ok(_) => {
+            let _ = state.tx.send(ServerEvent::MessageUnpinned {
+                room_id: message.room_id,
+                message_id: message.id,
+            });
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Message unpinned".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to unpin message: {}", e),
+            })
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Pool, Postgres};
+use std::collections::HashMap;
+
+use crate::AppState;
+use crate::content_filter::filter_message;
+use crate::roles::room_role;
+use crate::websocket_handler::ServerEvent;
+
+const MIN_OPTIONS: usize = 2;
+const MAX_OPTIONS: usize = 10;
+const MAX_QUESTION_LENGTH: usize = 300;
+const MAX_OPTION_LENGTH: usize = 100;
+/// Polls can be left open for at most this long
+const MAX_OPEN_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// A poll as sent by a client, before it is validated
+#[derive(Debug, Clone)]
+pub struct PollDraft {
+    pub question: String,
+    pub options: Vec<String>,
+    pub multiple_choice: bool,
+    /// Seconds from now until voting closes; open until deleted when `None`
+    pub closes_in_seconds: Option<i64>,
+}
+
+impl PollDraft {
+    /// Trims and checks the question and options, running the options through
+    /// the content filter. The question is filtered as the message's content.
+    pub fn validate(mut self, state: &AppState) -> Result<PollDraft, String> {
+        self.question = self.question.trim().to_string();
+        if self.question.is_empty() || self.question.chars().count() > MAX_QUESTION_LENGTH {
+            return Err(format!(
+                "The question must be between 1 and {} characters",
+                MAX_QUESTION_LENGTH
+            ));
+        }
+        if self.options.len() < MIN_OPTIONS || self.options.len() > MAX_OPTIONS {
+            return Err(format!(
+                "A poll needs between {} and {} options",
+                MIN_OPTIONS, MAX_OPTIONS
+            ));
+        }
+        let mut options = Vec::with_capacity(self.options.len());
+        for option in &self.options {
+            let option = option.trim();
+            if option.is_empty() || option.chars().count() > MAX_OPTION_LENGTH {
+                return Err(format!(
+                    "Options must be between 1 and {} characters",
+                    MAX_OPTION_LENGTH
+                ));
+            }
+            let option = filter_message(state, option)
+                .map_err(|reason| format!("Message rejected: {}", reason))?;
+            if options.contains(&option) {
+                return Err("Options must be different from each other".to_string());
+            }
+            options.push(option);
+        }
+        self.options = options;
+        if let Some(seconds) = self.closes_in_seconds
+            && !(1..=MAX_OPEN_SECONDS).contains(&seconds)
+        {
+            return Err("A poll can stay open for at most 30 days".to_string());
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: i64,
+}
+
+/// A poll with its current results, attached to the message that asks it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollPayload {
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub multiple_choice: bool,
+    pub closes_at: Option<String>,
+    pub closed: bool,
+    /// Number of users who voted, which differs from the sum of the votes in
+    /// multiple choice polls
+    pub voters: i64,
+    /// Indexes of the options the requesting user voted for. Only set in
+    /// message history, never in broadcast events.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub my_votes: Option<Vec<i32>>,
+}
+
+/// Stores the poll asked by a message that is being created and returns it
+/// without any votes
+pub async fn create_poll<'e, E>(
+    executor: E,
+    message_id: i32,
+    poll: PollDraft,
+) -> Result<PollPayload, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let (closes_at,) = sqlx::query_as::<_, (Option<String>,)>(
+        "INSERT INTO polls (message_id, question, options, multiple_choice, closes_at)
+         VALUES ($1, $2, $3::JSONB, $4,
+                 CURRENT_TIMESTAMP + make_interval(secs => $5::DOUBLE PRECISION))
+         RETURNING closes_at::text",
+    )
+    .bind(message_id)
+    .bind(&poll.question)
+    .bind(serde_json::to_string(&poll.options).unwrap_or_else(|_| "[]".to_string()))
+    .bind(poll.multiple_choice)
+    .bind(poll.closes_in_seconds)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(PollPayload {
+        question: poll.question,
+        options: poll
+            .options
+            .into_iter()
+            .map(|text| PollOption { text, votes: 0 })
+            .collect(),
+        multiple_choice: poll.multiple_choice,
+        closes_at,
+        closed: false,
+        voters: 0,
+        my_votes: None,
+    })
+}
+
+/// Loads the polls asked by any of the given messages, with their results.
+/// `viewer_id` fills in `my_votes` for that user.
+pub async fn load_polls(
+    pool: &Pool<Postgres>,
+    message_ids: &[i32],
+    viewer_id: Option<i32>,
+) -> Result<HashMap<i32, PollPayload>, sqlx::Error> {
+    if message_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let polls = sqlx::query_as::<_, (i32, String, String, bool, Option<String>, bool)>(
+        "SELECT message_id, question, options::text, multiple_choice, closes_at::text,
+                COALESCE(closes_at <= CURRENT_TIMESTAMP, FALSE)
+         FROM polls WHERE message_id = ANY($1)",
+    )
+    .bind(message_ids)
+    .fetch_all(pool)
+    .await?;
+    if polls.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let votes = sqlx::query_as::<_, (i32, i32, i64, i64)>(
+        "SELECT message_id, option_index, COUNT(*),
+                (SELECT COUNT(DISTINCT user_id) FROM poll_votes v WHERE v.message_id = pv.message_id)
+         FROM poll_votes pv
+         WHERE message_id = ANY($1)
+         GROUP BY message_id, option_index",
+    )
+    .bind(message_ids)
+    .fetch_all(pool)
+    .await?;
+
+    let my_votes = match viewer_id {
+        Some(viewer_id) => {
+            sqlx::query_as::<_, (i32, i32)>(
+                "SELECT message_id, option_index FROM poll_votes
+                 WHERE message_id = ANY($1) AND user_id = $2
+                 ORDER BY option_index",
+            )
+            .bind(message_ids)
+            .bind(viewer_id)
+            .fetch_all(pool)
+            .await?
+        }
+        None => vec![],
+    };
+
+    let mut payloads = HashMap::new();
+    for (message_id, question, options, multiple_choice, closes_at, closed) in polls {
+        let options: Vec<String> = serde_json::from_str(&options).unwrap_or_default();
+        payloads.insert(
+            message_id,
+            PollPayload {
+                question,
+                options: options
+                    .into_iter()
+                    .map(|text| PollOption { text, votes: 0 })
+                    .collect(),
+                multiple_choice,
+                closes_at,
+                closed,
+                voters: 0,
+                my_votes: viewer_id.map(|_| vec![]),
+            },
+        );
+    }
+    for (message_id, option_index, count, voters) in votes {
+        if let Some(poll) = payloads.get_mut(&message_id) {
+            poll.voters = voters;
+            if let Some(option) = poll.options.get_mut(option_index as usize) {
+                option.votes = count;
+            }
+        }
+    }
+    for (message_id, option_index) in my_votes {
+        if let Some(my_votes) = payloads
+            .get_mut(&message_id)
+            .and_then(|poll| poll.my_votes.as_mut())
+        {
+            my_votes.push(option_index);
+        }
+    }
+    Ok(payloads)
+}
+
+/// Replaces a user's votes in a poll of their workspace and broadcasts the new
+/// results to the poll's room. An empty list of options takes the vote back.
+/// The error is meant to be shown to the user.
+pub async fn cast_vote(
+    state: &AppState,
+    workspace_id: i32,
+    user_id: i32,
+    message_id: i32,
+    options: &[i32],
+) -> Result<(), String> {
+    let pool = &state.pool;
+    let poll = sqlx::query_as::<_, (i32, i32, bool, bool)>(
+        "SELECT m.room_id, jsonb_array_length(p.options), p.multiple_choice,
+                COALESCE(p.closes_at <= CURRENT_TIMESTAMP, FALSE)
+         FROM polls p
+         JOIN messages m ON m.id = p.message_id
+         JOIN rooms r ON r.id = m.room_id
+         WHERE p.message_id = $1 AND r.workspace_id = $2",
+    )
+    .bind(message_id)
+    .bind(workspace_id)
+    .fetch_optional(pool)
+    .await;
+
+    let (room_id, option_count, multiple_choice, closed) = match poll {
+        Ok(Some(poll)) => poll,
+        Ok(None) => return Err("No poll found with this id".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Err("Failed to vote".to_string());
+        }
+    };
+    if room_role(pool, room_id, user_id).await.is_none() {
+        return Err("You are not a member of this room".to_string());
+    }
+    if closed {
+        return Err("This poll is closed".to_string());
+    }
+    if !multiple_choice && options.len() > 1 {
+        return Err("You can only vote for one option in this poll".to_string());
+    }
+    if options
+        .iter()
+        .any(|option| *option < 0 || *option >= option_count)
+    {
+        return Err("No option found with this index".to_string());
+    }
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut transaction = pool.begin().await?;
+        sqlx::query("DELETE FROM poll_votes WHERE message_id = $1 AND user_id = $2")
+            .bind(message_id)
+            .bind(user_id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query(
+            "INSERT INTO poll_votes (message_id, user_id, option_index)
+             SELECT DISTINCT $1, $2, option_index FROM UNNEST($3::INT[]) AS option_index",
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(options)
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        eprintln!("Database error: {:?}", e);
+        return Err("Failed to vote".to_string());
+    }
+
+    match load_polls(pool, &[message_id], None).await {
+        Ok(mut polls) => {
+            if let Some(poll) = polls.remove(&message_id) {
+                let _ = state.tx.send(ServerEvent::PollUpdated {
+                    room_id,
+                    message_id,
+                    poll,
+                });
+            }
+        }
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+    Ok(())
+}
@@ -0,0 +1,398 @@
+use axum::{
+    body::Bytes,
+    extract::{Json, Multipart, Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use image::{ImageFormat, imageops::FilterType, io::Reader as ImageReader};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::websocket_handler::ServerEvent;
+use crate::workspaces::is_workspace_member;
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_STATUS_TEXT_LENGTH: usize = 100;
+/// Avatars are stored as square PNGs of this many pixels per side
+const AVATAR_SIZE: u32 = 256;
+/// Uploads larger than this in either dimension are rejected before decoding
+const MAX_UPLOAD_DIMENSION: u32 = 4096;
+
+/// What other users can see about an account. The email address is only
+/// included in the owner's own profile.
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub id: i32,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub bot: bool,
+    pub bio: Option<String>,
+    pub status_text: Option<String>,
+    pub avatar_url: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProfileResponse {
+    pub status: String,
+    pub message: String,
+    pub profile: Option<Profile>,
+}
+
+impl ProfileResponse {
+    fn error(message: String) -> Json<ProfileResponse> {
+        Json(ProfileResponse {
+            status: "error".to_string(),
+            message,
+            profile: None,
+        })
+    }
+}
+
+/// Loads a user's profile. The avatar URL carries the upload time so clients
+/// fetch the new image after it changes.
+pub async fn load_profile(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+    include_email: bool,
+) -> Result<Option<Profile>, sqlx::Error> {
+    let row = sqlx::query_as::<
+        _,
+        (
+            String,
+            String,
+            bool,
+            Option<String>,
+            Option<String>,
+            Option<i64>,
+            String,
+        ),
+    >(
+        "SELECT name, email, is_bot, bio, status_text,
+                EXTRACT(EPOCH FROM avatar_updated_at)::BIGINT, created_at::text
+         FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(
+        |(name, email, bot, bio, status_text, avatar_version, created_at)| Profile {
+            id: user_id,
+            name,
+            email: include_email.then_some(email),
+            bot,
+            bio,
+            status_text,
+            avatar_url: avatar_version
+                .map(|version| format!("/users/{}/avatar?v={}", user_id, version)),
+            created_at,
+        },
+    ))
+}
+
+/// Tells every workspace the user belongs to that their profile changed, so
+/// clients can update the names and avatars they show
+async fn broadcast_profile(state: &AppState, user_id: i32) {
+    let profile = match load_profile(&state.pool, user_id, false).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return;
+        }
+    };
+    let workspace_ids = sqlx::query_as::<_, (i32,)>(
+        "SELECT workspace_id FROM workspace_members WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match workspace_ids {
+        Ok(rows) => {
+            let _ = state.tx.send(ServerEvent::ProfileUpdated {
+                workspace_ids: rows.into_iter().map(|(id,)| id).collect(),
+                profile,
+            });
+        }
+        Err(e) => eprintln!("Database error: {:?}", e),
+    }
+}
+
+/// Shows the caller's own profile, including their email address
+pub async fn get_my_profile(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<ProfileResponse> {
+    match load_profile(&state.pool, auth.user_id, true).await {
+        Ok(Some(profile)) => Json(ProfileResponse {
+            status: "success".to_string(),
+            message: "Profile retrieved".to_string(),
+            profile: Some(profile),
+        }),
+        Ok(None) => ProfileResponse::error("No user found with this id".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            ProfileResponse::error(format!("Failed to load profile: {}", e))
+        }
+    }
+}
+
+/// Shows the public profile of a member of the caller's workspace. Users of
+/// other workspaces are reported as not found.
+pub async fn get_profile(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(user_id): Path<i32>,
+) -> Json<ProfileResponse> {
+    if !is_workspace_member(&state.pool, auth.workspace_id, user_id).await {
+        return ProfileResponse::error("No user found with this id".to_string());
+    }
+
+    match load_profile(&state.pool, user_id, false).await {
+        Ok(Some(profile)) => Json(ProfileResponse {
+            status: "success".to_string(),
+            message: "Profile retrieved".to_string(),
+            profile: Some(profile),
+        }),
+        Ok(None) => ProfileResponse::error("No user found with this id".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            ProfileResponse::error(format!("Failed to load profile: {}", e))
+        }
+    }
+}
+
+/// Fields left out are kept; an empty bio or status text clears it
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub name: Option<String>,
+    pub bio: Option<String>,
+    pub status_text: Option<String>,
+}
+
+/// Trims an optional text field and checks its length. Returns the value to
+/// store, `None` meaning the field is cleared.
+fn optional_text(value: &str, field: &str, max_length: usize) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.chars().count() > max_length {
+        return Err(format!(
+            "{} must be at most {} characters",
+            field, max_length
+        ));
+    }
+    Ok((!value.is_empty()).then(|| value.to_string()))
+}
+
+/// Updates the caller's name, bio and status text
+pub async fn update_my_profile(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Json<ProfileResponse> {
+    let name = payload.name.as_deref().map(str::trim);
+    if let Some(name) = name
+        && (name.is_empty() || name.chars().count() > MAX_NAME_LENGTH)
+    {
+        return ProfileResponse::error(format!(
+            "Name must be between 1 and {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    let bio = match payload.bio.as_deref() {
+        Some(bio) => match optional_text(bio, "Bio", MAX_BIO_LENGTH) {
+            Ok(bio) => Some(bio),
+            Err(message) => return ProfileResponse::error(message),
+        },
+        None => None,
+    };
+    let status_text = match payload.status_text.as_deref() {
+        Some(status_text) => {
+            match optional_text(status_text, "Status text", MAX_STATUS_TEXT_LENGTH) {
+                Ok(status_text) => Some(status_text),
+                Err(message) => return ProfileResponse::error(message),
+            }
+        }
+        None => None,
+    };
+
+    // Each field is only written when it was part of the request
+    let query_result = sqlx::query(
+        "UPDATE users SET
+            name = COALESCE($2, name),
+            bio = CASE WHEN $3 THEN $4 ELSE bio END,
+            status_text = CASE WHEN $5 THEN $6 ELSE status_text END
+         WHERE id = $1",
+    )
+    .bind(auth.user_id)
+    .bind(name)
+    .bind(bio.is_some())
+    .bind(bio.flatten())
+    .bind(status_text.is_some())
+    .bind(status_text.flatten())
+    .execute(&state.pool)
+    .await;
+
+    if let Err(e) = query_result {
+        eprintln!("Database error: {:?}", e);
+        return ProfileResponse::error(format!("Failed to update profile: {}", e));
+    }
+
+    broadcast_profile(&state, auth.user_id).await;
+    get_my_profile(State(state), auth).await
+}
+
+/// Decodes an uploaded image and scales and crops it to a square PNG
+fn resize_avatar(upload: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = ImageReader::new(Cursor::new(upload))
+        .with_guessed_format()
+        .map_err(|_| "Unsupported image format".to_string())?;
+    if reader.format().is_none() {
+        return Err("Unsupported image format".to_string());
+    }
+    let mut limits = image::io::Limits::default();
+    limits.max_image_width = Some(MAX_UPLOAD_DIMENSION);
+    limits.max_image_height = Some(MAX_UPLOAD_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader
+        .decode()
+        .map_err(|e| format!("Invalid image: {}", e))?;
+    let avatar = image.resize_to_fill(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+
+    let mut png = Vec::new();
+    avatar
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| format!("Failed to encode avatar: {}", e))?;
+    Ok(png)
+}
+
+/// Replaces the caller's avatar with the image in the `avatar` field of a
+/// multipart upload (PNG, JPEG, GIF or WebP)
+pub async fn upload_avatar(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    mut multipart: Multipart,
+) -> Json<ProfileResponse> {
+    let mut upload: Option<Bytes> = None;
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) if field.name() == Some("avatar") => match field.bytes().await {
+                Ok(bytes) => {
+                    upload = Some(bytes);
+                    break;
+                }
+                Err(e) => return ProfileResponse::error(format!("Failed to read upload: {}", e)),
+            },
+            Ok(Some(_)) => continue,
+            Ok(None) => break,
+            Err(e) => return ProfileResponse::error(format!("Failed to read upload: {}", e)),
+        }
+    }
+    let Some(upload) = upload else {
+        return ProfileResponse::error("The upload must have an avatar field".to_string());
+    };
+
+    // Decoding and resizing is CPU bound, keep it off the async workers
+    let avatar = match tokio::task::spawn_blocking(move || resize_avatar(&upload)).await {
+        Ok(Ok(avatar)) => avatar,
+        Ok(Err(message)) => return ProfileResponse::error(message),
+        Err(e) => {
+            eprintln!("Avatar task failed: {:?}", e);
+            return ProfileResponse::error("Failed to process avatar".to_string());
+        }
+    };
+
+    let query_result = sqlx::query(
+        "UPDATE users SET avatar = $2, avatar_updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+    )
+    .bind(auth.user_id)
+    .bind(avatar)
+    .execute(&state.pool)
+    .await;
+
+    if let Err(e) = query_result {
+        eprintln!("Database error: {:?}", e);
+        return ProfileResponse::error(format!("Failed to update avatar: {}", e));
+    }
+
+    broadcast_profile(&state, auth.user_id).await;
+    get_my_profile(State(state), auth).await
+}
+
+/// Removes the caller's avatar
+pub async fn delete_avatar(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query(
+        "UPDATE users SET avatar = NULL, avatar_updated_at = NULL
+         WHERE id = $1 AND avatar IS NOT NULL",
+    )
+    .bind(auth.user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have an avatar".to_string(),
+        }),
+        Ok(_) => {
+            broadcast_profile(&state, auth.user_id).await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Avatar removed".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to remove avatar: {}", e),
+            })
+        }
+    }
+}
+
+/// Serves the avatar of a member of the caller's workspace as a PNG
+pub async fn get_avatar(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(user_id): Path<i32>,
+) -> Response {
+    if !is_workspace_member(&state.pool, auth.workspace_id, user_id).await {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let query_result = sqlx::query_as::<_, (Vec<u8>,)>(
+        "SELECT avatar FROM users WHERE id = $1 AND avatar IS NOT NULL",
+    )
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(Some((avatar,))) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "private, max-age=86400"),
+            ],
+            avatar,
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use axum::extract::{ConnectInfo, Json, Path, Query, State};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::message_operations::{find_message, remove_message};
+use crate::moderation::{ModerationRequest, ban, mute};
+use crate::roles::{Authorized, permission, room_role};
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+
+const DEFAULT_QUEUE_SIZE: i64 = 50;
+const MAX_QUEUE_SIZE: i64 = 200;
+
+const STATUS_OPEN: &str = "open";
+const STATUS_RESOLVED: &str = "resolved";
+
+/// What a moderator did about a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Nothing wrong was found
+    Dismiss,
+    /// The reported message was deleted
+    DeleteMessage,
+    /// The reported user was muted
+    Mute,
+    /// The reported user was banned
+    Ban,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Dismiss => "dismiss",
+            Resolution::DeleteMessage => "delete_message",
+            Resolution::Mute => "mute",
+            Resolution::Ban => "ban",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Resolution> {
+        match value {
+            "dismiss" => Some(Resolution::Dismiss),
+            "delete_message" => Some(Resolution::DeleteMessage),
+            "mute" => Some(Resolution::Mute),
+            "ban" => Some(Resolution::Ban),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateReportRequest {
+    /// Message being reported; its author becomes the reported user
+    pub message_id: Option<i32>,
+    /// User being reported, when no message is given
+    pub user_id: Option<i32>,
+    pub reason: String,
+}
+
+/// Reports a message or a user to the moderators of the caller's workspace
+pub async fn create_report(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<CreateReportRequest>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+
+    let reason = payload.reason.trim();
+    if reason.is_empty() || reason.len() > 1000 {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Reason must be between 1 and 1000 characters".to_string(),
+        });
+    }
+
+    // Keep a copy of the message so the report still makes sense if it is deleted
+    let (reported_user_id, message) = match (payload.message_id, payload.user_id) {
+        (Some(message_id), None) => match find_message(pool, auth.workspace_id, message_id).await {
+            // Only messages of rooms the caller is in can be reported
+            Ok(Some(message))
+                if room_role(pool, message.room_id, auth.user_id)
+                    .await
+                    .is_some() =>
+            {
+                (message.author_id, Some(message))
+            }
+            Ok(_) => {
+                return Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: "No message found with this id".to_string(),
+                });
+            }
+            Err(e) => {
+                eprintln!("Database error: {:?}", e);
+                return Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: format!("Failed to create report: {}", e),
+                });
+            }
+        },
+        (None, Some(user_id)) => {
+            let member = sqlx::query(
+                "SELECT 1 FROM workspace_members WHERE workspace_id = $1 AND user_id = $2",
+            )
+            .bind(auth.workspace_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await;
+            match member {
+                Ok(Some(_)) => (user_id, None),
+                Ok(None) => {
+                    return Json(ApiResponse {
+                        status: "error".to_string(),
+                        message: "No user found with this id in this workspace".to_string(),
+                    });
+                }
+                Err(e) => {
+                    eprintln!("Database error: {:?}", e);
+                    return Json(ApiResponse {
+                        status: "error".to_string(),
+                        message: format!("Failed to create report: {}", e),
+                    });
+                }
+            }
+        }
+        _ => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "Report either a message_id or a user_id".to_string(),
+            });
+        }
+    };
+
+    if reported_user_id == auth.user_id {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You can't report yourself".to_string(),
+        });
+    }
+
+    // One open report per reporter and target is enough
+    let duplicate = sqlx::query_as::<_, (i32,)>(
+        "SELECT id FROM reports
+         WHERE reporter_id = $1 AND reported_user_id = $2
+           AND message_id IS NOT DISTINCT FROM $3 AND status = $4",
+    )
+    .bind(auth.user_id)
+    .bind(reported_user_id)
+    .bind(payload.message_id)
+    .bind(STATUS_OPEN)
+    .fetch_optional(pool)
+    .await;
+
+    if let Ok(Some(_)) = duplicate {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You have already reported this".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO reports
+            (reporter_id, reported_user_id, workspace_id, message_id, room_id, content, reason)
+         VALUES ($1, $2, $3, $4, $5, $6, $7)
+         RETURNING id",
+    )
+    .bind(auth.user_id)
+    .bind(reported_user_id)
+    .bind(auth.workspace_id)
+    .bind(payload.message_id)
+    .bind(message.as_ref().map(|message| message.room_id))
+    .bind(message.map(|message| message.content))
+    .bind(reason)
+    .fetch_one(pool)
+    .await;
+
+    match query_result {
+        Ok((report_id,)) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("Report {} submitted", report_id),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to create report: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQueueQuery {
+    /// `open` (default), `resolved` or `all`
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportInfo {
+    pub id: i32,
+    pub reporter_id: Option<i32>,
+    pub reported_user_id: i32,
+    pub message_id: Option<i32>,
+    pub room_id: Option<i32>,
+    /// Content of the reported message when the report was made
+    pub content: Option<String>,
+    pub reason: String,
+    pub status: String,
+    pub resolution: Option<String>,
+    pub resolution_note: Option<String>,
+    pub resolved_by: Option<i32>,
+    pub created_at: String,
+    pub resolved_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportQueueResponse {
+    pub status: String,
+    pub reports: Vec<ReportInfo>,
+}
+
+type ReportRow = (
+    i32,
+    Option<i32>,
+    i32,
+    Option<i32>,
+    Option<i32>,
+    Option<String>,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<i32>,
+    String,
+    Option<String>,
+);
+
+/// Lists the workspace's reports for moderators, oldest open report first
+pub async fn list_reports(
+    State(state): State<Arc<AppState>>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Query(params): Query<ReportQueueQuery>,
+) -> Json<ReportQueueResponse> {
+    let status = match params.status.as_deref() {
+        None | Some(STATUS_OPEN) => Some(STATUS_OPEN),
+        Some(STATUS_RESOLVED) => Some(STATUS_RESOLVED),
+        _ => None,
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_QUEUE_SIZE)
+        .clamp(1, MAX_QUEUE_SIZE);
+
+    let query_result = sqlx::query_as::<_, ReportRow>(
+        "SELECT id, reporter_id, reported_user_id, message_id, room_id, content, reason, status,
+                resolution, resolution_note, resolved_by, created_at::text, resolved_at::text
+         FROM reports
+         WHERE workspace_id = $1 AND ($2::TEXT IS NULL OR status = $2)
+         ORDER BY created_at ASC
+         LIMIT $3",
+    )
+    .bind(moderator.auth.workspace_id)
+    .bind(status)
+    .bind(limit)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(ReportQueueResponse {
+            status: "success".to_string(),
+            reports: rows
+                .into_iter()
+                .map(
+                    |(
+                        id,
+                        reporter_id,
+                        reported_user_id,
+                        message_id,
+                        room_id,
+                        content,
+                        reason,
+                        status,
+                        resolution,
+                        resolution_note,
+                        resolved_by,
+                        created_at,
+                        resolved_at,
+                    )| ReportInfo {
+                        id,
+                        reporter_id,
+                        reported_user_id,
+                        message_id,
+                        room_id,
+                        content,
+                        reason,
+                        status,
+                        resolution,
+                        resolution_note,
+                        resolved_by,
+                        created_at,
+                        resolved_at,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ReportQueueResponse {
+                status: "error".to_string(),
+                reports: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveReportRequest {
+    /// `dismiss`, `delete_message`, `mute` or `ban`
+    pub action: String,
+    /// Explanation kept with the report; also used as the mute or ban reason
+    pub note: Option<String>,
+    /// Length of a mute or ban; permanent when omitted
+    pub duration_minutes: Option<i32>,
+}
+
+/// Resolves an open report, carrying out the chosen action. The report is
+/// claimed first, so when two moderators resolve it at once only one of them
+/// acts on it. It is opened again if the action fails.
+pub async fn resolve_report(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    moderator: Authorized<permission::ModerateUsers>,
+    Path(report_id): Path<i32>,
+    Json(payload): Json<ResolveReportRequest>,
+) -> Json<ApiResponse> {
+    let pool = &state.pool;
+    let ip_address = addr.ip().to_string();
+
+    let Some(resolution) = Resolution::parse(&payload.action) else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Action must be one of dismiss, delete_message, mute or ban".to_string(),
+        });
+    };
+
+    let note = payload
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+
+    let report = sqlx::query_as::<_, (i32, Option<i32>, String)>(
+        "UPDATE reports
+         SET status = $1, resolution = $2, resolution_note = $3, resolved_by = $4,
+             resolved_at = CURRENT_TIMESTAMP
+         WHERE id = $5 AND workspace_id = $6 AND status = $7
+         RETURNING reported_user_id, message_id, reason",
+    )
+    .bind(STATUS_RESOLVED)
+    .bind(resolution.as_str())
+    .bind(note)
+    .bind(moderator.auth.user_id)
+    .bind(report_id)
+    .bind(moderator.auth.workspace_id)
+    .bind(STATUS_OPEN)
+    .fetch_optional(pool)
+    .await;
+
+    let (reported_user_id, message_id, report_reason) = match report {
+        Ok(Some(report)) => report,
+        Ok(None) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: "No open report found with this id".to_string(),
+            });
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to resolve report: {}", e),
+            });
+        }
+    };
+
+    let outcome = match resolution {
+        Resolution::Dismiss => Ok(()),
+        Resolution::DeleteMessage => match message_id {
+            None => Err("This report is not about a message".to_string()),
+            // The message may already be gone, which is fine
+            Some(message_id) => {
+                match find_message(pool, moderator.auth.workspace_id, message_id).await {
+                    Ok(Some(message)) => {
+                        remove_message(&state, &message, moderator.auth.user_id, &ip_address)
+                            .await
+                            .map_err(|e| {
+                                eprintln!("Database error: {:?}", e);
+                                format!("Failed to delete message: {}", e)
+                            })
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => {
+                        eprintln!("Database error: {:?}", e);
+                        Err(format!("Failed to delete message: {}", e))
+                    }
+                }
+            }
+        },
+        Resolution::Mute | Resolution::Ban => {
+            let request = ModerationRequest {
+                user_id: reported_user_id,
+                reason: note.unwrap_or(&report_reason).to_string(),
+                duration_minutes: payload.duration_minutes,
+                room_id: None,
+            };
+            if resolution == Resolution::Mute {
+                mute(&state, &moderator.auth, &ip_address, &request).await
+            } else {
+                ban(&state, &moderator.auth, &ip_address, &request).await
+            }
+        }
+    };
+
+    if let Err(message) = outcome {
+        let reopened = sqlx::query(
+            "UPDATE reports
+             SET status = $1, resolution = NULL, resolution_note = NULL, resolved_by = NULL,
+                 resolved_at = NULL
+             WHERE id = $2",
+        )
+        .bind(STATUS_OPEN)
+        .bind(report_id)
+        .execute(pool)
+        .await;
+        if let Err(e) = reopened {
+            eprintln!("Database error: {:?}", e);
+        }
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        });
+    }
+
+    record_event(
+        pool,
+        AuditEvent::ReportResolved,
+        Some(moderator.auth.user_id),
+        Some(reported_user_id),
+        Some(&ip_address),
+        serde_json::json!({
+            "report_id": report_id,
+            "message_id": message_id,
+            "resolution": resolution.as_str(),
+            "note": note,
+        }),
+    )
+    .await;
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Report resolved: {}", resolution.as_str()),
+    })
+}
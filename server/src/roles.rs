@@ -0,0 +1,273 @@
+use axum::{
+    Json, async_trait,
+    extract::FromRequestParts,
+    http::{StatusCode, request::Parts},
+};
+use sqlx::{Pool, Postgres};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+
+/// Actions that are restricted to some users
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Delete messages written by other users
+    DeleteAnyMessage,
+    /// Pin and unpin messages in a room
+    PinMessages,
+    /// Rename and delete rooms, and change roles inside them
+    ManageRooms,
+    /// Change global roles and use the admin endpoints
+    ManageRoles,
+    /// Ban, mute and kick users
+    ModerateUsers,
+    /// Read the audit log
+    ViewAuditLog,
+    /// Post in read-only rooms and ignore slow mode
+    BypassRoomLimits,
+    /// Create registration invites and manage everyone's invites
+    ManageInvites,
+    /// Add and remove members of a workspace and change their workspace role
+    ManageMembers,
+    /// Create new workspaces
+    ManageWorkspaces,
+    /// Register and remove the webhooks of a workspace
+    ManageWebhooks,
+    /// Create and remove the bots and incoming webhooks of a workspace
+    ManageBots,
+}
+
+/// Site-wide role, stored in `users.role`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Moderator,
+    Member,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::Member => "member",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Role> {
+        match value {
+            "admin" => Some(Role::Admin),
+            "moderator" => Some(Role::Moderator),
+            "member" => Some(Role::Member),
+            _ => None,
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Moderator => matches!(
+                permission,
+                Permission::DeleteAnyMessage
+                    | Permission::PinMessages
+                    | Permission::ModerateUsers
+                    | Permission::BypassRoomLimits
+            ),
+            Role::Member => false,
+        }
+    }
+}
+
+/// Role inside a single room, stored in `room_members.role`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomRole {
+    Owner,
+    Moderator,
+    Member,
+}
+
+impl RoomRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomRole::Owner => "owner",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Member => "member",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<RoomRole> {
+        match value {
+            "owner" => Some(RoomRole::Owner),
+            "moderator" => Some(RoomRole::Moderator),
+            "member" => Some(RoomRole::Member),
+            _ => None,
+        }
+    }
+
+    pub fn has(&self, permission: Permission) -> bool {
+        match self {
+            RoomRole::Owner => matches!(
+                permission,
+                Permission::DeleteAnyMessage
+                    | Permission::PinMessages
+                    | Permission::ManageRooms
+                    | Permission::ModerateUsers
+                    | Permission::BypassRoomLimits
+            ),
+            RoomRole::Moderator => matches!(
+                permission,
+                Permission::DeleteAnyMessage
+                    | Permission::PinMessages
+                    | Permission::ModerateUsers
+                    | Permission::BypassRoomLimits
+            ),
+            RoomRole::Member => false,
+        }
+    }
+}
+
+/// The user's role in a room, or `None` when they are not a member
+pub async fn room_role(pool: &Pool<Postgres>, room_id: i32, user_id: i32) -> Option<RoomRole> {
+    let result = sqlx::query_as::<_, (String,)>(
+        "SELECT role FROM room_members WHERE room_id = $1 AND user_id = $2",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(row) => row.and_then(|(role,)| RoomRole::parse(&role)),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Whether the user holds `permission` in a room, either through their global
+/// or workspace role or through their role in that room. Rooms of other
+/// workspaces are always refused.
+pub async fn has_room_permission(
+    pool: &Pool<Postgres>,
+    auth: &AuthSession,
+    room_id: i32,
+    permission: Permission,
+) -> bool {
+    let result = sqlx::query_as::<_, (Option<String>,)>(
+        "SELECT rm.role FROM rooms r
+         LEFT JOIN room_members rm ON rm.room_id = r.id AND rm.user_id = $2
+         WHERE r.id = $1 AND r.workspace_id = $3",
+    )
+    .bind(room_id)
+    .bind(auth.user_id)
+    .bind(auth.workspace_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(Some((role,))) => {
+            auth.has(permission)
+                || role
+                    .as_deref()
+                    .and_then(RoomRole::parse)
+                    .is_some_and(|role| role.has(permission))
+        }
+        Ok(None) => false,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Ties a marker type to the permission an [`Authorized`] extractor checks
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+pub mod permission {
+    use super::{Permission, RequiredPermission};
+
+    pub struct ManageRoles;
+
+    impl RequiredPermission for ManageRoles {
+        const PERMISSION: Permission = Permission::ManageRoles;
+    }
+
+    pub struct ModerateUsers;
+
+    impl RequiredPermission for ModerateUsers {
+        const PERMISSION: Permission = Permission::ModerateUsers;
+    }
+
+    pub struct ViewAuditLog;
+
+    impl RequiredPermission for ViewAuditLog {
+        const PERMISSION: Permission = Permission::ViewAuditLog;
+    }
+
+    pub struct ManageMembers;
+
+    impl RequiredPermission for ManageMembers {
+        const PERMISSION: Permission = Permission::ManageMembers;
+    }
+
+    pub struct ManageWorkspaces;
+
+    impl RequiredPermission for ManageWorkspaces {
+        const PERMISSION: Permission = Permission::ManageWorkspaces;
+    }
+
+    pub struct ManageWebhooks;
+
+    impl RequiredPermission for ManageWebhooks {
+        const PERMISSION: Permission = Permission::ManageWebhooks;
+    }
+
+    pub struct ManageBots;
+
+    impl RequiredPermission for ManageBots {
+        const PERMISSION: Permission = Permission::ManageBots;
+    }
+}
+
+/// Extracts the authenticated session and rejects the request with 403 unless
+/// the user's global or workspace role grants `P`, e.g. `Authorized<permission::ManageRoles>`
+pub struct Authorized<P> {
+    pub auth: AuthSession,
+    _permission: PhantomData<P>,
+}
+
+#[async_trait]
+impl<P> FromRequestParts<Arc<AppState>> for Authorized<P>
+where
+    P: RequiredPermission + Send + Sync,
+{
+    type Rejection = (StatusCode, Json<ApiResponse>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let auth = AuthSession::from_request_parts(parts, state).await?;
+
+        if !auth.has(P::PERMISSION) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: "You don't have permission to do this".to_string(),
+                }),
+            ));
+        }
+
+        Ok(Authorized {
+            auth,
+            _permission: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,621 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::connections::ConnectionControl;
+use crate::roles::{Permission, Role, RoomRole, has_room_permission};
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::webhooks::{WebhookEvent, queue_event};
+use crate::workspaces::{WorkspaceRole, is_default_room};
+
+const DEFAULT_ROOM_NAME: &str = "general";
+const MAX_SLOW_MODE_SECONDS: i32 = 6 * 60 * 60;
+
+/// Creates the room every member of a workspace belongs to, if needed, and
+/// returns its id. A room already named like the default room is adopted.
+pub async fn ensure_default_room(
+    pool: &Pool<Postgres>,
+    workspace_id: i32,
+) -> Result<i32, sqlx::Error> {
+    let (existing,) =
+        sqlx::query_as::<_, (Option<i32>,)>("SELECT default_room_id FROM workspaces WHERE id = $1")
+            .bind(workspace_id)
+            .fetch_one(pool)
+            .await?;
+    if let Some(room_id) = existing {
+        return Ok(room_id);
+    }
+
+    let (room_id,) = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO rooms (name, workspace_id) VALUES ($1, $2)
+         ON CONFLICT (workspace_id, name) DO UPDATE SET name = EXCLUDED.name
+         RETURNING id",
+    )
+    .bind(DEFAULT_ROOM_NAME)
+    .bind(workspace_id)
+    .fetch_one(pool)
+    .await?;
+
+    sqlx::query("UPDATE workspaces SET default_room_id = $1 WHERE id = $2")
+        .bind(room_id)
+        .bind(workspace_id)
+        .execute(pool)
+        .await?;
+    Ok(room_id)
+}
+
+/// Whether a room exists in the given workspace
+pub async fn room_in_workspace(pool: &Pool<Postgres>, room_id: i32, workspace_id: i32) -> bool {
+    let result = sqlx::query("SELECT 1 FROM rooms WHERE id = $1 AND workspace_id = $2")
+        .bind(room_id)
+        .bind(workspace_id)
+        .fetch_optional(pool)
+        .await;
+
+    match result {
+        Ok(row) => row.is_some(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Checks that a user may join a room of their workspace on their own.
+/// Private rooms can only be entered with an invite. The error is meant to be
+/// shown to the user.
+pub async fn check_can_join(
+    pool: &Pool<Postgres>,
+    workspace_id: i32,
+    room_id: i32,
+    user_id: i32,
+) -> Result<(), String> {
+    let room = sqlx::query_as::<_, (bool, bool)>(
+        "SELECT r.is_private,
+                EXISTS (SELECT 1 FROM room_members rm WHERE rm.room_id = r.id AND rm.user_id = $2)
+         FROM rooms r WHERE r.id = $1 AND r.workspace_id = $3",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(workspace_id)
+    .fetch_optional(pool)
+    .await;
+
+    match room {
+        Ok(Some((true, false))) => {
+            Err("This room is private, you need an invite to join it".to_string())
+        }
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("No room found with this id".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err("Failed to join room".to_string())
+        }
+    }
+}
+
+/// Adds a user to a room; does nothing if they already are a member
+pub async fn add_room_member(
+    pool: &Pool<Postgres>,
+    room_id: i32,
+    user_id: i32,
+    role: RoomRole,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let joined = sqlx::query_as::<_, (i32, String)>(
+        "WITH joined AS (
+             INSERT INTO room_members (room_id, user_id, role) VALUES ($1, $2, $3)
+             ON CONFLICT (room_id, user_id) DO NOTHING
+             RETURNING room_id
+         )
+         SELECT rooms.workspace_id, users.name FROM joined
+         JOIN rooms ON rooms.id = joined.room_id
+         JOIN users ON users.id = $2",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(role.as_str())
+    .fetch_optional(&mut transaction)
+    .await?;
+
+    if let Some((workspace_id, username)) = joined {
+        queue_event(
+            &mut transaction,
+            workspace_id,
+            Some(room_id),
+            WebhookEvent::MemberJoined,
+            serde_json::json!({ "room_id": room_id, "user_id": user_id, "username": username }),
+        )
+        .await?;
+    }
+    transaction.commit().await
+}
+
+/// Ids of every room of a workspace the user is a member of
+pub async fn member_room_ids(pool: &Pool<Postgres>, user_id: i32, workspace_id: i32) -> Vec<i32> {
+    let result = sqlx::query_as::<_, (i32,)>(
+        "SELECT rm.room_id FROM room_members rm
+         JOIN rooms r ON r.id = rm.room_id
+         WHERE rm.user_id = $1 AND r.workspace_id = $2",
+    )
+    .bind(user_id)
+    .bind(workspace_id)
+    .fetch_all(pool)
+    .await;
+
+    match result {
+        Ok(rows) => rows.into_iter().map(|(room_id,)| room_id).collect(),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// Checks that a user may post in a room of their workspace right now: they
+/// must be a member and, unless they moderate the room, respect its read-only
+/// and slow mode settings. Passing slow mode records the post, so call this in
+/// the transaction that stores the message: if storing fails, the slot is
+/// given back. The error is meant to be shown to the user.
+pub async fn check_can_post(
+    connection: &mut PgConnection,
+    workspace_id: i32,
+    room_id: i32,
+    user_id: i32,
+) -> Result<(), String> {
+    let room = sqlx::query_as::<_, (String, String, String, bool, i32)>(
+        "SELECT u.role, wm.role, rm.role, r.read_only, r.slow_mode_seconds
+         FROM rooms r
+         JOIN room_members rm ON rm.room_id = r.id AND rm.user_id = $2
+         JOIN users u ON u.id = rm.user_id
+         JOIN workspace_members wm ON wm.workspace_id = r.workspace_id AND wm.user_id = u.id
+         WHERE r.id = $1 AND r.workspace_id = $3",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(workspace_id)
+    .fetch_optional(&mut *connection)
+    .await;
+
+    let (role, workspace_role, room_role, read_only, slow_mode_seconds) = match room {
+        Ok(Some(room)) => room,
+        Ok(None) => return Err("You are not a member of this room".to_string()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Err("Failed to check room settings".to_string());
+        }
+    };
+
+    if !read_only && slow_mode_seconds == 0 {
+        return Ok(());
+    }
+    let exempt = Role::parse(&role).is_some_and(|role| role.has(Permission::BypassRoomLimits))
+        || WorkspaceRole::parse(&workspace_role)
+            .is_some_and(|role| role.has(Permission::BypassRoomLimits))
+        || RoomRole::parse(&room_role).is_some_and(|role| role.has(Permission::BypassRoomLimits));
+    if exempt {
+        return Ok(());
+    }
+    if read_only {
+        return Err("This room is read-only".to_string());
+    }
+
+    // The row lock of the upsert makes concurrent posts wait for each other,
+    // so only one of them can claim the slot
+    let claimed = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO room_post_times (room_id, user_id) VALUES ($1, $2)
+         ON CONFLICT (room_id, user_id) DO UPDATE SET last_posted_at = CURRENT_TIMESTAMP
+         WHERE room_post_times.last_posted_at
+               <= CURRENT_TIMESTAMP - make_interval(secs => $3::DOUBLE PRECISION)
+         RETURNING room_id",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(slow_mode_seconds)
+    .fetch_optional(&mut *connection)
+    .await;
+
+    match claimed {
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Err("Failed to check room settings".to_string());
+        }
+    }
+
+    let wait = sqlx::query_as::<_, (i64,)>(
+        "SELECT GREATEST(CEIL(EXTRACT(EPOCH FROM (
+                    last_posted_at + make_interval(secs => $3::DOUBLE PRECISION) - CURRENT_TIMESTAMP
+                )))::BIGINT, 1)
+         FROM room_post_times WHERE room_id = $1 AND user_id = $2",
+    )
+    .bind(room_id)
+    .bind(user_id)
+    .bind(slow_mode_seconds)
+    .fetch_one(&mut *connection)
+    .await;
+
+    match wait {
+        Ok((seconds,)) => Err(format!(
+            "Slow mode is on, wait {} seconds before posting again",
+            seconds
+        )),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err("Failed to check room settings".to_string())
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub id: i32,
+    pub name: String,
+    pub member_count: i64,
+    /// Minimum number of seconds between two messages of a user; 0 when off
+    pub slow_mode_seconds: i32,
+    /// Only moderators can post
+    pub read_only: bool,
+    /// Set with the `/topic` command
+    pub topic: Option<String>,
+    /// Only members can see the room, others need an invite to join it
+    pub private: bool,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomsResponse {
+    pub status: String,
+    pub rooms: Vec<RoomInfo>,
+}
+
+/// Lists the rooms of the caller's workspace, leaving out private rooms the
+/// caller isn't a member of
+pub async fn list_rooms(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<RoomsResponse> {
+    let query_result =
+        sqlx::query_as::<_, (i32, String, i64, i32, bool, Option<String>, bool, String)>(
+            "SELECT r.id, r.name, COUNT(rm.user_id), r.slow_mode_seconds, r.read_only, r.topic,
+                r.is_private, r.created_at::text
+         FROM rooms r
+         LEFT JOIN room_members rm ON rm.room_id = r.id
+         WHERE r.workspace_id = $1
+           AND (NOT r.is_private
+                OR EXISTS (SELECT 1 FROM room_members m WHERE m.room_id = r.id AND m.user_id = $2))
+         GROUP BY r.id
+         ORDER BY r.id",
+        )
+        .bind(auth.workspace_id)
+        .bind(auth.user_id)
+        .fetch_all(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(rows) => Json(RoomsResponse {
+            status: "success".to_string(),
+            rooms: rows
+                .into_iter()
+                .map(
+                    |(
+                        id,
+                        name,
+                        member_count,
+                        slow_mode_seconds,
+                        read_only,
+                        topic,
+                        private,
+                        created_at,
+                    )| {
+                        RoomInfo {
+                            id,
+                            name,
+                            member_count,
+                            slow_mode_seconds,
+                            read_only,
+                            topic,
+                            private,
+                            created_at,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(RoomsResponse {
+                status: "error".to_string(),
+                rooms: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomNameRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRoomRequest {
+    pub name: String,
+    #[serde(default)]
+    pub private: bool,
+}
+
+/// Creates a room in the caller's workspace, owned by the caller
+pub async fn create_room(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<CreateRoomRequest>,
+) -> Json<ApiResponse> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Room name must be between 1 and 100 characters".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO rooms (name, created_by, workspace_id, is_private)
+         VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(name)
+    .bind(auth.user_id)
+    .bind(auth.workspace_id)
+    .bind(payload.private)
+    .fetch_one(&state.pool)
+    .await;
+
+    let room_id = match query_result {
+        Ok((room_id,)) => room_id,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to create room: {}", e),
+            });
+        }
+    };
+
+    if let Err(e) = add_room_member(&state.pool, room_id, auth.user_id, RoomRole::Owner).await {
+        eprintln!("Database error: {:?}", e);
+    }
+    state.connections.send_to_member(
+        auth.workspace_id,
+        auth.user_id,
+        ConnectionControl::JoinRoom(room_id),
+    );
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Room created with id {}", room_id),
+    })
+}
+
+pub async fn rename_room(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(room_id): Path<i32>,
+    Json(payload): Json<RoomNameRequest>,
+) -> Json<ApiResponse> {
+    if !has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to manage this room".to_string(),
+        });
+    }
+
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Room name must be between 1 and 100 characters".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query("UPDATE rooms SET name = $1 WHERE id = $2")
+        .bind(name)
+        .bind(room_id)
+        .execute(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No room found with this id".to_string(),
+        }),
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Room renamed".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to rename room: {}", e),
+            })
+        }
+    }
+}
+
+pub async fn delete_room(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(room_id): Path<i32>,
+) -> Json<ApiResponse> {
+    if is_default_room(&state.pool, room_id).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "The default room can't be deleted".to_string(),
+        });
+    }
+
+    if !has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to manage this room".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query("DELETE FROM rooms WHERE id = $1")
+        .bind(room_id)
+        .execute(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No room found with this id".to_string(),
+        }),
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Room deleted".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to delete room: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomSettingsRequest {
+    /// 0 turns slow mode off
+    pub slow_mode_seconds: Option<i32>,
+    pub read_only: Option<bool>,
+}
+
+/// Changes the slow mode and read-only settings of a room; omitted settings are kept
+pub async fn update_room_settings(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(room_id): Path<i32>,
+    Json(payload): Json<RoomSettingsRequest>,
+) -> Json<ApiResponse> {
+    if !has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to manage this room".to_string(),
+        });
+    }
+
+    if payload
+        .slow_mode_seconds
+        .is_some_and(|seconds| !(0..=MAX_SLOW_MODE_SECONDS).contains(&seconds))
+    {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: format!(
+                "Slow mode must be between 0 and {} seconds",
+                MAX_SLOW_MODE_SECONDS
+            ),
+        });
+    }
+
+    let query_result = sqlx::query(
+        "UPDATE rooms
+         SET slow_mode_seconds = COALESCE($1, slow_mode_seconds),
+             read_only = COALESCE($2, read_only)
+         WHERE id = $3",
+    )
+    .bind(payload.slow_mode_seconds)
+    .bind(payload.read_only)
+    .bind(room_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No room found with this id".to_string(),
+        }),
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Room settings updated".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to update room settings: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleRequest {
+    pub role: String,
+}
+
+/// Changes the role of a member inside a room
+pub async fn set_room_role(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Path((room_id, user_id)): Path<(i32, i32)>,
+    Json(payload): Json<RoleRequest>,
+) -> Json<ApiResponse> {
+    let Some(role) = RoomRole::parse(&payload.role) else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Role must be one of owner, moderator or member".to_string(),
+        });
+    };
+
+    if !has_room_permission(&state.pool, &auth, room_id, Permission::ManageRooms).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You don't have permission to manage this room".to_string(),
+        });
+    }
+
+    let query_result =
+        sqlx::query("UPDATE room_members SET role = $1 WHERE room_id = $2 AND user_id = $3")
+            .bind(role.as_str())
+            .bind(room_id)
+            .bind(user_id)
+            .execute(&state.pool)
+            .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "This user is not a member of the room".to_string(),
+        }),
+        Ok(_) => {
+            record_event(
+                &state.pool,
+                AuditEvent::RoomRoleChanged,
+                Some(auth.user_id),
+                Some(user_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "room_id": room_id, "role": role.as_str() }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: format!("Room role changed to {}", role.as_str()),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to change room role: {}", e),
+            })
+        }
+    }
+}
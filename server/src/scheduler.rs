@@ -0,0 +1,677 @@
+use axum::extract::{Json, Path, State};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::AppState;
+use crate::connections::{ConnectionControl, Notice, NoticeKind};
+use crate::content_filter::filter_message;
+use crate::link_previews::parse_stored_previews;
+use crate::markdown::render_markdown;
+use crate::message_operations::{find_message, post_message};
+use crate::polls::load_polls;
+use crate::roles::room_role;
+use crate::room_operations::room_in_workspace;
+use crate::session_operations::AuthSession;
+use crate::user_operations::ApiResponse;
+use crate::websocket_handler::{ChatMessage, MessagePayload};
+use crate::workspaces::default_room_id;
+
+/// How often the scheduler looks for jobs that are due
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Jobs claimed per poll, per kind
+const BATCH_SIZE: i64 = 50;
+/// How far ahead messages and reminders can be scheduled
+const MAX_DELAY_SECONDS: i64 = 365 * 24 * 60 * 60;
+/// Scheduled messages and reminders a user can have pending in a workspace
+const MAX_PENDING_JOBS: i64 = 100;
+const MAX_NOTE_LENGTH: usize = 500;
+
+/// What a row of `scheduled_jobs` does when it comes due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobKind {
+    /// Post `content` in `room_id` on behalf of the user
+    Message,
+    /// Remind the user of `content` and/or `message_id`
+    Reminder,
+}
+
+impl JobKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Message => "message",
+            JobKind::Reminder => "reminder",
+        }
+    }
+}
+
+/// Delivers scheduled messages and reminders as they come due. Jobs live in
+/// the database, so anything that came due while the server was down is
+/// delivered on the first poll after it starts again.
+pub async fn run_scheduler(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        deliver_due_messages(&state).await;
+        deliver_due_reminders(&state).await;
+    }
+}
+
+/// Posts due scheduled messages through the same checks and broadcast as
+/// messages sent over the WebSocket. A job is claimed before it is posted so
+/// it is never posted twice; if posting fails, the reason is kept on the job
+/// and the author is told.
+async fn deliver_due_messages(state: &AppState) {
+    let query_result = sqlx::query_as::<_, (i32, i32, i32, i32, String)>(
+        "UPDATE scheduled_jobs SET delivered_at = CURRENT_TIMESTAMP
+         WHERE id IN (
+             SELECT id FROM scheduled_jobs
+             WHERE kind = $1 AND delivered_at IS NULL AND run_at <= CURRENT_TIMESTAMP
+             ORDER BY run_at
+             LIMIT $2
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, workspace_id, user_id, room_id, content",
+    )
+    .bind(JobKind::Message.as_str())
+    .bind(BATCH_SIZE)
+    .fetch_all(&state.pool)
+    .await;
+
+    let jobs = match query_result {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return;
+        }
+    };
+
+    for (job_id, workspace_id, user_id, room_id, content) in jobs {
+        let Err(reason) = post_message(state, workspace_id, user_id, room_id, &content, None).await
+        else {
+            continue;
+        };
+        if let Err(e) = sqlx::query("UPDATE scheduled_jobs SET failure = $2 WHERE id = $1")
+            .bind(job_id)
+            .bind(&reason)
+            .execute(&state.pool)
+            .await
+        {
+            eprintln!("Database error: {:?}", e);
+        }
+        state.connections.send_to_member(
+            workspace_id,
+            user_id,
+            ConnectionControl::Notice(
+                Notice::new(
+                    NoticeKind::ScheduledMessageFailed,
+                    format!("Your scheduled message could not be posted: {}", reason),
+                )
+                .in_room(room_id)
+                .with_detail("scheduled_message_id", job_id),
+            ),
+        );
+    }
+}
+
+/// Sends due reminders to the connections of their users. Reminders of users
+/// who aren't connected stay pending until they are.
+async fn deliver_due_reminders(state: &AppState) {
+    let (workspace_ids, user_ids): (Vec<i32>, Vec<i32>) =
+        state.connections.connected_members().into_iter().unzip();
+    if user_ids.is_empty() {
+        return;
+    }
+
+    let query_result = sqlx::query_as::<_, (i32, i32, i32, Option<i32>, Option<String>)>(
+        "UPDATE scheduled_jobs SET delivered_at = CURRENT_TIMESTAMP
+         WHERE id IN (
+             SELECT j.id FROM scheduled_jobs j
+             JOIN UNNEST($3::INT[], $4::INT[]) AS c(workspace_id, user_id)
+                 ON c.workspace_id = j.workspace_id AND c.user_id = j.user_id
+             WHERE j.kind = $1 AND j.delivered_at IS NULL AND j.run_at <= CURRENT_TIMESTAMP
+             ORDER BY j.run_at
+             LIMIT $2
+             FOR UPDATE OF j SKIP LOCKED
+         )
+         RETURNING id, workspace_id, user_id, message_id, content",
+    )
+    .bind(JobKind::Reminder.as_str())
+    .bind(BATCH_SIZE)
+    .bind(&workspace_ids)
+    .bind(&user_ids)
+    .fetch_all(&state.pool)
+    .await;
+
+    let reminders = match query_result {
+        Ok(reminders) => reminders,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return;
+        }
+    };
+
+    for (reminder_id, workspace_id, user_id, message_id, note) in reminders {
+        let message = match message_id {
+            Some(message_id) => load_chat_message(state, workspace_id, user_id, message_id).await,
+            None => None,
+        };
+        state.connections.send_to_member(
+            workspace_id,
+            user_id,
+            ConnectionControl::Reminder {
+                reminder_id,
+                note,
+                message,
+            },
+        );
+    }
+}
+
+/// Loads a message as it is sent to clients, if the user may still read it:
+/// reminders of users who left the message's room come without it
+async fn load_chat_message(
+    state: &AppState,
+    workspace_id: i32,
+    user_id: i32,
+    message_id: i32,
+) -> Option<ChatMessage> {
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            i32,
+            i32,
+            String,
+            String,
+            bool,
+            String,
+            String,
+            Option<String>,
+        ),
+    >(
+        "SELECT m.room_id, u.id, u.email, u.name, u.is_bot, m.content, m.created_at::text,
+                    m.link_previews::text
+         FROM messages m
+         JOIN rooms r ON r.id = m.room_id
+         JOIN users u ON u.id = m.user_id
+         WHERE m.id = $1 AND r.workspace_id = $2
+           AND EXISTS (SELECT 1 FROM room_members rm WHERE rm.room_id = m.room_id AND rm.user_id = $3)",
+    )
+    .bind(message_id)
+    .bind(workspace_id)
+    .bind(user_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    let (room_id, user_id, user_email, username, bot, content, timestamp, link_previews) =
+        match query_result {
+            Ok(row) => row?,
+            Err(e) => {
+                eprintln!("Database error: {:?}", e);
+                return None;
+            }
+        };
+    let payload = match load_polls(&state.pool, &[message_id], None).await {
+        Ok(mut polls) => polls.remove(&message_id).map(MessagePayload::Poll),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    };
+
+    Some(ChatMessage {
+        id: message_id,
+        room_id,
+        user_id,
+        user_email,
+        username,
+        bot,
+        html: render_markdown(&content),
+        content,
+        timestamp,
+        payload,
+        link_previews: parse_stored_previews(link_previews.as_deref()),
+    })
+}
+
+/// When a job should run: either an RFC 3339 time or a number of seconds from
+/// now. Returns the number of seconds from now.
+fn delay_seconds(at: Option<&str>, delay_seconds: Option<i64>) -> Result<i64, String> {
+    let seconds = match (at, delay_seconds) {
+        (Some(at), None) => {
+            let at = chrono::DateTime::parse_from_rfc3339(at)
+                .map_err(|_| "The time must be in RFC 3339 format".to_string())?;
+            (at.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds()
+        }
+        (None, Some(seconds)) => seconds,
+        _ => return Err("Give either a time or a delay in seconds".to_string()),
+    };
+    if seconds <= 0 {
+        return Err("The time must be in the future".to_string());
+    }
+    if seconds > MAX_DELAY_SECONDS {
+        return Err("The time can be at most a year from now".to_string());
+    }
+    Ok(seconds)
+}
+
+/// Stores a job and returns its id, unless the user has too many pending jobs
+async fn insert_job(
+    state: &AppState,
+    auth: &AuthSession,
+    kind: JobKind,
+    room_id: Option<i32>,
+    message_id: Option<i32>,
+    content: Option<&str>,
+    delay_seconds: i64,
+) -> Result<i32, String> {
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO scheduled_jobs
+            (kind, workspace_id, user_id, room_id, message_id, content, run_at)
+         SELECT $1, $2, $3, $4, $5, $6,
+                CURRENT_TIMESTAMP + make_interval(secs => $7::DOUBLE PRECISION)
+         WHERE (
+             SELECT COUNT(*) FROM scheduled_jobs
+             WHERE workspace_id = $2 AND user_id = $3 AND delivered_at IS NULL
+         ) < $8
+         RETURNING id",
+    )
+    .bind(kind.as_str())
+    .bind(auth.workspace_id)
+    .bind(auth.user_id)
+    .bind(room_id)
+    .bind(message_id)
+    .bind(content)
+    .bind(delay_seconds)
+    .bind(MAX_PENDING_JOBS)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(Some((job_id,))) => Ok(job_id),
+        Ok(None) => Err(format!(
+            "You can't have more than {} scheduled messages and reminders",
+            MAX_PENDING_JOBS
+        )),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err(format!("Failed to schedule: {}", e))
+        }
+    }
+}
+
+/// Reminds the user of a note after a delay, as the `/remind` command does,
+/// and returns the reminder's id
+pub async fn remind_later(
+    state: &AppState,
+    auth: &AuthSession,
+    note: &str,
+    delay: i64,
+) -> Result<i32, String> {
+    let delay = delay_seconds(None, Some(delay))?;
+    let note = note.trim();
+    if note.is_empty() {
+        return Err("A reminder needs a note".to_string());
+    }
+    if note.chars().count() > MAX_NOTE_LENGTH {
+        return Err(format!(
+            "The note must be at most {} characters",
+            MAX_NOTE_LENGTH
+        ));
+    }
+    insert_job(
+        state,
+        auth,
+        JobKind::Reminder,
+        None,
+        None,
+        Some(note),
+        delay,
+    )
+    .await
+}
+
+/// Deletes one of the caller's jobs that hasn't been delivered, or that failed
+async fn delete_job(state: &AppState, auth: &AuthSession, kind: JobKind, job_id: i32) -> bool {
+    let query_result = sqlx::query(
+        "DELETE FROM scheduled_jobs
+         WHERE id = $1 AND kind = $2 AND workspace_id = $3 AND user_id = $4
+           AND (delivered_at IS NULL OR failure IS NOT NULL)",
+    )
+    .bind(job_id)
+    .bind(kind.as_str())
+    .bind(auth.workspace_id)
+    .bind(auth.user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) => result.rows_affected() > 0,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleMessageRequest {
+    /// Defaults to the workspace's default room
+    pub room_id: Option<i32>,
+    pub content: String,
+    /// RFC 3339 time to post the message at
+    pub send_at: Option<String>,
+    /// Seconds from now to post the message in, instead of `send_at`
+    pub delay_seconds: Option<i64>,
+}
+
+/// Schedules a message to be posted later in a room the caller is a member of
+pub async fn schedule_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<ScheduleMessageRequest>,
+) -> Json<ApiResponse> {
+    let delay = match delay_seconds(payload.send_at.as_deref(), payload.delay_seconds) {
+        Ok(delay) => delay,
+        Err(message) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message,
+            });
+        }
+    };
+
+    let room_id = match payload.room_id {
+        Some(room_id) if room_in_workspace(&state.pool, room_id, auth.workspace_id).await => {
+            Some(room_id)
+        }
+        Some(_) => None,
+        None => default_room_id(&state.pool, auth.workspace_id).await,
+    };
+    let Some(room_id) = room_id else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No room found with this id".to_string(),
+        });
+    };
+    if room_role(&state.pool, room_id, auth.user_id)
+        .await
+        .is_none()
+    {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "You are not a member of this room".to_string(),
+        });
+    }
+
+    // The filter runs again when the message is posted, as it may have changed by then
+    if let Err(reason) = filter_message(&state, &payload.content) {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: format!("Message rejected: {}", reason),
+        });
+    }
+
+    match insert_job(
+        &state,
+        &auth,
+        JobKind::Message,
+        Some(room_id),
+        None,
+        Some(&payload.content),
+        delay,
+    )
+    .await
+    {
+        Ok(job_id) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("Message scheduled with id {}", job_id),
+        }),
+        Err(message) => Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        }),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduledMessageInfo {
+    pub id: i32,
+    pub room_id: i32,
+    pub content: String,
+    pub send_at: String,
+    /// Why the message could not be posted, for messages that failed
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduledMessagesResponse {
+    pub status: String,
+    pub messages: Vec<ScheduledMessageInfo>,
+}
+
+/// Lists the caller's pending and failed scheduled messages in their workspace
+pub async fn list_scheduled_messages(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<ScheduledMessagesResponse> {
+    let query_result = sqlx::query_as::<_, (i32, i32, String, String, Option<String>)>(
+        "SELECT id, room_id, content, run_at::text, failure
+         FROM scheduled_jobs
+         WHERE kind = $1 AND workspace_id = $2 AND user_id = $3
+           AND (delivered_at IS NULL OR failure IS NOT NULL)
+         ORDER BY run_at",
+    )
+    .bind(JobKind::Message.as_str())
+    .bind(auth.workspace_id)
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(ScheduledMessagesResponse {
+            status: "success".to_string(),
+            messages: rows
+                .into_iter()
+                .map(
+                    |(id, room_id, content, send_at, failure)| ScheduledMessageInfo {
+                        id,
+                        room_id,
+                        content,
+                        send_at,
+                        failure,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ScheduledMessagesResponse {
+                status: "error".to_string(),
+                messages: vec![],
+            })
+        }
+    }
+}
+
+/// Cancels a scheduled message, or dismisses one that failed
+pub async fn cancel_scheduled_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(job_id): Path<i32>,
+) -> Json<ApiResponse> {
+    if delete_job(&state, &auth, JobKind::Message, job_id).await {
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Scheduled message cancelled".to_string(),
+        })
+    } else {
+        Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No scheduled message found with this id".to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateReminderRequest {
+    /// Message to be reminded of
+    pub message_id: Option<i32>,
+    pub note: Option<String>,
+    /// RFC 3339 time of the reminder
+    pub remind_at: Option<String>,
+    /// Seconds from now, instead of `remind_at`
+    pub delay_seconds: Option<i64>,
+}
+
+/// Creates a personal reminder, about a message and/or with a note
+pub async fn create_reminder(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<CreateReminderRequest>,
+) -> Json<ApiResponse> {
+    let delay = match delay_seconds(payload.remind_at.as_deref(), payload.delay_seconds) {
+        Ok(delay) => delay,
+        Err(message) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message,
+            });
+        }
+    };
+
+    let note = payload
+        .note
+        .as_deref()
+        .map(str::trim)
+        .filter(|note| !note.is_empty());
+    if note.is_some_and(|note| note.chars().count() > MAX_NOTE_LENGTH) {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: format!("The note must be at most {} characters", MAX_NOTE_LENGTH),
+        });
+    }
+    if note.is_none() && payload.message_id.is_none() {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "A reminder needs a message or a note".to_string(),
+        });
+    }
+
+    if let Some(message_id) = payload.message_id {
+        // Only messages of rooms the caller can read, as for the history
+        match find_message(&state.pool, auth.workspace_id, message_id).await {
+            Ok(Some(message))
+                if room_role(&state.pool, message.room_id, auth.user_id)
+                    .await
+                    .is_some() => {}
+            Ok(_) => {
+                return Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: "No message found with this id".to_string(),
+                });
+            }
+            Err(e) => {
+                eprintln!("Database error: {:?}", e);
+                return Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: format!("Failed to schedule: {}", e),
+                });
+            }
+        }
+    }
+
+    match insert_job(
+        &state,
+        &auth,
+        JobKind::Reminder,
+        None,
+        payload.message_id,
+        note,
+        delay,
+    )
+    .await
+    {
+        Ok(job_id) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("Reminder created with id {}", job_id),
+        }),
+        Err(message) => Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        }),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReminderInfo {
+    pub id: i32,
+    pub message_id: Option<i32>,
+    pub note: Option<String>,
+    pub remind_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemindersResponse {
+    pub status: String,
+    pub reminders: Vec<ReminderInfo>,
+}
+
+/// Lists the caller's pending reminders in their workspace
+pub async fn list_reminders(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<RemindersResponse> {
+    let query_result = sqlx::query_as::<_, (i32, Option<i32>, Option<String>, String)>(
+        "SELECT id, message_id, content, run_at::text
+         FROM scheduled_jobs
+         WHERE kind = $1 AND workspace_id = $2 AND user_id = $3 AND delivered_at IS NULL
+         ORDER BY run_at",
+    )
+    .bind(JobKind::Reminder.as_str())
+    .bind(auth.workspace_id)
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(RemindersResponse {
+            status: "success".to_string(),
+            reminders: rows
+                .into_iter()
+                .map(|(id, message_id, note, remind_at)| ReminderInfo {
+                    id,
+                    message_id,
+                    note,
+                    remind_at,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(RemindersResponse {
+                status: "error".to_string(),
+                reminders: vec![],
+            })
+        }
+    }
+}
+
+/// Cancels a pending reminder
+pub async fn cancel_reminder(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(job_id): Path<i32>,
+) -> Json<ApiResponse> {
+    if delete_job(&state, &auth, JobKind::Reminder, job_id).await {
+        Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Reminder cancelled".to_string(),
+        })
+    } else {
+        Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No reminder found with this id".to_string(),
+        })
+    }
+}
@@ -0,0 +1,519 @@
+use axum::{
+    Json, async_trait,
+    extract::{FromRequestParts, State},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use rand::{Rng, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Pool, Postgres};
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::bots::{API_TOKEN_PREFIX, api_token_session};
+use crate::roles::{Permission, Role};
+use crate::user_operations::ApiResponse;
+use crate::workspaces::WorkspaceRole;
+
+/// How long an access token stays valid
+const ACCESS_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+/// How long a refresh token can be exchanged for a new token pair
+const REFRESH_TOKEN_TTL_DAYS: i32 = 30;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: i32,
+    sid: Uuid,
+    exp: i64,
+}
+
+/// A request authenticated with a bearer token belonging to an active session
+#[derive(Debug, Clone)]
+pub struct AuthSession {
+    pub user_id: i32,
+    pub session_id: Uuid,
+    pub email: String,
+    pub username: String,
+    pub role: Role,
+    /// Workspace the session was opened in; everything the request sees is limited to it
+    pub workspace_id: i32,
+    pub workspace_role: WorkspaceRole,
+}
+
+impl AuthSession {
+    /// Whether the user's global role or their role in the session's workspace grants `permission`
+    pub fn has(&self, permission: Permission) -> bool {
+        self.role.has(permission) || self.workspace_role.has(permission)
+    }
+}
+
+static JWT_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Reads the key access tokens are signed with from the `JWT_SECRET`
+/// environment variable. Called on startup, so the server refuses to start
+/// without a key rather than sign tokens with one anybody could know.
+pub fn load_jwt_secret() -> Result<(), String> {
+    let secret = std::env::var("JWT_SECRET").unwrap_or_default();
+    if secret.trim().is_empty() {
+        return Err("The JWT_SECRET environment variable must be set".to_string());
+    }
+    let _ = JWT_SECRET.set(secret.into_bytes());
+    Ok(())
+}
+
+fn jwt_secret() -> &'static [u8] {
+    JWT_SECRET
+        .get()
+        .expect("The JWT secret is loaded on startup")
+}
+
+/// Signs an access token for the given session
+pub fn issue_access_token(user_id: i32, session_id: Uuid) -> String {
+    let claims = Claims {
+        sub: user_id,
+        sid: session_id,
+        exp: chrono::Utc::now().timestamp() + ACCESS_TOKEN_TTL_SECONDS,
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(jwt_secret()),
+    )
+    .expect("Failed to sign access token")
+}
+
+/// Generates a random opaque token suitable for refresh tokens and similar secrets
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+/// Hash opaque tokens with SHA256 so the database never holds them in plain text
+pub fn hash_token(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_bytes());
+    let result = hasher.finalize();
+    format!("{:x}", result)
+}
+
+/// Creates a refresh token for a session and returns it in plain text.
+/// All refresh tokens of a session form one rotation family.
+pub async fn issue_refresh_token<'e, E>(
+    executor: E,
+    session_id: Uuid,
+) -> Result<String, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let token = generate_token();
+    sqlx::query(
+        "INSERT INTO refresh_tokens (session_id, token_hash, expires_at)
+         VALUES ($1, $2, CURRENT_TIMESTAMP + make_interval(days => $3))",
+    )
+    .bind(session_id)
+    .bind(hash_token(&token))
+    .bind(REFRESH_TOKEN_TTL_DAYS)
+    .execute(executor)
+    .await?;
+    Ok(token)
+}
+
+/// Reads the `User-Agent` header of a request
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// Records a new session for a user who just proved their credentials
+pub async fn create_session<'e, E>(
+    executor: E,
+    user_id: i32,
+    workspace_id: i32,
+    device_name: &str,
+    ip_address: &str,
+    user_agent: Option<&str>,
+) -> Result<Uuid, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let session_id = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO sessions (id, user_id, workspace_id, device_name, ip_address, user_agent)
+         VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(workspace_id)
+    .bind(device_name)
+    .bind(ip_address)
+    .bind(user_agent)
+    .execute(executor)
+    .await?;
+    Ok(session_id)
+}
+
+/// Validates an access token, or a bot's API token, and checks that its
+/// session has not been revoked and that the user still belongs to the
+/// session's workspace
+pub async fn authenticate_token(pool: &Pool<Postgres>, token: &str) -> Option<AuthSession> {
+    let (session_id, user_id) = if token.starts_with(API_TOKEN_PREFIX) {
+        api_token_session(pool, token).await?
+    } else {
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(jwt_secret()),
+            &Validation::default(),
+        )
+        .ok()?
+        .claims;
+        (claims.sid, claims.sub)
+    };
+    load_session(pool, session_id, user_id).await
+}
+
+/// Loads a session that hasn't been revoked, with the user's current roles,
+/// and marks it as seen
+pub async fn load_session(
+    pool: &Pool<Postgres>,
+    session_id: Uuid,
+    user_id: i32,
+) -> Option<AuthSession> {
+    let result = sqlx::query_as::<_, (String, String, String, i32, String)>(
+        "UPDATE sessions s SET last_seen = CURRENT_TIMESTAMP
+         FROM users u, workspace_members wm
+         WHERE s.id = $1 AND s.user_id = $2 AND s.revoked_at IS NULL AND u.id = s.user_id
+           AND wm.workspace_id = s.workspace_id AND wm.user_id = s.user_id
+         RETURNING u.email, u.name, u.role, s.workspace_id, wm.role",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await;
+
+    match result {
+        Ok(Some((email, username, role, workspace_id, workspace_role))) => Some(AuthSession {
+            user_id,
+            session_id,
+            email,
+            username,
+            role: Role::parse(&role).unwrap_or(Role::Member),
+            workspace_id,
+            workspace_role: WorkspaceRole::parse(&workspace_role).unwrap_or(WorkspaceRole::Member),
+        }),
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Revokes every active session of a user except `keep`, and closes their sockets
+pub async fn revoke_user_sessions(
+    state: &AppState,
+    user_id: i32,
+    keep: Option<Uuid>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+         WHERE user_id = $1 AND revoked_at IS NULL AND ($2::UUID IS NULL OR id <> $2)",
+    )
+    .bind(user_id)
+    .bind(keep)
+    .execute(&state.pool)
+    .await?;
+
+    state
+        .connections
+        .close_user(user_id, keep, "Session revoked");
+    Ok(result.rows_affected())
+}
+
+/// Revokes every active session a user has in one workspace, and closes their
+/// sockets in it with `reason`
+pub async fn revoke_workspace_sessions(
+    state: &AppState,
+    user_id: i32,
+    workspace_id: i32,
+    reason: &str,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+         WHERE user_id = $1 AND workspace_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(workspace_id)
+    .execute(&state.pool)
+    .await?;
+
+    state
+        .connections
+        .close_member(workspace_id, user_id, reason);
+    Ok(result.rows_affected())
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthSession {
+    type Rejection = (StatusCode, Json<ApiResponse>);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = |message: &str| {
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(ApiResponse {
+                    status: "error".to_string(),
+                    message: message.to_string(),
+                }),
+            )
+        };
+
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("Missing bearer token"))?;
+
+        authenticate_token(&state.pool, token)
+            .await
+            .ok_or_else(|| unauthorized("Invalid or expired token"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub device_name: String,
+    pub ip_address: String,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_seen: String,
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionsResponse {
+    pub status: String,
+    pub sessions: Vec<SessionInfo>,
+}
+
+pub async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<SessionsResponse> {
+    let query_result = sqlx::query_as::<_, (Uuid, String, String, Option<String>, String, String)>(
+        "SELECT id, device_name, ip_address, user_agent, created_at::text, last_seen::text
+         FROM sessions
+         WHERE user_id = $1 AND revoked_at IS NULL
+         ORDER BY last_seen DESC",
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(SessionsResponse {
+            status: "success".to_string(),
+            sessions: rows
+                .into_iter()
+                .map(
+                    |(id, device_name, ip_address, user_agent, created_at, last_seen)| {
+                        SessionInfo {
+                            id,
+                            device_name,
+                            ip_address,
+                            user_agent,
+                            created_at,
+                            last_seen,
+                            current: id == auth.session_id,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(SessionsResponse {
+                status: "error".to_string(),
+                sessions: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: Uuid,
+}
+
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(payload): Json<RevokeSessionRequest>,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query(
+        "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+         WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(payload.session_id)
+    .bind(auth.user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No active session found with this id".to_string(),
+        }),
+        Ok(_) => {
+            state
+                .connections
+                .close_session(payload.session_id, "Session revoked");
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Session revoked".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to revoke session: {}", e),
+            })
+        }
+    }
+}
+
+pub async fn revoke_all_sessions(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<ApiResponse> {
+    match revoke_user_sessions(&state, auth.user_id, None).await {
+        Ok(count) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: format!("Revoked {} sessions", count),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to revoke sessions: {}", e),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub status: String,
+    pub message: String,
+    pub token: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+impl TokenResponse {
+    fn error(message: &str) -> Json<TokenResponse> {
+        Json(TokenResponse {
+            status: "error".to_string(),
+            message: message.to_string(),
+            token: None,
+            refresh_token: None,
+        })
+    }
+}
+
+/// Exchanges a refresh token for a new access token and refresh token.
+/// Presenting a refresh token that was already rotated revokes its whole session.
+pub async fn refresh_token(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<RefreshTokenRequest>,
+) -> Json<TokenResponse> {
+    match rotate_refresh_token(&state, &payload.refresh_token).await {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            TokenResponse::error(&format!("Failed to refresh token: {}", e))
+        }
+    }
+}
+
+async fn rotate_refresh_token(
+    state: &AppState,
+    refresh_token: &str,
+) -> Result<Json<TokenResponse>, sqlx::Error> {
+    let mut transaction = state.pool.begin().await?;
+
+    let row = sqlx::query_as::<_, (i32, Uuid, i32, bool, bool, bool)>(
+        "SELECT rt.id, rt.session_id, s.user_id, rt.rotated_at IS NOT NULL,
+                rt.expires_at < CURRENT_TIMESTAMP, s.revoked_at IS NOT NULL
+         FROM refresh_tokens rt
+         JOIN sessions s ON s.id = rt.session_id
+         WHERE rt.token_hash = $1
+         FOR UPDATE OF rt",
+    )
+    .bind(hash_token(refresh_token))
+    .fetch_optional(&mut transaction)
+    .await?;
+
+    let Some((token_id, session_id, user_id, rotated, expired, session_revoked)) = row else {
+        return Ok(TokenResponse::error("Invalid refresh token"));
+    };
+
+    if session_revoked {
+        return Ok(TokenResponse::error("Session has been revoked"));
+    }
+
+    if rotated {
+        // A rotated token can only be presented again if it was stolen, so
+        // the whole family is considered compromised
+        sqlx::query("UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1")
+            .bind(session_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+
+        state
+            .connections
+            .close_session(session_id, "Session revoked after refresh token reuse");
+        println!(
+            "Refresh token reuse detected for user {}, revoked session {}",
+            user_id, session_id
+        );
+        return Ok(TokenResponse::error(
+            "Refresh token reuse detected, session revoked",
+        ));
+    }
+
+    if expired {
+        return Ok(TokenResponse::error("Refresh token expired"));
+    }
+
+    sqlx::query("UPDATE refresh_tokens SET rotated_at = CURRENT_TIMESTAMP WHERE id = $1")
+        .bind(token_id)
+        .execute(&mut transaction)
+        .await?;
+    let new_refresh_token = issue_refresh_token(&mut transaction, session_id).await?;
+    transaction.commit().await?;
+
+    Ok(Json(TokenResponse {
+        status: "success".to_string(),
+        message: "Token refreshed".to_string(),
+        token: Some(issue_access_token(user_id, session_id)),
+        refresh_token: Some(new_refresh_token),
+    }))
+}
@@ -0,0 +1,362 @@
+use axum::extract::{ConnectInfo, Json, State};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{Rng, RngCore, distributions::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::login_protection::{AuthFailure, ensure_not_locked, record_login_attempt};
+use crate::session_operations::{AuthSession, hash_token};
+use crate::user_operations::{ApiResponse, record_login_failure, verify_credentials};
+
+const ISSUER: &str = "ChatPlatform";
+const TOTP_DIGITS: u32 = 6;
+const TOTP_PERIOD_SECONDS: i64 = 30;
+/// Codes from one period before or after the current one are still accepted
+const TOTP_ALLOWED_SKEW: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// RFC 6238 code for the given time step
+fn totp_code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10_u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+/// Returns the time step `code` belongs to, if it is valid for the current time
+fn matching_step(secret: &[u8], code: &str) -> Option<i64> {
+    let current = chrono::Utc::now().timestamp() / TOTP_PERIOD_SECONDS;
+    (current - TOTP_ALLOWED_SKEW..=current + TOTP_ALLOWED_SKEW)
+        .find(|step| totp_code(secret, *step) == code)
+}
+
+/// Percent-encodes a value for use in an `otpauth://` URI
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Recovery codes are compared case-insensitively and without separators
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_code() -> String {
+    let raw: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(|byte| (byte as char).to_ascii_lowercase())
+        .collect();
+    format!("{}-{}", &raw[..5], &raw[5..])
+}
+
+/// Checks a TOTP code against the user's stored secret. Each time step can
+/// only be used once, so an intercepted code can't be replayed.
+async fn verify_totp(pool: &Pool<Postgres>, user_id: i32, code: &str) -> bool {
+    let secret =
+        sqlx::query_as::<_, (Option<String>,)>("SELECT totp_secret FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await;
+
+    let secret = match secret {
+        Ok(Some((Some(secret),))) => secret,
+        Ok(_) => return false,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return false;
+        }
+    };
+
+    let Ok(secret) = BASE32_NOPAD.decode(secret.as_bytes()) else {
+        return false;
+    };
+    let Some(step) = matching_step(&secret, code.trim()) else {
+        return false;
+    };
+
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = $1
+         WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+    )
+    .bind(step)
+    .bind(user_id)
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(result) => result.rows_affected() == 1,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Consumes an unused recovery code of the user
+async fn use_recovery_code(pool: &Pool<Postgres>, user_id: i32, code: &str) -> bool {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used_at = CURRENT_TIMESTAMP
+         WHERE id = (
+             SELECT id FROM recovery_codes
+             WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+             LIMIT 1
+         )",
+    )
+    .bind(user_id)
+    .bind(hash_token(&normalize_recovery_code(code)))
+    .execute(pool)
+    .await;
+
+    match result {
+        Ok(result) => result.rows_affected() == 1,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Accepts either a current TOTP code or one of the user's recovery codes
+pub async fn verify_second_factor(pool: &Pool<Postgres>, user_id: i32, code: &str) -> bool {
+    verify_totp(pool, user_id, code).await || use_recovery_code(pool, user_id, code).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorSetupResponse {
+    pub status: String,
+    pub message: String,
+    pub secret: Option<String>,
+    /// `otpauth://` URI for the authenticator app, meant to be shown as a QR code
+    pub provisioning_uri: Option<String>,
+}
+
+/// Starts enrolment by generating a new secret. Two-factor authentication is
+/// only turned on once a code generated from it is confirmed.
+pub async fn setup_two_factor(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<TwoFactorSetupResponse> {
+    let mut secret_bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret_bytes);
+    let secret = BASE32_NOPAD.encode(&secret_bytes);
+
+    let query_result = sqlx::query(
+        "UPDATE users SET totp_secret = $1, totp_last_step = NULL
+         WHERE id = $2 AND totp_enabled = FALSE",
+    )
+    .bind(&secret)
+    .bind(auth.user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(TwoFactorSetupResponse {
+            status: "error".to_string(),
+            message: "Two-factor authentication is already enabled".to_string(),
+            secret: None,
+            provisioning_uri: None,
+        }),
+        Ok(_) => {
+            let provisioning_uri = format!(
+                "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+                ISSUER,
+                uri_encode(&auth.email),
+                secret,
+                ISSUER,
+                TOTP_DIGITS,
+                TOTP_PERIOD_SECONDS
+            );
+            Json(TwoFactorSetupResponse {
+                status: "success".to_string(),
+                message: "Scan the QR code, then confirm with a code from your app".to_string(),
+                secret: Some(secret),
+                provisioning_uri: Some(provisioning_uri),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(TwoFactorSetupResponse {
+                status: "error".to_string(),
+                message: format!("Failed to set up two-factor authentication: {}", e),
+                secret: None,
+                provisioning_uri: None,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodesResponse {
+    pub status: String,
+    pub message: String,
+    pub recovery_codes: Vec<String>,
+}
+
+impl RecoveryCodesResponse {
+    fn error(message: String) -> Json<RecoveryCodesResponse> {
+        Json(RecoveryCodesResponse {
+            status: "error".to_string(),
+            message,
+            recovery_codes: vec![],
+        })
+    }
+}
+
+/// Confirms enrolment with a code from the authenticator app and returns
+/// the recovery codes, which are shown only this once. Wrong codes count
+/// towards the login lockout of the account, as at login.
+pub async fn enable_two_factor(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Json(payload): Json<TwoFactorCodeRequest>,
+) -> Json<RecoveryCodesResponse> {
+    let ip_address = addr.ip().to_string();
+    if let Err(failure) = ensure_not_locked(&state.pool, &auth.email, &ip_address).await {
+        return RecoveryCodesResponse::error(failure.message());
+    }
+    if !verify_totp(&state.pool, auth.user_id, &payload.code).await {
+        let failure = AuthFailure::InvalidTwoFactorCode;
+        record_login_attempt(&state.pool, &auth.email, &ip_address, false).await;
+        record_login_failure(&state.pool, &auth.email, &ip_address, &failure).await;
+        return RecoveryCodesResponse::error(failure.message());
+    }
+
+    match enable_with_recovery_codes(&state.pool, auth.user_id).await {
+        Ok(recovery_codes) => Json(RecoveryCodesResponse {
+            status: "success".to_string(),
+            message: "Two-factor authentication enabled. Store these recovery codes safely"
+                .to_string(),
+            recovery_codes,
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            RecoveryCodesResponse::error(format!(
+                "Failed to enable two-factor authentication: {}",
+                e
+            ))
+        }
+    }
+}
+
+async fn enable_with_recovery_codes(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+) -> Result<Vec<String>, sqlx::Error> {
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query("UPDATE users SET totp_enabled = TRUE WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?;
+    for code in &recovery_codes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(hash_token(&normalize_recovery_code(code)))
+            .execute(&mut transaction)
+            .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(recovery_codes)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTwoFactorRequest {
+    pub password: String,
+    /// Current code or a recovery code
+    pub code: String,
+}
+
+/// Turns two-factor authentication off. Requires the password and a current
+/// code or a recovery code, checked as at login, lockouts included.
+pub async fn disable_two_factor(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    auth: AuthSession,
+    Json(payload): Json<DisableTwoFactorRequest>,
+) -> Json<ApiResponse> {
+    let ip_address = addr.ip().to_string();
+    if let Err(failure) = verify_credentials(
+        &state,
+        &auth.email,
+        &payload.password,
+        Some(&payload.code),
+        &ip_address,
+    )
+    .await
+    {
+        record_login_failure(&state.pool, &auth.email, &ip_address, &failure).await;
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: failure.message(),
+        });
+    }
+
+    let query_result = sqlx::query(
+        "UPDATE users SET totp_enabled = FALSE, totp_secret = NULL, totp_last_step = NULL
+         WHERE id = $1",
+    )
+    .bind(auth.user_id)
+    .execute(&state.pool)
+    .await;
+
+    let _ = sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(auth.user_id)
+        .execute(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Two-factor authentication disabled".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to disable two-factor authentication: {}", e),
+            })
+        }
+    }
+}
@@ -1,14 +1,36 @@
-use axum::extract::{Json, State};
+use axum::extract::{ConnectInfo, Json, State};
+use axum::http::HeaderMap;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::{Pool, Postgres};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::config::RegistrationMode;
+use crate::email_operations::{is_valid_email, send_verification_email};
+use crate::invites::{RedeemedInvite, consume_invite};
+use crate::login_protection::{AuthFailure, ensure_not_locked, record_login_attempt};
+use crate::session_operations::{
+    AuthSession, create_session, issue_access_token, issue_refresh_token, revoke_user_sessions,
+    user_agent,
+};
+use crate::roles::RoomRole;
+use crate::room_operations::add_room_member;
+use crate::two_factor::verify_second_factor;
+use crate::workspaces::{WorkspaceRole, add_workspace_member, enter_workspace};
 
 #[derive(Debug, Deserialize)]
 pub struct CreateUserRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// Required when registration is invite-only. The user joins the invite's
+    /// workspace, and its room for room invites; without an invite they join
+    /// the default workspace.
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,12 +56,19 @@ async fn check_user_exists(pool: &Pool<Postgres>, email: &str) -> bool {
 }
 
 pub async fn create_user(
-    State(state): State<Arc<(Pool<Postgres>, tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>)>>,
+    State(state): State<Arc<AppState>>,
     Json(payload): Json<CreateUserRequest>,
 ) -> Json<ApiResponse> {
-    let pool = &state.0;
+    let pool = &state.pool;
     let password_hash = hash_password(&payload.password);
 
+    if !is_valid_email(&payload.email) {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Invalid email address".to_string(),
+        });
+    }
+
     if check_user_exists(pool, &payload.email).await {
         return Json(ApiResponse {
             status: "error".to_string(),
@@ -47,47 +76,108 @@ pub async fn create_user(
         });
     }
 
-    let query_result =
-        sqlx::query("INSERT INTO users (name, email, password_hash) VALUES ($1, $2, $3)")
-            .bind(payload.username)
-            .bind(payload.email)
-            .bind(password_hash)
-            .execute(pool)
-            .await;
-
-    match query_result {
-        Ok(_) => Json(ApiResponse {
-            status: "success".to_string(),
-            message: "User created successfully".to_string(),
-        }),
-        Err(e) => {
-            eprintln!("Database error: {:?}", e);
+    match insert_user(&state, &payload, password_hash).await {
+        Ok((user_id, invite)) => {
+            if let Some(room_id) = invite.and_then(|invite| invite.room_id)
+                && let Err(e) = add_room_member(pool, room_id, user_id, RoomRole::Member).await
+            {
+                eprintln!("Database error: {:?}", e);
+            }
+            send_verification_email(&state, user_id, &payload.email).await;
             Json(ApiResponse {
-                status: "error".to_string(),
-                message: format!("Failed to create user: {}", e),
+                status: "success".to_string(),
+                message: "User created successfully. Check your inbox to verify your email address"
+                    .to_string(),
             })
         }
+        Err(message) => Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        }),
     }
 }
 
+/// Inserts a new user, adds them to their workspace and uses up their invite
+/// in the same transaction, so the invite is only spent if the account is created
+async fn insert_user(
+    state: &AppState,
+    payload: &CreateUserRequest,
+    password_hash: String,
+) -> Result<(i32, Option<RedeemedInvite>), String> {
+    let database_error = |e: sqlx::Error| {
+        eprintln!("Database error: {:?}", e);
+        format!("Failed to create user: {}", e)
+    };
+
+    let mut transaction = state.pool.begin().await.map_err(database_error)?;
+
+    let invite = match payload.invite_code.as_deref() {
+        Some(code) => match consume_invite(&mut transaction, code)
+            .await
+            .map_err(database_error)?
+        {
+            Some(invite) => Some(invite),
+            None => return Err("This invite is invalid or has expired".to_string()),
+        },
+        None if state.config.registration == RegistrationMode::InviteOnly => {
+            return Err("Registration requires an invite code".to_string());
+        }
+        None => None,
+    };
+
+    let (user_id,) = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO users (name, email, password_hash) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(&payload.username)
+    .bind(&payload.email)
+    .bind(password_hash)
+    .fetch_one(&mut transaction)
+    .await
+    .map_err(database_error)?;
+
+    let workspace_id = invite
+        .as_ref()
+        .map_or(state.default_workspace_id, |invite| invite.workspace_id);
+    add_workspace_member(&mut transaction, workspace_id, user_id, WorkspaceRole::Member)
+        .await
+        .map_err(database_error)?;
+
+    transaction.commit().await.map_err(database_error)?;
+    Ok((user_id, invite))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ChangePasswordRequest {
     pub email: String,
     pub old_password: String,
     pub new_password: String,
+    pub totp_code: Option<String>,
 }
 
 pub async fn change_password(
-    State(state): State<Arc<(Pool<Postgres>, tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>)>>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    current_session: Option<AuthSession>,
     Json(payload): Json<ChangePasswordRequest>,
 ) -> Json<ApiResponse> {
-    let pool = &state.0;
-    if !authenticate_user(pool, &payload.email, &payload.old_password).await {
-        return Json(ApiResponse {
-            status: "error".to_string(),
-            message: "Invalid email or password".to_string(),
-        });
-    }
+    let pool = &state.pool;
+    let user_id = match verify_credentials(
+        &state,
+        &payload.email,
+        &payload.old_password,
+        payload.totp_code.as_deref(),
+        &addr.ip().to_string(),
+    )
+    .await
+    {
+        Ok(user) => user.user_id,
+        Err(failure) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: failure.message(),
+            });
+        }
+    };
 
     let password_hash = hash_password(&payload.new_password);
 
@@ -105,6 +195,23 @@ pub async fn change_password(
                     message: "No user found with this email".to_string(),
                 })
             } else {
+                // Sign out every other device, keeping the session that made the change
+                let keep = current_session
+                    .filter(|session| session.user_id == user_id)
+                    .map(|session| session.session_id);
+                if let Err(e) = revoke_user_sessions(&state, user_id, keep).await {
+                    eprintln!("Database error: {:?}", e);
+                }
+                record_event(
+                    pool,
+                    AuditEvent::PasswordChanged,
+                    Some(user_id),
+                    Some(user_id),
+                    Some(&addr.ip().to_string()),
+                    serde_json::json!({}),
+                )
+                .await;
+
                 Json(ApiResponse {
                     status: "success".to_string(),
                     message: "Password changed successfully".to_string(),
@@ -121,41 +228,112 @@ pub async fn change_password(
     }
 }
 
-async fn authenticate_user(pool: &Pool<Postgres>, email: &str, password: &str) -> bool {
+/// A user whose email and password were just checked
+#[derive(Debug, Clone)]
+pub struct VerifiedUser {
+    pub user_id: i32,
+    pub username: String,
+    pub email: String,
+}
+
+/// Checks an email and password pair, enforcing login lockouts, the second
+/// factor and email verification. Shared by the HTTP routes and the WebSocket
+/// `auth` message. Workspace membership and bans are checked separately by
+/// `enter_workspace` when a session is opened.
+pub async fn verify_credentials(
+    state: &AppState,
+    email: &str,
+    password: &str,
+    totp_code: Option<&str>,
+    ip_address: &str,
+) -> Result<VerifiedUser, AuthFailure> {
+    let pool = &state.pool;
+    ensure_not_locked(pool, email, ip_address).await?;
+
     let password_hash = hash_password(password);
-    if !check_user_exists(pool, email).await {
-        return false;
-    }
-    let result = sqlx::query("SELECT 1 FROM users WHERE email = $1 AND password_hash = $2")
+    let user = if !check_user_exists(pool, email).await {
+        None
+    } else {
+        let result = sqlx::query_as::<_, (i32, String, String, bool, bool)>(
+            "SELECT id, name, email, email_verified, totp_enabled FROM users
+             WHERE email = $1 AND password_hash = $2 AND NOT is_bot",
+        )
         .bind(email)
         .bind(password_hash)
         .fetch_optional(pool)
         .await;
 
-    match result {
-        Ok(Some(_)) => true,
-        Ok(None) => false,
-        Err(e) => {
-            eprintln!("Database error: {:?}", e);
-            false
+        match result {
+            Ok(row) => row,
+            Err(e) => {
+                eprintln!("Database error: {:?}", e);
+                None
+            }
+        }
+    };
+
+    let Some((user_id, username, email, email_verified, totp_enabled)) = user else {
+        record_login_attempt(pool, email, ip_address, false).await;
+        return Err(AuthFailure::InvalidCredentials);
+    };
+
+    if totp_enabled {
+        // A missing code is not a failed attempt, the client just has to ask for it
+        let Some(code) = totp_code else {
+            return Err(AuthFailure::TwoFactorRequired);
+        };
+        if !verify_second_factor(pool, user_id, code).await {
+            record_login_attempt(pool, &email, ip_address, false).await;
+            return Err(AuthFailure::InvalidTwoFactorCode);
         }
     }
+
+    record_login_attempt(pool, &email, ip_address, true).await;
+
+    if state.config.require_email_verification && !email_verified {
+        return Err(AuthFailure::EmailNotVerified);
+    }
+
+    Ok(VerifiedUser {
+        user_id,
+        username,
+        email,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserRequest {
+    pub email: String,
+    pub password: String,
+    pub totp_code: Option<String>,
 }
 
 pub async fn delete_user(
-    State(state): State<Arc<(Pool<Postgres>, tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>)>>,
-    Json(payload): Json<CreateUserRequest>,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<DeleteUserRequest>,
 ) -> Json<ApiResponse> {
-    let pool = &state.0;
-    if !authenticate_user(pool, &payload.email, &payload.password).await {
-        return Json(ApiResponse {
-            status: "error".to_string(),
-            message: "Invalid email or password".to_string(),
-        });
-    }
+    let pool = &state.pool;
+    let user_id = match verify_credentials(
+        &state,
+        &payload.email,
+        &payload.password,
+        payload.totp_code.as_deref(),
+        &addr.ip().to_string(),
+    )
+    .await
+    {
+        Ok(user) => user.user_id,
+        Err(failure) => {
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: failure.message(),
+            });
+        }
+    };
 
     let query_result = sqlx::query("DELETE FROM users WHERE email = $1")
-        .bind(payload.email)
+        .bind(&payload.email)
         .execute(pool)
         .await;
 
@@ -167,6 +345,16 @@ pub async fn delete_user(
                     message: "No user found with this email".to_string(),
                 })
             } else {
+                state.connections.close_user(user_id, None, "Account deleted");
+                record_event(
+                    pool,
+                    AuditEvent::AccountDeleted,
+                    Some(user_id),
+                    Some(user_id),
+                    Some(&addr.ip().to_string()),
+                    serde_json::json!({ "email": payload.email }),
+                )
+                .await;
                 Json(ApiResponse {
                     status: "success".to_string(),
                     message: "User deleted successfully".to_string(),
@@ -183,25 +371,143 @@ pub async fn delete_user(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+    pub totp_code: Option<String>,
+    pub device_name: Option<String>,
+    /// Slug of the workspace to sign in to; the first workspace the user joined when omitted
+    pub workspace: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    pub status: String,
+    pub message: String,
+    pub token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub session_id: Option<Uuid>,
+    /// Set when the password was right but a two-factor code must be supplied
+    pub two_factor_required: bool,
+}
+
 pub async fn login_user(
-    State(state): State<Arc<(Pool<Postgres>, tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>)>>,
-    Json(payload): Json<CreateUserRequest>,
-) -> Json<ApiResponse> {
-    let pool = &state.0;
-    if authenticate_user(pool, &payload.email, &payload.password).await {
-        Json(ApiResponse {
-            status: "success".to_string(),
-            message: "Login successful".to_string(),
-        })
-    } else {
-        Json(ApiResponse {
-            status: "error".to_string(),
-            message: "Invalid email or password".to_string(),
-        })
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginRequest>,
+) -> Json<LoginResponse> {
+    let pool = &state.pool;
+    let ip_address = addr.ip().to_string();
+    let verified = match verify_credentials(
+        &state,
+        &payload.email,
+        &payload.password,
+        payload.totp_code.as_deref(),
+        &ip_address,
+    )
+    .await
+    {
+        Ok(user) => enter_workspace(pool, user.user_id, payload.workspace.as_deref())
+            .await
+            .map(|workspace_id| (user.user_id, workspace_id)),
+        Err(failure) => Err(failure),
+    };
+    let (user_id, workspace_id) = match verified {
+        Ok(verified) => verified,
+        Err(failure) => {
+            record_login_failure(pool, &payload.email, &ip_address, &failure).await;
+            return Json(LoginResponse {
+                status: "error".to_string(),
+                message: failure.message(),
+                token: None,
+                refresh_token: None,
+                session_id: None,
+                two_factor_required: matches!(failure, AuthFailure::TwoFactorRequired),
+            });
+        }
+    };
+
+    let device_name = payload.device_name.as_deref().unwrap_or("Unknown device");
+    let user_agent = user_agent(&headers);
+    let session = match create_session(
+        pool,
+        user_id,
+        workspace_id,
+        device_name,
+        &ip_address,
+        user_agent.as_deref(),
+    )
+    .await
+    {
+        Ok(session_id) => issue_refresh_token(pool, session_id)
+            .await
+            .map(|refresh_token| (session_id, refresh_token)),
+        Err(e) => Err(e),
+    };
+
+    match session {
+        Ok((session_id, refresh_token)) => {
+            record_event(
+                pool,
+                AuditEvent::Login,
+                Some(user_id),
+                Some(user_id),
+                Some(&ip_address),
+                serde_json::json!({
+                    "session_id": session_id,
+                    "device_name": device_name,
+                    "workspace_id": workspace_id,
+                }),
+            )
+            .await;
+            Json(LoginResponse {
+                status: "success".to_string(),
+                message: "Login successful".to_string(),
+                token: Some(issue_access_token(user_id, session_id)),
+                refresh_token: Some(refresh_token),
+                session_id: Some(session_id),
+                two_factor_required: false,
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(LoginResponse {
+                status: "error".to_string(),
+                message: format!("Failed to create session: {}", e),
+                token: None,
+                refresh_token: None,
+                session_id: None,
+                two_factor_required: false,
+            })
+        }
+    }
+}
+
+/// Writes a failed login to the audit log. Being asked for a second factor is
+/// part of a normal login and isn't recorded.
+pub async fn record_login_failure(
+    pool: &Pool<Postgres>,
+    email: &str,
+    ip_address: &str,
+    failure: &AuthFailure,
+) {
+    if matches!(failure, AuthFailure::TwoFactorRequired) {
+        return;
     }
+    record_event(
+        pool,
+        AuditEvent::LoginFailed,
+        None,
+        None,
+        Some(ip_address),
+        serde_json::json!({ "email": email, "reason": failure.message() }),
+    )
+    .await;
 }
 
-fn hash_password(password: &str) -> String {
+pub fn hash_password(password: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(password.as_bytes());
     let result = hasher.finalize();
@@ -0,0 +1,616 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use data_encoding::HEXLOWER;
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use rand::{Rng, distributions::Alphanumeric};
+use reqwest::Url;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Executor, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::http_client::{build_http_client, check_url};
+use crate::roles::{Authorized, permission};
+use crate::room_operations::room_in_workspace;
+use crate::user_operations::ApiResponse;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Deliveries sent at once
+const BATCH_SIZE: i64 = 20;
+/// A claimed delivery is tried again after this long if its attempt never
+/// finished, e.g. because the server stopped
+const CLAIM_SECONDS: i64 = 300;
+/// Delay before the first retry, doubled for every further one
+const FIRST_RETRY_SECONDS: i64 = 15;
+const MAX_RETRY_SECONDS: i64 = 60 * 60;
+/// Finished deliveries are kept this long, for `/webhooks/:id/deliveries`
+const DELIVERY_RETENTION_DAYS: i32 = 7;
+const MAX_WEBHOOKS_PER_WORKSPACE: i64 = 20;
+const MAX_URL_LENGTH: usize = 2048;
+const SECRET_LENGTH: usize = 40;
+const USER_AGENT: &str = "ChatPlatform-Webhooks/1.0";
+
+/// Chat events webhooks can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    MessageCreated,
+    MessageDeleted,
+    MemberJoined,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::MessageCreated,
+        WebhookEvent::MessageDeleted,
+        WebhookEvent::MemberJoined,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::MessageCreated => "message.created",
+            WebhookEvent::MessageDeleted => "message.deleted",
+            WebhookEvent::MemberJoined => "member.joined",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<WebhookEvent> {
+        WebhookEvent::ALL
+            .into_iter()
+            .find(|event| event.as_str() == value)
+    }
+}
+
+/// Queues an event for every webhook subscribed to it: those of the
+/// workspace and, for events in a room, those of the room. Meant to run in
+/// the transaction that makes the change, so an event is sent exactly when
+/// the change is committed.
+pub async fn queue_event<'e, E>(
+    executor: E,
+    workspace_id: i32,
+    room_id: Option<i32>,
+    event: WebhookEvent,
+    data: serde_json::Value,
+) -> Result<(), sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let payload = serde_json::json!({
+        "event": event.as_str(),
+        "workspace_id": workspace_id,
+        "room_id": room_id,
+        "created_at": chrono::Utc::now().to_rfc3339(),
+        "data": data,
+    });
+    sqlx::query(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+         SELECT id, $3, $4 FROM webhooks
+         WHERE workspace_id = $1 AND (room_id IS NULL OR room_id = $2) AND $3 = ANY(events)",
+    )
+    .bind(workspace_id)
+    .bind(room_id)
+    .bind(event.as_str())
+    .bind(payload.to_string())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Sends queued deliveries until the server stops, retrying failed ones with
+/// exponential backoff, and clears out old deliveries
+pub async fn run_webhooks(state: Arc<AppState>) {
+    let config = &state.config.webhooks;
+    // Redirects aren't followed: the webhook's address is the one registered
+    let client = build_http_client(
+        USER_AGENT,
+        Duration::from_secs(config.timeout_seconds),
+        0,
+        config.allow_private_addresses,
+    );
+    let mut deliveries = tokio::time::interval(POLL_INTERVAL);
+    let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = deliveries.tick() => send_due_deliveries(&state, &client).await,
+            _ = cleanup.tick() => delete_old_deliveries(&state).await,
+        }
+    }
+}
+
+/// A delivery claimed for an attempt
+struct Delivery {
+    id: i64,
+    event: String,
+    payload: String,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+/// Claims the deliveries that are due and sends them. Claiming counts the
+/// attempt and pushes the next one back, so a delivery is never sent twice
+/// at the same time.
+async fn send_due_deliveries(state: &AppState, client: &reqwest::Client) {
+    let query_result = sqlx::query_as::<_, (i64, String, String, i32, String, String)>(
+        "UPDATE webhook_deliveries d
+         SET attempts = d.attempts + 1,
+             next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+         FROM webhooks w
+         WHERE w.id = d.webhook_id AND d.id IN (
+             SELECT id FROM webhook_deliveries
+             WHERE delivered_at IS NULL AND failed_at IS NULL
+               AND next_attempt_at <= CURRENT_TIMESTAMP
+             ORDER BY next_attempt_at
+             LIMIT $1
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING d.id, d.event, d.payload, d.attempts, w.url, w.secret",
+    )
+    .bind(BATCH_SIZE)
+    .bind(CLAIM_SECONDS as f64)
+    .fetch_all(&state.pool)
+    .await;
+
+    let deliveries = match query_result {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return;
+        }
+    };
+    join_all(
+        deliveries
+            .into_iter()
+            .map(|(id, event, payload, attempts, url, secret)| {
+                send_delivery(
+                    state,
+                    client,
+                    Delivery {
+                        id,
+                        event,
+                        payload,
+                        attempts,
+                        url,
+                        secret,
+                    },
+                )
+            }),
+    )
+    .await;
+}
+
+/// Hex encoded HMAC-SHA256 of the payload, keyed with the webhook's secret
+pub fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    HEXLOWER.encode(&mac.finalize().into_bytes())
+}
+
+/// Makes one attempt at a delivery and records its outcome. Any 2xx answer
+/// counts as delivered.
+async fn send_delivery(state: &AppState, client: &reqwest::Client, delivery: Delivery) {
+    let config = &state.config.webhooks;
+    let result = async {
+        let url = Url::parse(&delivery.url).map_err(|e| e.to_string())?;
+        check_url(&url, config.allow_private_addresses)?;
+        let response = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Event", &delivery.event)
+            .header("X-Webhook-Delivery", delivery.id.to_string())
+            .header(
+                "X-Webhook-Signature",
+                format!("sha256={}", sign(&delivery.secret, &delivery.payload)),
+            )
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("Status {}", response.status()))
+        }
+    }
+    .await;
+
+    let query_result = match result {
+        Ok(()) => {
+            sqlx::query(
+                "UPDATE webhook_deliveries SET delivered_at = CURRENT_TIMESTAMP, last_error = NULL
+                 WHERE id = $1",
+            )
+            .bind(delivery.id)
+            .execute(&state.pool)
+            .await
+        }
+        Err(error) if delivery.attempts >= config.max_attempts => {
+            sqlx::query(
+                "UPDATE webhook_deliveries SET failed_at = CURRENT_TIMESTAMP, last_error = $2
+                 WHERE id = $1",
+            )
+            .bind(delivery.id)
+            .bind(error)
+            .execute(&state.pool)
+            .await
+        }
+        Err(error) => {
+            let delay = (FIRST_RETRY_SECONDS << (delivery.attempts - 1).clamp(0, 16))
+                .min(MAX_RETRY_SECONDS);
+            sqlx::query(
+                "UPDATE webhook_deliveries
+                 SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2), last_error = $3
+                 WHERE id = $1",
+            )
+            .bind(delivery.id)
+            .bind(delay as f64)
+            .bind(error)
+            .execute(&state.pool)
+            .await
+        }
+    };
+    if let Err(e) = query_result {
+        eprintln!("Database error: {:?}", e);
+    }
+}
+
+async fn delete_old_deliveries(state: &AppState) {
+    let query_result = sqlx::query(
+        "DELETE FROM webhook_deliveries
+         WHERE (delivered_at IS NOT NULL OR failed_at IS NOT NULL)
+           AND created_at < CURRENT_TIMESTAMP - make_interval(days => $1)",
+    )
+    .bind(DELIVERY_RETENTION_DAYS)
+    .execute(&state.pool)
+    .await;
+    if let Err(e) = query_result {
+        eprintln!("Database error: {:?}", e);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Webhook {
+    pub id: i32,
+    /// Only events of this room are sent when set, events of every room of
+    /// the workspace otherwise
+    pub room_id: Option<i32>,
+    pub url: String,
+    pub events: Vec<String>,
+    pub created_by: Option<i32>,
+    pub created_at: String,
+    /// Only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhooksResponse {
+    pub status: String,
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookResponse {
+    pub status: String,
+    pub message: String,
+    pub webhook: Option<Webhook>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub room_id: Option<i32>,
+    /// Every event when omitted
+    pub events: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub event: String,
+    pub attempts: i32,
+    pub created_at: String,
+    pub delivered_at: Option<String>,
+    pub failed_at: Option<String>,
+    /// When the next attempt is due, for deliveries still being tried
+    pub next_attempt_at: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDeliveriesResponse {
+    pub status: String,
+    pub deliveries: Vec<WebhookDelivery>,
+}
+
+/// Lists the webhooks of the caller's workspace, without their secrets
+pub async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageWebhooks>,
+) -> Json<WebhooksResponse> {
+    let query_result =
+        sqlx::query_as::<_, (i32, Option<i32>, String, Vec<String>, Option<i32>, String)>(
+            "SELECT id, room_id, url, events, created_by, created_at::text
+             FROM webhooks WHERE workspace_id = $1
+             ORDER BY id",
+        )
+        .bind(admin.auth.workspace_id)
+        .fetch_all(&state.pool)
+        .await;
+
+    match query_result {
+        Ok(rows) => Json(WebhooksResponse {
+            status: "success".to_string(),
+            webhooks: rows
+                .into_iter()
+                .map(
+                    |(id, room_id, url, events, created_by, created_at)| Webhook {
+                        id,
+                        room_id,
+                        url,
+                        events,
+                        created_by,
+                        created_at,
+                        secret: None,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(WebhooksResponse {
+                status: "error".to_string(),
+                webhooks: vec![],
+            })
+        }
+    }
+}
+
+pub fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Registers a webhook for the caller's workspace or one of its rooms. The
+/// answer is the only time its signing secret is shown.
+pub async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageWebhooks>,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> Json<WebhookResponse> {
+    let error = |message: String| {
+        Json(WebhookResponse {
+            status: "error".to_string(),
+            message,
+            webhook: None,
+        })
+    };
+    let pool = &state.pool;
+    let workspace_id = admin.auth.workspace_id;
+
+    let url = payload.url.trim();
+    let checked = Url::parse(url)
+        .map_err(|e| format!("Invalid URL: {}", e))
+        .and_then(|url| check_url(&url, state.config.webhooks.allow_private_addresses));
+    if let Err(message) = checked {
+        return error(message);
+    }
+    if url.len() > MAX_URL_LENGTH {
+        return error(format!(
+            "The URL can be at most {} characters",
+            MAX_URL_LENGTH
+        ));
+    }
+    if let Some(room_id) = payload.room_id
+        && !room_in_workspace(pool, room_id, workspace_id).await
+    {
+        return error("No room found with this id".to_string());
+    }
+    let events = match payload.events {
+        Some(events) => events,
+        None => WebhookEvent::ALL
+            .iter()
+            .map(|event| event.as_str().to_string())
+            .collect(),
+    };
+    if events.is_empty() {
+        return error("A webhook needs at least one event".to_string());
+    }
+    if let Some(unknown) = events
+        .iter()
+        .find(|event| WebhookEvent::parse(event).is_none())
+    {
+        return error(format!("Unknown event: {}", unknown));
+    }
+
+    let secret = generate_secret();
+    // The limit is checked in the same statement so concurrent requests can't exceed it
+    let query_result = sqlx::query_as::<_, (i32, String)>(
+        "INSERT INTO webhooks (workspace_id, room_id, url, secret, events, created_by)
+         SELECT $1, $2, $3, $4, $5, $6
+         WHERE (SELECT COUNT(*) FROM webhooks WHERE workspace_id = $1) < $7
+         RETURNING id, created_at::text",
+    )
+    .bind(workspace_id)
+    .bind(payload.room_id)
+    .bind(url)
+    .bind(&secret)
+    .bind(&events)
+    .bind(admin.auth.user_id)
+    .bind(MAX_WEBHOOKS_PER_WORKSPACE)
+    .fetch_optional(pool)
+    .await;
+
+    match query_result {
+        Ok(Some((id, created_at))) => {
+            record_event(
+                pool,
+                AuditEvent::WebhookCreated,
+                Some(admin.auth.user_id),
+                None,
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": workspace_id,
+                    "webhook_id": id,
+                    "room_id": payload.room_id,
+                    "url": url,
+                    "events": events,
+                }),
+            )
+            .await;
+            Json(WebhookResponse {
+                status: "success".to_string(),
+                message: "Webhook created".to_string(),
+                webhook: Some(Webhook {
+                    id,
+                    room_id: payload.room_id,
+                    url: url.to_string(),
+                    events,
+                    created_by: Some(admin.auth.user_id),
+                    created_at,
+                    secret: Some(secret),
+                }),
+            })
+        }
+        Ok(None) => error(format!(
+            "A workspace can't have more than {} webhooks",
+            MAX_WEBHOOKS_PER_WORKSPACE
+        )),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            error(format!("Failed to create webhook: {}", e))
+        }
+    }
+}
+
+/// Removes a webhook of the caller's workspace, along with its pending deliveries
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageWebhooks>,
+    Path(webhook_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let query_result = sqlx::query_as::<_, (String,)>(
+        "DELETE FROM webhooks WHERE id = $1 AND workspace_id = $2 RETURNING url",
+    )
+    .bind(webhook_id)
+    .bind(admin.auth.workspace_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(Some((url,))) => {
+            record_event(
+                &state.pool,
+                AuditEvent::WebhookDeleted,
+                Some(admin.auth.user_id),
+                None,
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": admin.auth.workspace_id,
+                    "webhook_id": webhook_id,
+                    "url": url,
+                }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Webhook deleted".to_string(),
+            })
+        }
+        Ok(None) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No webhook found with this id".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to delete webhook: {}", e),
+            })
+        }
+    }
+}
+
+/// Lists the latest deliveries of a webhook of the caller's workspace, newest first
+pub async fn list_deliveries(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageWebhooks>,
+    Path(webhook_id): Path<i32>,
+) -> Json<WebhookDeliveriesResponse> {
+    let query_result = sqlx::query_as::<
+        _,
+        (
+            i64,
+            String,
+            i32,
+            String,
+            Option<String>,
+            Option<String>,
+            String,
+            Option<String>,
+        ),
+    >(
+        "SELECT d.id, d.event, d.attempts, d.created_at::text, d.delivered_at::text,
+                d.failed_at::text, d.next_attempt_at::text, d.last_error
+         FROM webhook_deliveries d
+         JOIN webhooks w ON w.id = d.webhook_id
+         WHERE d.webhook_id = $1 AND w.workspace_id = $2
+         ORDER BY d.id DESC
+         LIMIT 100",
+    )
+    .bind(webhook_id)
+    .bind(admin.auth.workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(WebhookDeliveriesResponse {
+            status: "success".to_string(),
+            deliveries: rows
+                .into_iter()
+                .map(
+                    |(
+                        id,
+                        event,
+                        attempts,
+                        created_at,
+                        delivered_at,
+                        failed_at,
+                        next_attempt_at,
+                        last_error,
+                    )| {
+                        let pending = delivered_at.is_none() && failed_at.is_none();
+                        WebhookDelivery {
+                            id,
+                            event,
+                            attempts,
+                            created_at,
+                            delivered_at,
+                            failed_at,
+                            next_attempt_at: pending.then_some(next_attempt_at),
+                            last_error,
+                        }
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(WebhookDeliveriesResponse {
+                status: "error".to_string(),
+                deliveries: vec![],
+            })
+        }
+    }
+}
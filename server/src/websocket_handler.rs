@@ -1,23 +1,64 @@
 use axum::{
     extract::{
-        State,
+        ConnectInfo, State,
         ws::{Message, WebSocket, WebSocketUpgrade},
     },
+    http::HeaderMap,
     response::Response,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres};
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast;
-use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::blocks::blocked_user_ids;
+use crate::commands::{ChatInput, CommandContext, parse_chat_input, run_command};
+use crate::connections::ConnectionControl;
+use crate::link_previews::LinkPreview;
+use crate::login_protection::AuthFailure;
+use crate::message_operations::post_message;
+use crate::moderation::{ModerationAction, active_sanction};
+use crate::pins::PinnedMessage;
+use crate::polls::{PollDraft, PollPayload, cast_vote};
+use crate::profiles::Profile;
+use crate::roles::RoomRole;
+use crate::room_operations::{add_room_member, check_can_join, member_room_ids};
+use crate::session_operations::{authenticate_token, create_session, user_agent};
+use crate::user_operations::{record_login_failure, verify_credentials};
+use crate::workspaces::{default_room_id, enter_workspace};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
+    pub id: i32,
+    pub room_id: i32,
+    pub user_id: i32,
     pub user_email: String,
     pub username: String,
+    /// Whether the author is a bot account
+    #[serde(default)]
+    pub bot: bool,
     pub content: String,
+    /// `content` rendered from markdown to safe HTML
+    #[serde(default)]
+    pub html: String,
     pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<MessagePayload>,
+    /// Filled in shortly after the message is posted, see `ServerEvent::LinkPreviews`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link_previews: Vec<LinkPreview>,
+}
+
+/// Structured content a message carries on top of its text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessagePayload {
+    Poll(PollPayload),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,16 +67,43 @@ pub enum WsMessage {
     #[serde(rename = "auth")]
     Auth { 
         email: String, 
-        password: String 
+        password: String,
+        totp_code: Option<String>,
+        /// Slug of the workspace to sign in to
+        workspace: Option<String>,
+    },
+    #[serde(rename = "auth_token")]
+    AuthToken {
+        token: String
     },
     #[serde(rename = "chat")]
     Chat { 
-        content: String 
+        content: String,
+        room_id: Option<i32>,
+    },
+    #[serde(rename = "poll")]
+    Poll {
+        room_id: Option<i32>,
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        multiple_choice: bool,
+        closes_in_seconds: Option<i64>,
+    },
+    /// Replaces the user's votes in a poll; an empty list takes the vote back
+    #[serde(rename = "vote")]
+    Vote {
+        message_id: i32,
+        options: Vec<i32>,
     },
     #[serde(rename = "join")]
-    Join,
+    Join {
+        room_id: Option<i32>,
+    },
     #[serde(rename = "leave")]
-    Leave,
+    Leave {
+        room_id: Option<i32>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +111,126 @@ pub struct WsResponse {
     pub status: String,
     pub message: Option<ChatMessage>,
     pub info: Option<String>,
+    /// Payload of events other than chat messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+/// Everything that is broadcast to connected clients. Room events are only
+/// delivered to connections subscribed to the room, profile changes to every
+/// connection in a workspace the user belongs to.
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    Message(ChatMessage),
+    MessageDeleted { room_id: i32, message_id: i32 },
+    MessagePinned(PinnedMessage),
+    MessageUnpinned { room_id: i32, message_id: i32 },
+    PollUpdated { room_id: i32, message_id: i32, poll: PollPayload },
+    LinkPreviews { room_id: i32, message_id: i32, user_id: i32, previews: Vec<LinkPreview> },
+    ProfileUpdated { workspace_ids: Vec<i32>, profile: Profile },
+}
+
+impl ServerEvent {
+    /// Whether a connection in `workspace_id`, subscribed to `rooms`, should
+    /// receive the event. Messages of users in `blocked` are held back.
+    fn is_visible_to(
+        &self,
+        workspace_id: i32,
+        rooms: &HashSet<i32>,
+        blocked: &HashSet<i32>,
+    ) -> bool {
+        match self {
+            ServerEvent::Message(message) => {
+                rooms.contains(&message.room_id) && !blocked.contains(&message.user_id)
+            }
+            ServerEvent::MessageDeleted { room_id, .. }
+            | ServerEvent::MessageUnpinned { room_id, .. } => rooms.contains(room_id),
+            ServerEvent::MessagePinned(pin) => rooms.contains(&pin.room_id),
+            ServerEvent::PollUpdated { room_id, .. } => rooms.contains(room_id),
+            ServerEvent::LinkPreviews {
+                room_id, user_id, ..
+            } => rooms.contains(room_id) && !blocked.contains(user_id),
+            ServerEvent::ProfileUpdated { workspace_ids, .. } => {
+                workspace_ids.contains(&workspace_id)
+            }
+        }
+    }
+
+    fn into_response(self) -> WsResponse {
+        match self {
+            ServerEvent::Message(message) => WsResponse {
+                status: "message".to_string(),
+                message: Some(message),
+                info: None,
+                data: None,
+            },
+            ServerEvent::MessageDeleted {
+                room_id,
+                message_id,
+            } => WsResponse {
+                status: "message_deleted".to_string(),
+                message: None,
+                info: None,
+                data: Some(serde_json::json!({
+                    "room_id": room_id,
+                    "message_id": message_id,
+                })),
+            },
+            ServerEvent::MessagePinned(pin) => WsResponse {
+                status: "message_pinned".to_string(),
+                message: None,
+                info: None,
+                data: serde_json::to_value(pin).ok(),
+            },
+            ServerEvent::MessageUnpinned {
+                room_id,
+                message_id,
+            } => WsResponse {
+                status: "message_unpinned".to_string(),
+                message: None,
+                info: None,
+                data: Some(serde_json::json!({
+                    "room_id": room_id,
+                    "message_id": message_id,
+                })),
+            },
+            ServerEvent::PollUpdated {
+                room_id,
+                message_id,
+                poll,
+            } => WsResponse {
+                status: "poll_updated".to_string(),
+                message: None,
+                info: None,
+                data: Some(serde_json::json!({
+                    "room_id": room_id,
+                    "message_id": message_id,
+                    "poll": poll,
+                })),
+            },
+            ServerEvent::LinkPreviews {
+                room_id,
+                message_id,
+                previews,
+                ..
+            } => WsResponse {
+                status: "link_previews".to_string(),
+                message: None,
+                info: None,
+                data: Some(serde_json::json!({
+                    "room_id": room_id,
+                    "message_id": message_id,
+                    "previews": previews,
+                })),
+            },
+            ServerEvent::ProfileUpdated { profile, .. } => WsResponse {
+                status: "profile_updated".to_string(),
+                message: None,
+                info: None,
+                data: serde_json::to_value(profile).ok(),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -50,132 +238,304 @@ struct AuthenticatedUser {
     email: String,
     username: String,
     user_id: i32,
+    session_id: Uuid,
+    workspace_id: i32,
+    /// The session was opened for this connection by a password login, and
+    /// ends when the connection closes
+    owns_session: bool,
 }
 
-pub type Tx = broadcast::Sender<ChatMessage>;
+pub type Tx = broadcast::Sender<ServerEvent>;
 
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
-    State(state): State<
-        Arc<(
-            Pool<Postgres>,
-            tokio::sync::broadcast::Sender<crate::websocket_handler::ChatMessage>,
-        )>,
-    >,
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
-    ws.on_upgrade(move |socket| websocket_connection(socket, state.0.clone(), state.1.clone()))
+    let user_agent = user_agent(&headers);
+    ws.on_upgrade(move |socket| {
+        websocket_connection(socket, state, addr.ip().to_string(), user_agent)
+    })
 }
 
-async fn websocket_connection(stream: WebSocket, pool: Pool<Postgres>, tx: Tx) {
+async fn websocket_connection(
+    stream: WebSocket,
+    state: Arc<AppState>,
+    ip_address: String,
+    user_agent: Option<String>,
+) {
     let (mut sender, mut receiver) = stream.split();
-    
+    let pool = state.pool.clone();
+    let tx = state.tx.clone();
+
     // Wait for authentication message first
-    let authenticated_user = match receiver.next().await {
-        Some(Ok(Message::Text(text))) => {
-            match serde_json::from_str::<WsMessage>(&text) {
-                Ok(WsMessage::Auth { email, password }) => {
-                    match authenticate_user(&pool, &email, &password).await {
-                        Some(user) => {
-                            // Send success response
-                            let response = WsResponse {
-                                status: "authenticated".to_string(),
-                                message: None,
-                                info: Some(format!("Welcome, {}!", user.username)),
-                            };
-                            if let Ok(json) = serde_json::to_string(&response) {
-                                let _ = sender.send(Message::Text(json)).await;
-                            }
-                            println!("User {} ({}) authenticated", user.username, user.email);
-                            Some(user)
-                        }
-                        None => {
-                            // Send error response
-                            let response = WsResponse {
-                                status: "error".to_string(),
-                                message: None,
-                                info: Some("Authentication failed: Invalid email or password".to_string()),
-                            };
-                            if let Ok(json) = serde_json::to_string(&response) {
-                                let _ = sender.send(Message::Text(json)).await;
-                            }
-                            None
-                        }
-                    }
-                }
-                _ => {
-                    // Send error response
-                    let response = WsResponse {
-                        status: "error".to_string(),
-                        message: None,
-                        info: Some("First message must be authentication".to_string()),
-                    };
-                    if let Ok(json) = serde_json::to_string(&response) {
-                        let _ = sender.send(Message::Text(json)).await;
-                    }
-                    None
-                }
-            }
+    let auth_result = match receiver.next().await {
+        Some(Ok(Message::Text(text))) => match serde_json::from_str::<WsMessage>(&text) {
+            Ok(WsMessage::Auth {
+                email,
+                password,
+                totp_code,
+                workspace,
+            }) => authenticate_user(
+                &state,
+                &email,
+                &password,
+                totp_code.as_deref(),
+                workspace.as_deref(),
+                &ip_address,
+                user_agent.as_deref(),
+            )
+            .await
+            .map_err(|failure| format!("Authentication failed: {}", failure.message())),
+            Ok(WsMessage::AuthToken { token }) => authenticate_token(&pool, &token)
+                .await
+                .map(|session| AuthenticatedUser {
+                    email: session.email,
+                    username: session.username,
+                    user_id: session.user_id,
+                    session_id: session.session_id,
+                    workspace_id: session.workspace_id,
+                    owns_session: false,
+                })
+                .ok_or_else(|| "Authentication failed: Invalid or expired token".to_string()),
+            _ => Err("First message must be authentication".to_string()),
+        },
+        _ => {
+            let _ = sender.close().await;
+            return;
         }
-        _ => None,
     };
 
-    // If authentication failed, close the connection
-    let user = match authenticated_user {
-        Some(u) => u,
-        None => {
+    let user = match auth_result {
+        Ok(user) => {
+            // Send success response
+            let response = WsResponse {
+                status: "authenticated".to_string(),
+                message: None,
+                info: Some(format!("Welcome, {}!", user.username)),
+                data: None,
+            };
+            if let Ok(json) = serde_json::to_string(&response) {
+                let _ = sender.send(Message::Text(json)).await;
+            }
+            println!("User {} ({}) authenticated", user.username, user.email);
+            user
+        }
+        Err(info) => {
+            // Send error response and close the connection
+            let response = WsResponse {
+                status: "error".to_string(),
+                message: None,
+                info: Some(info),
+                data: None,
+            };
+            if let Ok(json) = serde_json::to_string(&response) {
+                let _ = sender.send(Message::Text(json)).await;
+            }
             let _ = sender.close().await;
             return;
         }
     };
 
+    // Everyone belongs to the default room of their workspace; deliver events of
+    // every room the user is in within that workspace
+    let default_room_id = default_room_id(&pool, user.workspace_id).await;
+    if let Some(room_id) = default_room_id
+        && let Err(e) = add_room_member(&pool, room_id, user.user_id, RoomRole::Member).await
+    {
+        eprintln!("Database error: {:?}", e);
+    }
+    let rooms: Arc<Mutex<HashSet<i32>>> = Arc::new(Mutex::new(
+        member_room_ids(&pool, user.user_id, user.workspace_id).await.into_iter().collect(),
+    ));
+
+    let (connection_id, mut control_rx) =
+        state.connections.register(user.user_id, user.session_id, user.workspace_id);
     let mut rx = tx.subscribe();
+    let workspace_id = user.workspace_id;
+    let mut blocked: HashSet<i32> = blocked_user_ids(&pool, user.user_id).await.into_iter().collect();
 
     // Task to receive messages from the broadcast channel and send to the client
+    let send_rooms = rooms.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let response = WsResponse {
-                status: "message".to_string(),
-                message: Some(msg),
-                info: None,
+        loop {
+            let response = tokio::select! {
+                event = rx.recv() => match event {
+                    Ok(event) => {
+                        if !event.is_visible_to(workspace_id, &send_rooms.lock().unwrap(), &blocked) {
+                            continue;
+                        }
+                        event.into_response()
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                },
+                control = control_rx.recv() => match control {
+                    Some(ConnectionControl::Close(reason)) => {
+                        let response = WsResponse {
+                            status: "closed".to_string(),
+                            message: None,
+                            info: Some(reason),
+                            data: None,
+                        };
+                        if let Ok(json) = serde_json::to_string(&response) {
+                            let _ = sender.send(Message::Text(json)).await;
+                        }
+                        let _ = sender.close().await;
+                        break;
+                    }
+                    Some(ConnectionControl::JoinRoom(room_id)) => {
+                        send_rooms.lock().unwrap().insert(room_id);
+                        continue;
+                    }
+                    Some(ConnectionControl::LeaveRoom(room_id)) => {
+                        send_rooms.lock().unwrap().remove(&room_id);
+                        continue;
+                    }
+                    Some(ConnectionControl::Block(user_id)) => {
+                        blocked.insert(user_id);
+                        continue;
+                    }
+                    Some(ConnectionControl::Unblock(user_id)) => {
+                        blocked.remove(&user_id);
+                        continue;
+                    }
+                    Some(ConnectionControl::Mention(message)) => WsResponse {
+                        status: "mention".to_string(),
+                        message: Some(message),
+                        info: None,
+                        data: None,
+                    },
+                    Some(ConnectionControl::Reminder { reminder_id, note, message }) => WsResponse {
+                        status: "reminder".to_string(),
+                        message,
+                        info: note,
+                        data: Some(serde_json::json!({ "reminder_id": reminder_id })),
+                    },
+                    Some(ConnectionControl::Notice(notice)) => WsResponse {
+                        status: "notice".to_string(),
+                        message: None,
+                        data: Some(notice.data()),
+                        info: Some(notice.text),
+                    },
+                    Some(ConnectionControl::Error(info)) => WsResponse {
+                        status: "error".to_string(),
+                        message: None,
+                        info: Some(info),
+                        data: None,
+                    },
+                    None => break,
+                },
             };
 
-            if let Ok(json) = serde_json::to_string(&response) {
-                if sender.send(Message::Text(json)).await.is_err() {
-                    break;
-                }
+            if let Ok(json) = serde_json::to_string(&response)
+                && sender.send(Message::Text(json)).await.is_err()
+            {
+                break;
             }
         }
     });
 
     // Task to receive messages from the client and broadcast to others
-    let tx_clone = tx.clone();
     let user_clone = user.clone();
+    let recv_state = state.clone();
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(Message::Text(text))) = receiver.next().await {
             // Parse the incoming message
             match serde_json::from_str::<WsMessage>(&text) {
                 Ok(ws_msg) => match ws_msg {
-                    WsMessage::Chat { content } => {
-                        // Store message in database
-                        let _ = store_message(&pool, user_clone.user_id, &content).await;
-
-                        // Broadcast message to all connected clients
-                        let chat_message = ChatMessage {
-                            user_email: user_clone.email.clone(),
-                            username: user_clone.username.clone(),
-                            content,
-                            timestamp: chrono::Utc::now().to_rfc3339(),
+                    WsMessage::Chat { content, room_id } => {
+                        let Some(room_id) = room_id.or(default_room_id) else {
+                            continue;
                         };
-
-                        let _ = tx_clone.send(chat_message);
+                        match parse_chat_input(&content) {
+                            ChatInput::Text(content) => {
+                                if let Err(reason) = post_message(&recv_state, user_clone.workspace_id, user_clone.user_id, room_id, content, None).await {
+                                    recv_state.connections.send_to_connection(
+                                        connection_id,
+                                        ConnectionControl::Error(reason),
+                                    );
+                                }
+                            }
+                            ChatInput::Command { name, args } => {
+                                let context = CommandContext {
+                                    state: recv_state.clone(),
+                                    session_id: user_clone.session_id,
+                                    user_id: user_clone.user_id,
+                                    workspace_id: user_clone.workspace_id,
+                                    room_id,
+                                    connection_id,
+                                    ip_address: ip_address.clone(),
+                                };
+                                run_command(&context, &name, args).await;
+                            }
+                        }
                     }
-                    WsMessage::Join => {
+                    WsMessage::Poll { room_id, question, options, multiple_choice, closes_in_seconds } => {
+                        let Some(room_id) = room_id.or(default_room_id) else {
+                            continue;
+                        };
+                        let poll = PollDraft {
+                            question,
+                            options,
+                            multiple_choice,
+                            closes_in_seconds,
+                        };
+                        if let Err(reason) = post_message(&recv_state, user_clone.workspace_id, user_clone.user_id, room_id, "", Some(poll)).await {
+                            recv_state.connections.send_to_connection(
+                                connection_id,
+                                ConnectionControl::Error(reason),
+                            );
+                        }
+                    }
+                    WsMessage::Vote { message_id, options } => {
+                        if let Err(reason) = cast_vote(&recv_state, user_clone.workspace_id, user_clone.user_id, message_id, &options).await {
+                            recv_state.connections.send_to_connection(
+                                connection_id,
+                                ConnectionControl::Error(reason),
+                            );
+                        }
+                    }
+                    WsMessage::Join { room_id: None } => {
                         println!("User {} joined the chat", user_clone.email);
                     }
-                    WsMessage::Leave => {
+                    WsMessage::Join { room_id: Some(room_id) } => {
+                        if let Err(reason) = check_can_join(&pool, user_clone.workspace_id, room_id, user_clone.user_id).await {
+                            recv_state.connections.send_to_connection(connection_id, ConnectionControl::Error(reason));
+                            continue;
+                        }
+                        if let Some(kick) = active_sanction(&pool, user_clone.user_id, user_clone.workspace_id, ModerationAction::Kick, Some(room_id)).await {
+                            recv_state.connections.send_to_connection(
+                                connection_id,
+                                ConnectionControl::Error(kick.describe("You have been kicked from this room")),
+                            );
+                            continue;
+                        }
+                        match add_room_member(&pool, room_id, user_clone.user_id, RoomRole::Member).await {
+                            Ok(()) => {
+                                rooms.lock().unwrap().insert(room_id);
+                                println!("User {} joined room {}", user_clone.email, room_id);
+                            }
+                            Err(e) => eprintln!("Failed to join room {}: {:?}", room_id, e),
+                        }
+                    }
+                    WsMessage::Leave { room_id: None } => {
                         println!("User {} left the chat", user_clone.email);
                     }
-                    WsMessage::Auth { .. } => {
+                    WsMessage::Leave { room_id: Some(room_id) } => {
+                        if Some(room_id) == default_room_id {
+                            continue;
+                        }
+                        let _ = sqlx::query("DELETE FROM room_members WHERE room_id = $1 AND user_id = $2")
+                            .bind(room_id)
+                            .bind(user_clone.user_id)
+                            .execute(&pool)
+                            .await;
+                        rooms.lock().unwrap().remove(&room_id);
+                        println!("User {} left room {}", user_clone.email, room_id);
+                    }
+                    WsMessage::Auth { .. } | WsMessage::AuthToken { .. } => {
                         // Ignore subsequent auth messages
                         eprintln!("Received auth message after authentication");
                     }
@@ -192,50 +552,73 @@ async fn websocket_connection(stream: WebSocket, pool: Pool<Postgres>, tx: Tx) {
         _ = (&mut send_task) => recv_task.abort(),
         _ = (&mut recv_task) => send_task.abort(),
     };
-    
-    println!("User {} disconnected", user.email);
-}
 
-/// Authenticates a user and returns their information if successful
-async fn authenticate_user(pool: &Pool<Postgres>, email: &str, password: &str) -> Option<AuthenticatedUser> {
-    let password_hash = hash_password(password);
-    
-    let result = sqlx::query_as::<_, (i32, String, String)>(
-        "SELECT id, name, email FROM users WHERE email = $1 AND password_hash = $2"
-    )
-    .bind(email)
-    .bind(&password_hash)
-    .fetch_optional(pool)
-    .await;
+    state.connections.unregister(connection_id);
 
-    match result {
-        Ok(Some((user_id, username, email))) => Some(AuthenticatedUser {
-            email,
-            username,
-            user_id,
-        }),
-        _ => None,
+    // Nothing else can use the session of a password login, so it goes with the connection
+    if user.owns_session
+        && let Err(e) = sqlx::query("DELETE FROM sessions WHERE id = $1")
+            .bind(user.session_id)
+            .execute(&state.pool)
+            .await
+    {
+        eprintln!("Database error: {:?}", e);
     }
-}
 
-/// Hash password using SHA256
-fn hash_password(password: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(password.as_bytes());
-    let result = hasher.finalize();
-    format!("{:x}", result)
+    println!("User {} disconnected", user.email);
 }
 
-/// Stores message in DB
-async fn store_message(
-    pool: &Pool<Postgres>,
-    user_id: i32,
-    content: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO messages (user_id, content) VALUES ($1, $2)")
-        .bind(user_id)
-        .bind(content)
-        .execute(pool)
-        .await?;
-    Ok(())
+/// Authenticates a user, opens a session in their workspace for the connection
+/// and returns their information if successful
+async fn authenticate_user(
+    state: &AppState,
+    email: &str,
+    password: &str,
+    totp_code: Option<&str>,
+    workspace: Option<&str>,
+    ip_address: &str,
+    user_agent: Option<&str>,
+) -> Result<AuthenticatedUser, AuthFailure> {
+    let verified = match verify_credentials(state, email, password, totp_code, ip_address).await {
+        Ok(user) => enter_workspace(&state.pool, user.user_id, workspace)
+            .await
+            .map(|workspace_id| (user, workspace_id)),
+        Err(failure) => Err(failure),
+    };
+    let (user, workspace_id) = match verified {
+        Ok(verified) => verified,
+        Err(failure) => {
+            record_login_failure(&state.pool, email, ip_address, &failure).await;
+            return Err(failure);
+        }
+    };
+    let session_id = create_session(&state.pool, user.user_id, workspace_id, "WebSocket", ip_address, user_agent)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {:?}", e);
+            AuthFailure::InvalidCredentials
+        })?;
+
+    record_event(
+        &state.pool,
+        AuditEvent::Login,
+        Some(user.user_id),
+        Some(user.user_id),
+        Some(ip_address),
+        serde_json::json!({
+            "session_id": session_id,
+            "device_name": "WebSocket",
+            "workspace_id": workspace_id,
+        }),
+    )
+    .await;
+
+    Ok(AuthenticatedUser {
+        email: user.email,
+        username: user.username,
+        user_id: user.user_id,
+        session_id,
+        workspace_id,
+        owns_session: true,
+    })
 }
@@ -0,0 +1,825 @@
+use axum::extract::{ConnectInfo, Json, Path, State};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, Pool, Postgres};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::AppState;
+use crate::audit_log::{AuditEvent, record_event};
+use crate::login_protection::AuthFailure;
+use crate::mailer::Email;
+use crate::moderation::{ModerationAction, active_sanction};
+use crate::roles::{Authorized, Permission, Role, permission};
+use crate::room_operations::{RoleRequest, ensure_default_room};
+use crate::session_operations::{AuthSession, revoke_workspace_sessions};
+use crate::user_operations::ApiResponse;
+use crate::webhooks::{WebhookEvent, queue_event};
+
+const DEFAULT_WORKSPACE_NAME: &str = "Default";
+const DEFAULT_WORKSPACE_SLUG: &str = "default";
+
+/// Role inside a workspace, stored in `workspace_members.role`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkspaceRole {
+    Admin,
+    Member,
+}
+
+impl WorkspaceRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkspaceRole::Admin => "admin",
+            WorkspaceRole::Member => "member",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<WorkspaceRole> {
+        match value {
+            "admin" => Some(WorkspaceRole::Admin),
+            "member" => Some(WorkspaceRole::Member),
+            _ => None,
+        }
+    }
+
+    /// Workspace admins run their own workspace, but can't touch global roles,
+    /// the audit log or other workspaces
+    pub fn has(&self, permission: Permission) -> bool {
+        match self {
+            WorkspaceRole::Admin => matches!(
+                permission,
+                Permission::DeleteAnyMessage
+                    | Permission::PinMessages
+                    | Permission::ManageRooms
+                    | Permission::ModerateUsers
+                    | Permission::BypassRoomLimits
+                    | Permission::ManageInvites
+                    | Permission::ManageMembers
+                    | Permission::ManageWebhooks
+                    | Permission::ManageBots
+            ),
+            WorkspaceRole::Member => false,
+        }
+    }
+}
+
+/// Creates the workspace that existing data and open registrations belong
+/// to, if needed, and returns its id
+pub async fn ensure_default_workspace(pool: &Pool<Postgres>) -> i32 {
+    let _ = sqlx::query(
+        "INSERT INTO workspaces (name, slug) VALUES ($1, $2) ON CONFLICT (slug) DO NOTHING",
+    )
+    .bind(DEFAULT_WORKSPACE_NAME)
+    .bind(DEFAULT_WORKSPACE_SLUG)
+    .execute(pool)
+    .await;
+
+    let (workspace_id,) = sqlx::query_as::<_, (i32,)>("SELECT id FROM workspaces WHERE slug = $1")
+        .bind(DEFAULT_WORKSPACE_SLUG)
+        .fetch_one(pool)
+        .await
+        .expect("Failed to create the default workspace.");
+
+    // Everything created before workspaces existed belongs to the default one
+    for table in [
+        "rooms",
+        "sessions",
+        "invites",
+        "moderation_actions",
+        "reports",
+    ] {
+        let _ = sqlx::query(&format!(
+            "UPDATE {} SET workspace_id = $1 WHERE workspace_id IS NULL",
+            table
+        ))
+        .bind(workspace_id)
+        .execute(pool)
+        .await;
+    }
+
+    let room_id = ensure_default_room(pool, workspace_id)
+        .await
+        .expect("Failed to create the default room.");
+
+    // Messages written before rooms existed belong to the default room
+    let _ = sqlx::query("UPDATE messages SET room_id = $1 WHERE room_id IS NULL")
+        .bind(room_id)
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query(
+        "INSERT INTO workspace_members (workspace_id, user_id)
+         SELECT $1, u.id FROM users u
+         WHERE NOT EXISTS (SELECT 1 FROM workspace_members wm WHERE wm.user_id = u.id)",
+    )
+    .bind(workspace_id)
+    .execute(pool)
+    .await;
+
+    workspace_id
+}
+
+/// Adds a user to a workspace and its default room; does nothing if they
+/// already are a member
+pub async fn add_workspace_member<'a, A>(
+    connection: A,
+    workspace_id: i32,
+    user_id: i32,
+    role: WorkspaceRole,
+) -> Result<(), sqlx::Error>
+where
+    A: Acquire<'a, Database = Postgres>,
+{
+    let mut transaction = connection.begin().await?;
+    let joined = sqlx::query_as::<_, (i32, String)>(
+        "WITH member AS (
+             INSERT INTO workspace_members (workspace_id, user_id, role) VALUES ($1, $2, $3)
+             ON CONFLICT (workspace_id, user_id) DO NOTHING
+         ), joined AS (
+             INSERT INTO room_members (room_id, user_id)
+             SELECT default_room_id, $2 FROM workspaces WHERE id = $1 AND default_room_id IS NOT NULL
+             ON CONFLICT (room_id, user_id) DO NOTHING
+             RETURNING room_id
+         )
+         SELECT joined.room_id, users.name FROM joined, users WHERE users.id = $2",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .bind(role.as_str())
+    .fetch_optional(&mut transaction)
+    .await?;
+
+    if let Some((room_id, username)) = joined {
+        queue_event(
+            &mut transaction,
+            workspace_id,
+            Some(room_id),
+            WebhookEvent::MemberJoined,
+            serde_json::json!({ "room_id": room_id, "user_id": user_id, "username": username }),
+        )
+        .await?;
+    }
+    transaction.commit().await
+}
+
+/// The room every member of a workspace belongs to
+pub async fn default_room_id(pool: &Pool<Postgres>, workspace_id: i32) -> Option<i32> {
+    let result =
+        sqlx::query_as::<_, (Option<i32>,)>("SELECT default_room_id FROM workspaces WHERE id = $1")
+            .bind(workspace_id)
+            .fetch_optional(pool)
+            .await;
+
+    match result {
+        Ok(row) => row.and_then(|(room_id,)| room_id),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Whether a room is the default room of its workspace
+pub async fn is_default_room(pool: &Pool<Postgres>, room_id: i32) -> bool {
+    let result = sqlx::query_as::<_, (bool,)>(
+        "SELECT EXISTS (SELECT 1 FROM workspaces WHERE default_room_id = $1)",
+    )
+    .bind(room_id)
+    .fetch_one(pool)
+    .await;
+
+    match result {
+        Ok((is_default,)) => is_default,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether a user is a member of a workspace
+pub async fn is_workspace_member(pool: &Pool<Postgres>, workspace_id: i32, user_id: i32) -> bool {
+    let result = sqlx::query_as::<_, (bool,)>(
+        "SELECT EXISTS (
+             SELECT 1 FROM workspace_members WHERE workspace_id = $1 AND user_id = $2
+         )",
+    )
+    .bind(workspace_id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await;
+
+    match result {
+        Ok((is_member,)) => is_member,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Picks the workspace a login opens a session in: the one named by `slug`,
+/// or the first workspace the user joined and isn't banned from. The user
+/// must be a member and must not be banned from it.
+pub async fn enter_workspace(
+    pool: &Pool<Postgres>,
+    user_id: i32,
+    slug: Option<&str>,
+) -> Result<i32, AuthFailure> {
+    let result = sqlx::query_as::<_, (i32,)>(
+        "SELECT wm.workspace_id FROM workspace_members wm
+         JOIN workspaces w ON w.id = wm.workspace_id
+         WHERE wm.user_id = $1 AND ($2::TEXT IS NULL OR w.slug = $2)
+         ORDER BY EXISTS (
+                      SELECT 1 FROM moderation_actions ma
+                      WHERE ma.user_id = wm.user_id AND ma.workspace_id = wm.workspace_id
+                        AND ma.action = $3 AND ma.room_id IS NULL AND ma.revoked_at IS NULL
+                        AND (ma.expires_at IS NULL OR ma.expires_at > CURRENT_TIMESTAMP)
+                  ),
+                  wm.joined_at, wm.workspace_id
+         LIMIT 1",
+    )
+    .bind(user_id)
+    .bind(slug.map(str::trim))
+    .bind(ModerationAction::Ban.as_str())
+    .fetch_optional(pool)
+    .await;
+
+    let workspace_id = match result {
+        Ok(Some((workspace_id,))) => workspace_id,
+        Ok(None) => return Err(AuthFailure::NotWorkspaceMember),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Err(AuthFailure::NotWorkspaceMember);
+        }
+    };
+
+    if let Some(ban) =
+        active_sanction(pool, user_id, workspace_id, ModerationAction::Ban, None).await
+    {
+        return Err(AuthFailure::Banned {
+            reason: ban.reason,
+            expires_at: ban.expires_at,
+        });
+    }
+
+    Ok(workspace_id)
+}
+
+fn is_valid_slug(slug: &str) -> bool {
+    (1..=32).contains(&slug.len())
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWorkspaceRequest {
+    pub name: String,
+    /// Short identifier used to pick the workspace when logging in, e.g. `acme`
+    pub slug: String,
+}
+
+/// Creates a workspace with its default room and makes the caller its admin
+pub async fn create_workspace(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageWorkspaces>,
+    Json(payload): Json<CreateWorkspaceRequest>,
+) -> Json<ApiResponse> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > 100 {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Workspace name must be between 1 and 100 characters".to_string(),
+        });
+    }
+    if !is_valid_slug(&payload.slug) {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Slug must be 1 to 32 lowercase letters, digits or dashes".to_string(),
+        });
+    }
+
+    let query_result = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO workspaces (name, slug, created_by) VALUES ($1, $2, $3) RETURNING id",
+    )
+    .bind(name)
+    .bind(&payload.slug)
+    .bind(admin.auth.user_id)
+    .fetch_one(&state.pool)
+    .await;
+
+    let workspace_id = match query_result {
+        Ok((workspace_id,)) => workspace_id,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to create workspace: {}", e),
+            });
+        }
+    };
+
+    if let Err(e) = ensure_default_room(&state.pool, workspace_id).await {
+        eprintln!("Database error: {:?}", e);
+    }
+    if let Err(e) = add_workspace_member(
+        &state.pool,
+        workspace_id,
+        admin.auth.user_id,
+        WorkspaceRole::Admin,
+    )
+    .await
+    {
+        eprintln!("Database error: {:?}", e);
+    }
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Workspace created with id {}", workspace_id),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceInfo {
+    pub id: i32,
+    pub name: String,
+    pub slug: String,
+    /// The caller's role in the workspace
+    pub role: String,
+    /// Whether the caller's current session belongs to this workspace
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspacesResponse {
+    pub status: String,
+    pub workspaces: Vec<WorkspaceInfo>,
+}
+
+/// Lists the workspaces the caller is a member of
+pub async fn list_workspaces(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<WorkspacesResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, String, String)>(
+        "SELECT w.id, w.name, w.slug, wm.role
+         FROM workspace_members wm
+         JOIN workspaces w ON w.id = wm.workspace_id
+         WHERE wm.user_id = $1
+         ORDER BY wm.joined_at, w.id",
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(WorkspacesResponse {
+            status: "success".to_string(),
+            workspaces: rows
+                .into_iter()
+                .map(|(id, name, slug, role)| WorkspaceInfo {
+                    id,
+                    name,
+                    slug,
+                    role,
+                    current: id == auth.workspace_id,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(WorkspacesResponse {
+                status: "error".to_string(),
+                workspaces: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceMemberInfo {
+    pub user_id: i32,
+    pub username: String,
+    pub role: String,
+    pub joined_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceMembersResponse {
+    pub status: String,
+    pub members: Vec<WorkspaceMemberInfo>,
+}
+
+/// Lists the members of the caller's workspace
+pub async fn list_workspace_members(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<WorkspaceMembersResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, String, String)>(
+        "SELECT u.id, u.name, wm.role, wm.joined_at::text
+         FROM workspace_members wm
+         JOIN users u ON u.id = wm.user_id
+         WHERE wm.workspace_id = $1
+         ORDER BY u.id",
+    )
+    .bind(auth.workspace_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(WorkspaceMembersResponse {
+            status: "success".to_string(),
+            members: rows
+                .into_iter()
+                .map(|(user_id, username, role, joined_at)| WorkspaceMemberInfo {
+                    user_id,
+                    username,
+                    role,
+                    joined_at,
+                })
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(WorkspaceMembersResponse {
+                status: "error".to_string(),
+                members: vec![],
+            })
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMemberRequest {
+    pub email: String,
+    /// `admin` or `member` (default)
+    pub role: Option<String>,
+}
+
+/// Invites an existing account to the caller's workspace. The user only
+/// becomes a member once they accept. The reply is the same whether or not
+/// the account exists, so admins can't find out which emails are registered.
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    admin: Authorized<permission::ManageMembers>,
+    Json(payload): Json<AddMemberRequest>,
+) -> Json<ApiResponse> {
+    let Some(role) = WorkspaceRole::parse(payload.role.as_deref().unwrap_or("member")) else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Role must be one of admin or member".to_string(),
+        });
+    };
+    let email = payload.email.trim();
+
+    // Members and users who were already invited aren't invited again
+    let invited = sqlx::query_as::<_, (String,)>(
+        "INSERT INTO workspace_invitations (workspace_id, user_id, role, invited_by)
+         SELECT $1, u.id, $3, $4 FROM users u
+         WHERE u.email = $2 AND NOT u.is_bot
+           AND NOT EXISTS (
+               SELECT 1 FROM workspace_members wm WHERE wm.workspace_id = $1 AND wm.user_id = u.id
+           )
+         ON CONFLICT (workspace_id, user_id) DO NOTHING
+         RETURNING (SELECT name FROM workspaces WHERE id = $1)",
+    )
+    .bind(admin.auth.workspace_id)
+    .bind(email)
+    .bind(role.as_str())
+    .bind(admin.auth.user_id)
+    .fetch_optional(&state.pool)
+    .await;
+
+    match invited {
+        Ok(Some((workspace_name,))) => {
+            let result = state
+                .mailer
+                .send(Email {
+                    to: email.to_string(),
+                    subject: format!("You have been invited to {}", workspace_name),
+                    body: format!(
+                        "You have been invited to join the workspace {}. Log in to accept or decline the invitation.",
+                        workspace_name
+                    ),
+                })
+                .await;
+            if let Err(e) = result {
+                eprintln!("Failed to send workspace invitation to {}: {}", email, e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to invite member: {}", e),
+            });
+        }
+    }
+
+    Json(ApiResponse {
+        status: "success".to_string(),
+        message: "If an account exists with this email, it has been invited to the workspace"
+            .to_string(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceInvitationInfo {
+    pub workspace_id: i32,
+    pub name: String,
+    pub slug: String,
+    /// Role the caller gets by accepting
+    pub role: String,
+    pub invited_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkspaceInvitationsResponse {
+    pub status: String,
+    pub invitations: Vec<WorkspaceInvitationInfo>,
+}
+
+/// Lists the workspaces the caller has been invited to, in any workspace
+pub async fn list_workspace_invitations(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Json<WorkspaceInvitationsResponse> {
+    let query_result = sqlx::query_as::<_, (i32, String, String, String, String)>(
+        "SELECT w.id, w.name, w.slug, wi.role, wi.created_at::text
+         FROM workspace_invitations wi
+         JOIN workspaces w ON w.id = wi.workspace_id
+         WHERE wi.user_id = $1
+         ORDER BY wi.created_at",
+    )
+    .bind(auth.user_id)
+    .fetch_all(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(rows) => Json(WorkspaceInvitationsResponse {
+            status: "success".to_string(),
+            invitations: rows
+                .into_iter()
+                .map(
+                    |(workspace_id, name, slug, role, invited_at)| WorkspaceInvitationInfo {
+                        workspace_id,
+                        name,
+                        slug,
+                        role,
+                        invited_at,
+                    },
+                )
+                .collect(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(WorkspaceInvitationsResponse {
+                status: "error".to_string(),
+                invitations: vec![],
+            })
+        }
+    }
+}
+
+/// Accepts an invitation, making the caller a member of the workspace with
+/// the role they were invited with
+pub async fn accept_workspace_invitation(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(workspace_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let accepted: Result<bool, sqlx::Error> = async {
+        let mut transaction = state.pool.begin().await?;
+        let invitation = sqlx::query_as::<_, (String,)>(
+            "DELETE FROM workspace_invitations WHERE workspace_id = $1 AND user_id = $2
+             RETURNING role",
+        )
+        .bind(workspace_id)
+        .bind(auth.user_id)
+        .fetch_optional(&mut transaction)
+        .await?;
+        let Some((role,)) = invitation else {
+            return Ok(false);
+        };
+        let role = WorkspaceRole::parse(&role).unwrap_or(WorkspaceRole::Member);
+        add_workspace_member(&mut transaction, workspace_id, auth.user_id, role).await?;
+        transaction.commit().await?;
+        Ok(true)
+    }
+    .await;
+
+    match accepted {
+        Ok(true) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "You joined the workspace, log in to it to use it".to_string(),
+        }),
+        Ok(false) => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No invitation found for this workspace".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to accept invitation: {}", e),
+            })
+        }
+    }
+}
+
+/// Declines an invitation to a workspace
+pub async fn decline_workspace_invitation(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(workspace_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let query_result =
+        sqlx::query("DELETE FROM workspace_invitations WHERE workspace_id = $1 AND user_id = $2")
+            .bind(workspace_id)
+            .bind(auth.user_id)
+            .execute(&state.pool)
+            .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "No invitation found for this workspace".to_string(),
+        }),
+        Ok(_) => Json(ApiResponse {
+            status: "success".to_string(),
+            message: "Invitation declined".to_string(),
+        }),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to decline invitation: {}", e),
+            })
+        }
+    }
+}
+
+/// Only global admins can remove or demote someone with a global admin role
+async fn check_can_manage(
+    pool: &Pool<Postgres>,
+    admin: &AuthSession,
+    user_id: i32,
+) -> Result<(), String> {
+    if user_id == admin.user_id {
+        return Err("You can't change your own membership".to_string());
+    }
+
+    let result = sqlx::query_as::<_, (String,)>("SELECT role FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await;
+
+    match result {
+        Ok(Some((role,)))
+            if Role::parse(&role) == Some(Role::Admin) && admin.role != Role::Admin =>
+        {
+            Err("Only admins can manage admins".to_string())
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Err(format!("Failed to look up user: {}", e))
+        }
+    }
+}
+
+/// Removes a member from the caller's workspace, along with their rooms and
+/// sessions in it
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageMembers>,
+    Path(user_id): Path<i32>,
+) -> Json<ApiResponse> {
+    let workspace_id = admin.auth.workspace_id;
+    if let Err(message) = check_can_manage(&state.pool, &admin.auth, user_id).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        });
+    }
+
+    let query_result =
+        sqlx::query("DELETE FROM workspace_members WHERE workspace_id = $1 AND user_id = $2")
+            .bind(workspace_id)
+            .bind(user_id)
+            .execute(&state.pool)
+            .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "This user is not a member of the workspace".to_string(),
+        }),
+        Ok(_) => {
+            let _ = sqlx::query(
+                "DELETE FROM room_members rm USING rooms r
+                 WHERE r.id = rm.room_id AND r.workspace_id = $1 AND rm.user_id = $2",
+            )
+            .bind(workspace_id)
+            .bind(user_id)
+            .execute(&state.pool)
+            .await;
+            if let Err(e) = revoke_workspace_sessions(
+                &state,
+                user_id,
+                workspace_id,
+                "You have been removed from the workspace",
+            )
+            .await
+            {
+                eprintln!("Database error: {:?}", e);
+            }
+            record_event(
+                &state.pool,
+                AuditEvent::WorkspaceMemberRemoved,
+                Some(admin.auth.user_id),
+                Some(user_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({ "workspace_id": workspace_id }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: "Member removed".to_string(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to remove member: {}", e),
+            })
+        }
+    }
+}
+
+/// Changes the role of a member inside the caller's workspace
+pub async fn set_workspace_role(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    admin: Authorized<permission::ManageMembers>,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<RoleRequest>,
+) -> Json<ApiResponse> {
+    let Some(role) = WorkspaceRole::parse(&payload.role) else {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message: "Role must be one of admin or member".to_string(),
+        });
+    };
+
+    if let Err(message) = check_can_manage(&state.pool, &admin.auth, user_id).await {
+        return Json(ApiResponse {
+            status: "error".to_string(),
+            message,
+        });
+    }
+
+    let query_result = sqlx::query(
+        "UPDATE workspace_members SET role = $1 WHERE workspace_id = $2 AND user_id = $3",
+    )
+    .bind(role.as_str())
+    .bind(admin.auth.workspace_id)
+    .bind(user_id)
+    .execute(&state.pool)
+    .await;
+
+    match query_result {
+        Ok(result) if result.rows_affected() == 0 => Json(ApiResponse {
+            status: "error".to_string(),
+            message: "This user is not a member of the workspace".to_string(),
+        }),
+        Ok(_) => {
+            record_event(
+                &state.pool,
+                AuditEvent::WorkspaceRoleChanged,
+                Some(admin.auth.user_id),
+                Some(user_id),
+                Some(&addr.ip().to_string()),
+                serde_json::json!({
+                    "workspace_id": admin.auth.workspace_id,
+                    "role": role.as_str(),
+                }),
+            )
+            .await;
+            Json(ApiResponse {
+                status: "success".to_string(),
+                message: format!("Workspace role changed to {}", role.as_str()),
+            })
+        }
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            Json(ApiResponse {
+                status: "error".to_string(),
+                message: format!("Failed to change workspace role: {}", e),
+            })
+        }
+    }
+}